@@ -2,21 +2,23 @@
 //! services like the database, onion service,...
 
 use crate::{
-    PROJECT_DIR, attachment,
+    PROJECT_DIR, attachment, collision, connection, contact_request, control,
     db::{self, DbModel, DbUpdateModel},
-    error,
+    deniable, envelope, error, frame, identity,
     ipc::{self, MessageToUI},
-    message::MessageContent,
-    ratchet, ui_focus,
+    message::{self, MessageContent},
+    outbox, padding, peer_lock, prekey, ratchet, receipt, replay, reset, rotation, safety,
+    ui_focus,
 };
 use arti_client::config::onion_service::OnionServiceConfigBuilder;
 use ed25519_dalek::{PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH, SigningKey, VerifyingKey};
-use futures::{AsyncWriteExt, Stream, StreamExt};
+use futures::{SinkExt, Stream, StreamExt};
 use notify_rust::Notification;
 use tokio::sync::Mutex as TokioMutex;
 use tokio::sync::mpsc::UnboundedSender;
 use tor_cell::relaycell::msg::Connected;
 use tor_proto::client::stream::IncomingStreamRequest;
+use zeroize::Zeroize;
 
 /// Type for TorClient with runtime.
 type ArtiTorClient = arti_client::TorClient<tor_rtcompat::PreferredRuntime>;
@@ -30,7 +32,8 @@ type ClientConfigType = std::sync::Arc<TokioMutex<ClientConfig>>;
 /// Encapsulates hidden service, database connection,...
 pub struct Client {
     /// Arti Tor Client.
-    pub tor_client: TokioMutex<ArtiTorClient>,
+    /// Swapped atomically on circuit reset, so connects never wait for each other.
+    pub tor_client: arc_swap::ArcSwap<ArtiTorClient>,
 
     /// Database connection.
     pub db_conn: DatabaseConnection,
@@ -46,10 +49,59 @@ pub struct Client {
     request_stream: TokioMutex<std::pin::Pin<OnionServiceRequestStream>>,
 
     /// Private key of user to sign chat messages.
-    private_key: SigningKey,
+    /// Behind a lock since it is replaced on key rotation.
+    private_key: TokioMutex<SigningKey>,
+
+    /// Onion service identity is derived from our key.
+    identity_mode: bool,
 
     /// Ratchets bound to contact onion id.
     ratchets: std::sync::Arc<TokioMutex<std::collections::HashMap<String, ratchet::RatchetChain>>>,
+
+    /// Held while the session of a peer is read, advanced and stored.
+    session_locks: peer_lock::PeerLocks,
+
+    /// Held while we set up a session with a peer, so only one is set up at a time.
+    session_setups: peer_lock::PeerLocks,
+
+    /// Nonces of recently accepted handshakes.
+    replay_cache: TokioMutex<replay::ReplayCache>,
+
+    /// Our handshakes waiting for a reply, to resolve simultaneous handshakes.
+    pending_handshakes: TokioMutex<collision::PendingHandshakes>,
+
+    /// Session resets we recently requested from peers.
+    sent_resets: TokioMutex<reset::ResetThrottle>,
+
+    /// Undecryptable messages of peers since their last authenticated one.
+    decrypt_failures: TokioMutex<reset::DecryptFailures>,
+
+    /// Typing notifications we recently sent to peers.
+    typing_throttle: TokioMutex<control::TypingThrottle>,
+
+    /// Session resets we recently accepted from peers.
+    accepted_resets: TokioMutex<reset::ResetThrottle>,
+
+    /// Contact requests we recently accepted from peers.
+    received_requests: TokioMutex<contact_request::RequestThrottle>,
+
+    /// Contact requests we recently sent to peers.
+    sent_requests: TokioMutex<contact_request::SentRequests>,
+
+    /// Wakes the outbox task when a message is queued.
+    outbox_wakeup: tokio::sync::Notify,
+
+    /// Peers the outbox task is delivering to right now.
+    outbox_peers: TokioMutex<std::collections::HashSet<String>>,
+
+    /// Open streams to peers, reused for frames sent shortly after each other.
+    connections: connection::ConnectionPool,
+
+    /// Receipts waiting to be sent by the receipt task.
+    receipts: TokioMutex<receipt::ReceiptQueue>,
+
+    /// Wakes the receipt task when a receipt is queued.
+    receipt_wakeup: tokio::sync::Notify,
 }
 
 /// Client configuration from database.
@@ -59,6 +111,24 @@ pub struct ClientConfig {
     pub enable_notifications: bool,
     /// Allow sending and receiving attachments.
     pub enable_attachments: bool,
+    /// Derive onion address from our key, applied on next launch.
+    pub enable_onion_identity: bool,
+    /// Padding of plaintext to hide the length of messages.
+    pub padding_policy: padding::PaddingPolicy,
+    /// Use the hybrid post-quantum handshake with peers supporting it.
+    pub enable_post_quantum: bool,
+    /// Refuse sessions with peers not using the hybrid post-quantum handshake.
+    pub require_post_quantum: bool,
+    /// Tell contacts when we read their messages.
+    pub enable_read_receipts: bool,
+    /// Exchange typing indicators with contacts.
+    pub enable_typing_indicators: bool,
+    /// Seconds until undelivered messages fail, `None` to retry until cancelled.
+    pub message_expiry: Option<i64>,
+    /// Seconds an unused stream to a peer is kept open.
+    pub connection_idle_timeout: u64,
+    /// Drop contact requests without proof-of-work stamp.
+    pub require_contact_request_stamp: bool,
 }
 
 impl ClientConfig {
@@ -69,6 +139,37 @@ impl ClientConfig {
                 .await?,
             enable_attachments: db::ConfigDb::get_bool("enable_attachments", db_conn.clone())
                 .await?,
+            enable_onion_identity: db::ConfigDb::get_bool("enable_onion_identity", db_conn.clone())
+                .await?,
+            padding_policy: db::ConfigDb::get("padding_policy", db_conn.clone())
+                .await?
+                .and_then(|policy| policy.parse().ok())
+                .unwrap_or_default(),
+            enable_post_quantum: db::ConfigDb::get_bool("enable_post_quantum", db_conn.clone())
+                .await?,
+            require_post_quantum: db::ConfigDb::get_bool("require_post_quantum", db_conn.clone())
+                .await?,
+            enable_read_receipts: db::ConfigDb::get_bool("enable_read_receipts", db_conn.clone())
+                .await?,
+            enable_typing_indicators: db::ConfigDb::get_bool(
+                "enable_typing_indicators",
+                db_conn.clone(),
+            )
+            .await?,
+            message_expiry: db::ConfigDb::get("message_expiry", db_conn.clone())
+                .await?
+                .and_then(|expiry| expiry.parse().ok())
+                .filter(|expiry| *expiry > 0),
+            connection_idle_timeout: db::ConfigDb::get("connection_idle_timeout", db_conn.clone())
+                .await?
+                .and_then(|timeout| timeout.parse().ok())
+                .unwrap_or(connection::DEFAULT_IDLE_TIMEOUT)
+                .min(connection::MAX_IDLE_TIMEOUT),
+            require_contact_request_stamp: db::ConfigDb::get_bool(
+                "require_contact_request_stamp",
+                db_conn.clone(),
+            )
+            .await?,
         })
     }
 
@@ -77,6 +178,17 @@ impl ClientConfig {
         match key {
             ClientConfigKey::EnableNotifications => self.enable_notifications.to_string(),
             ClientConfigKey::EnableAttachments => self.enable_attachments.to_string(),
+            ClientConfigKey::EnableOnionIdentity => self.enable_onion_identity.to_string(),
+            ClientConfigKey::PaddingPolicy => self.padding_policy.to_string(),
+            ClientConfigKey::EnablePostQuantum => self.enable_post_quantum.to_string(),
+            ClientConfigKey::RequirePostQuantum => self.require_post_quantum.to_string(),
+            ClientConfigKey::EnableReadReceipts => self.enable_read_receipts.to_string(),
+            ClientConfigKey::EnableTypingIndicators => self.enable_typing_indicators.to_string(),
+            ClientConfigKey::MessageExpiry => self.message_expiry.unwrap_or(0).to_string(),
+            ClientConfigKey::ConnectionIdleTimeout => self.connection_idle_timeout.to_string(),
+            ClientConfigKey::RequireContactRequestStamp => {
+                self.require_contact_request_stamp.to_string()
+            }
         }
     }
 }
@@ -88,6 +200,24 @@ pub enum ClientConfigKey {
     EnableNotifications,
     /// Setting for allowing attachments in chat.
     EnableAttachments,
+    /// Setting for deriving the onion address from the key of the user.
+    EnableOnionIdentity,
+    /// Setting for padding of messages: off, buckets or strict.
+    PaddingPolicy,
+    /// Setting for the hybrid post-quantum handshake.
+    EnablePostQuantum,
+    /// Setting for refusing sessions without the hybrid post-quantum handshake.
+    RequirePostQuantum,
+    /// Setting for sending read receipts.
+    EnableReadReceipts,
+    /// Setting for typing indicators.
+    EnableTypingIndicators,
+    /// Setting for seconds until undelivered messages fail, 0 to never fail.
+    MessageExpiry,
+    /// Setting for seconds an unused stream to a peer is kept open.
+    ConnectionIdleTimeout,
+    /// Setting for dropping contact requests without proof-of-work stamp.
+    RequireContactRequestStamp,
 }
 
 impl std::str::FromStr for ClientConfigKey {
@@ -97,6 +227,15 @@ impl std::str::FromStr for ClientConfigKey {
         match s {
             "enable_notifications" => Ok(Self::EnableNotifications),
             "enable_attachments" => Ok(Self::EnableAttachments),
+            "enable_onion_identity" => Ok(Self::EnableOnionIdentity),
+            "padding_policy" => Ok(Self::PaddingPolicy),
+            "enable_post_quantum" => Ok(Self::EnablePostQuantum),
+            "require_post_quantum" => Ok(Self::RequirePostQuantum),
+            "enable_read_receipts" => Ok(Self::EnableReadReceipts),
+            "enable_typing_indicators" => Ok(Self::EnableTypingIndicators),
+            "message_expiry" => Ok(Self::MessageExpiry),
+            "connection_idle_timeout" => Ok(Self::ConnectionIdleTimeout),
+            "require_contact_request_stamp" => Ok(Self::RequireContactRequestStamp),
             _ => Err(()),
         }
     }
@@ -109,12 +248,27 @@ impl Client {
         // Create Tor Client.
         let tor_client = Self::bootstrap_tor_client().await?;
 
+        // In identity mode the onion service key is derived from the key of the user.
+        let identity_mode =
+            db::ConfigDb::get_bool("enable_onion_identity", db_conn.clone()).await?;
+        let identity_key = match identity_mode {
+            true => Some(Self::identity_private_key(db_conn.clone()).await?),
+            false => None,
+        };
+
         // Launch onion service.
-        let (onion_service, request_stream) = Self::launch_onion_service(&tor_client).await?;
+        let (onion_service, request_stream) =
+            Self::launch_onion_service(&tor_client, identity_key.as_ref()).await?;
         let request_stream = TokioMutex::new(Box::into_pin(request_stream));
 
-        // Generate new keypair.
-        let (private_key, public_key) = Self::generate_keypair();
+        // Generate new keypair, or reuse identity key.
+        let (private_key, public_key) = match &identity_key {
+            Some(key) => (
+                hex::encode(key.to_bytes()),
+                hex::encode(key.verifying_key().to_bytes()),
+            ),
+            None => Self::generate_keypair(),
+        };
 
         // Store user with newly generated keypair.
         // Note that if the user with the given onion_id already exists
@@ -131,56 +285,67 @@ impl Client {
 
         // Retrieve user again to get actual stored keypair.
         let user: db::UserDb = db::UserDb::retrieve(&onion_id, db_conn.clone()).await?;
-        let (private_key, _public_key) =
+        let (private_key, public_key) =
             Self::get_validated_keypair(&user.private_key, &user.public_key)?;
+        if identity_mode && !identity::is_bound_to_onion_id(&onion_id, &public_key) {
+            return Err(error::ClientError::IdentityKeyMismatch);
+        }
+
+        // Contacts can only start a session while we are offline with a signed prekey.
+        if db::PrekeyDb::latest(db_conn.clone()).await?.is_none() {
+            prekey::generate_signed_prekey(&onion_id, &private_key)
+                .insert(db_conn.clone())
+                .await?;
+        }
+
+        // Restore ratchet sessions from previous runs.
+        let ratchets = Self::load_sessions(db_conn.clone()).await?;
 
         tracing::info!("ArtiChat client launched.");
         Ok(Self {
-            tor_client: TokioMutex::new(tor_client),
+            tor_client: arc_swap::ArcSwap::from_pointee(tor_client),
             db_conn: db_conn.clone(),
             config: std::sync::Arc::new(TokioMutex::new(
                 ClientConfig::load(db_conn.clone()).await?,
             )),
             onion_service,
             request_stream,
-            private_key,
-            ratchets: std::sync::Arc::new(TokioMutex::new(std::collections::HashMap::new())),
+            private_key: TokioMutex::new(private_key),
+            identity_mode,
+            ratchets: std::sync::Arc::new(TokioMutex::new(ratchets)),
+            session_locks: peer_lock::PeerLocks::default(),
+            session_setups: peer_lock::PeerLocks::default(),
+            replay_cache: TokioMutex::new(replay::ReplayCache::default()),
+            pending_handshakes: TokioMutex::new(collision::PendingHandshakes::default()),
+            sent_resets: TokioMutex::new(reset::ResetThrottle::default()),
+            decrypt_failures: TokioMutex::new(reset::DecryptFailures::default()),
+            typing_throttle: TokioMutex::new(control::TypingThrottle::default()),
+            accepted_resets: TokioMutex::new(reset::ResetThrottle::default()),
+            received_requests: TokioMutex::new(contact_request::RequestThrottle::default()),
+            sent_requests: TokioMutex::new(contact_request::SentRequests::default()),
+            outbox_wakeup: tokio::sync::Notify::new(),
+            outbox_peers: TokioMutex::new(std::collections::HashSet::new()),
+            connections: connection::ConnectionPool::default(),
+            receipts: TokioMutex::new(receipt::ReceiptQueue::default()),
+            receipt_wakeup: tokio::sync::Notify::new(),
         })
     }
 
     /// Main entrypoint/loop to accept requests from our hidden onion service.
     pub async fn serve(
-        &self,
+        self: std::sync::Arc<Self>,
         message_tx: tokio::sync::mpsc::UnboundedSender<String>,
     ) -> Result<(), error::ClientError> {
         let mut request_stream = self.request_stream.lock().await;
         let requests = tor_hsservice::handle_rend_requests(&mut *request_stream);
         tokio::pin!(requests);
 
-        let ratchets = self.ratchets.clone();
-        let self_private_key = self.private_key.clone();
-        let self_onion_id = self.get_identity_unredacted()?;
-
         while let Some(request) = requests.next().await {
+            let client = self.clone();
             let message_tx = message_tx.clone();
-            let db_conn = self.db_conn.clone();
-            let client_config = self.config.clone();
-
-            let ratchets = ratchets.clone();
-            let self_private_key = self_private_key.clone();
-            let self_onion_id = self_onion_id.clone();
 
             tokio::spawn(async move {
-                let _ = Self::handle_request(
-                    request,
-                    message_tx,
-                    db_conn,
-                    client_config,
-                    self_onion_id,
-                    self_private_key,
-                    ratchets,
-                )
-                .await;
+                let _ = client.handle_request(request, message_tx).await;
             });
         }
 
@@ -192,85 +357,352 @@ impl Client {
     pub async fn send_message_to_peer(
         &self,
         to_onion_id: &str,
+        message_id: &str,
         message: MessageContent,
     ) -> Result<(), error::ClientError> {
         let self_onion_id = self.get_identity_unredacted()?;
 
         self.ensure_ratchet_exists(to_onion_id).await?;
-        let payload = ratchet::PlaintextPayload {
-            onion_id: self_onion_id.clone(),
-            timestamp: chrono::Utc::now().timestamp(),
+        let payload = ratchet::PlaintextPayload::new(
+            message_id,
+            &self_onion_id,
+            chrono::Utc::now().timestamp(),
             message,
+        );
+
+        let encrypted = self
+            .encrypt_for_peer(to_onion_id, self_onion_id, &payload)
+            .await?;
+
+        self.write_to_peer(
+            to_onion_id,
+            envelope::WireMessage::Message(Box::new(encrypted)),
+        )
+        .await
+    }
+
+    /// Pad and encrypt plaintext with the session of peer.
+    async fn encrypt_for_peer(
+        &self,
+        to_onion_id: &str,
+        self_onion_id: String,
+        plaintext: &impl serde::Serialize,
+    ) -> Result<ratchet::EncryptedMessage, error::ClientError> {
+        let padding_policy = self.config.lock().await.padding_policy;
+        let mut encoded = Vec::new();
+        ciborium::into_writer(plaintext, &mut encoded)?;
+        let plaintext = padding::pad(&encoded, padding_policy);
+
+        let _session = self.session_locks.lock(to_onion_id).await;
+        // Can be missing if a simultaneous handshake of peer failed halfway.
+        let mut ratchet = self
+            .session_of(to_onion_id)
+            .await
+            .ok_or(error::RatchetError::NoSession)?;
+        let encrypted = ratchet.encrypt(&plaintext, self_onion_id);
+
+        // Persist advanced send chain before the message leaves.
+        self.keep_session(to_onion_id, ratchet, None).await?;
+        Ok(encrypted)
+    }
+
+    /// Tell peer we started or stopped typing.
+    /// Does nothing if disabled, rate limited or if no session with peer exists yet.
+    pub async fn set_typing(
+        &self,
+        peer_onion_id: &str,
+        typing: bool,
+    ) -> Result<(), error::ClientError> {
+        if !self.config.lock().await.enable_typing_indicators {
+            return Ok(());
+        }
+
+        // Never set up a session just to send a typing indicator.
+        let established = self
+            .ratchets
+            .lock()
+            .await
+            .get(peer_onion_id)
+            .is_some_and(|ratchet| {
+                ratchet.pending_handshake.is_none()
+                    && ratchet.peer_supports(envelope::Capability::Typing)
+            });
+        if !established
+            || !self
+                .typing_throttle
+                .lock()
+                .await
+                .allow(peer_onion_id, typing)
+        {
+            return Ok(());
+        }
+
+        let self_onion_id = self.get_identity_unredacted()?;
+        let encrypted = self
+            .encrypt_for_peer(
+                peer_onion_id,
+                self_onion_id,
+                &control::ControlMessage::Typing { typing },
+            )
+            .await?;
+
+        self.write_to_peer(
+            peer_onion_id,
+            envelope::WireMessage::Control(Box::new(encrypted)),
+        )
+        .await
+    }
+
+    /// Introduce ourselves to a peer which does not know us yet.
+    pub async fn send_contact_request(
+        &self,
+        peer_onion_id: &str,
+        nickname: &str,
+        note: &str,
+    ) -> Result<(), error::ClientError> {
+        let self_onion_id = self.get_identity_unredacted()?;
+        let private_key = self.private_key().await;
+        // The key of a contact added before the request is known, so it can get a MAC.
+        let deniable_peer = match db::ContactDb::retrieve(peer_onion_id, self.db_conn.clone()).await
+        {
+            Ok(contact) if contact.deniable => {
+                Some(ratchet::verifying_key_from_hex(&contact.public_key)?)
+            }
+            _ => None,
         };
+        let (peer, nickname, note) = (
+            peer_onion_id.to_string(),
+            nickname.to_string(),
+            note.to_string(),
+        );
+
+        // Peers may drop requests without stamp, so always pay for one.
+        let request = tokio::task::spawn_blocking(move || {
+            contact_request::ContactRequest::new(
+                &self_onion_id,
+                &peer,
+                &nickname,
+                &note,
+                &private_key,
+                deniable_peer.as_ref(),
+                true,
+            )
+        })
+        .await?;
+
+        // Peer probes us right after receiving the request.
+        self.sent_requests.lock().await.insert(peer_onion_id);
+        self.write_to_peer(
+            peer_onion_id,
+            envelope::WireMessage::ContactRequest(Box::new(request)),
+        )
+        .await
+    }
 
-        let plaintext = serde_json::to_vec(&payload)?;
-        let encrypted = {
-            let mut ratchets = self.ratchets.lock().await;
-            let ratchet = ratchets
-                .get_mut(to_onion_id)
-                .expect("ratchet must exist after ensure_ratchet_exists");
-            ratchet.encrypt(&plaintext, self_onion_id)
+    /// Store outgoing message and queue it in the outbox.
+    /// Returns the message id, delivery happens in the outbox task.
+    pub async fn queue_message(
+        &self,
+        to_onion_id: &str,
+        message: &MessageContent,
+    ) -> Result<String, error::ClientError> {
+        let expiry = self.config.lock().await.message_expiry;
+        let msg = db::MessageDb {
+            id: 0,
+            message_id: message::new_message_id(),
+            contact_onion_id: to_onion_id.into(),
+            body: serde_json::to_string(message)?,
+            timestamp: chrono::Utc::now().timestamp() as i32,
+            is_incoming: false,
+            sent_status: false,
+            verified_status: false,
+            delivered_at: None,
+            read_at: None,
+            failed: false,
         };
+        let entry = db::OutboxDb::new(&msg.message_id, expiry);
+        msg.insert_queued(&entry, self.db_conn.clone()).await?;
 
-        let target = format!("{to_onion_id}:80");
-        let tor_client = self.tor_client.lock().await;
-        let mut stream = tor_client.connect(&target).await?;
+        self.outbox_wakeup.notify_one();
+        Ok(msg.message_id)
+    }
 
-        let mut msg = serde_json::to_string(&encrypted)?;
-        msg.push('\0');
-        stream.write_all(msg.as_bytes()).await?;
-        stream.flush().await?;
+    /// Start delivery of an unsent message over, after it failed or to skip the backoff.
+    pub async fn retry_message(&self, msg: &db::MessageDb) -> Result<(), error::ClientError> {
+        let expiry = self.config.lock().await.message_expiry;
+        db::OutboxDb::new(&msg.message_id, expiry)
+            .requeue(self.db_conn.clone())
+            .await?;
 
+        self.outbox_wakeup.notify_one();
         Ok(())
     }
 
-    /// Retry sending failed messages.
-    pub async fn retry_failed_messages(
+    /// Make one delivery attempt for queued message.
+    /// Returns the new status of the message.
+    async fn attempt_delivery(
         &self,
+        msg: &db::MessageDb,
+        entry: &db::OutboxDb,
+    ) -> Result<message::MessageStatus, error::ClientError> {
+        let now = chrono::Utc::now().timestamp();
+        if entry.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            // Written at least once, so it may well have arrived.
+            if entry.awaiting_receipt {
+                db::OutboxDb::complete(&msg.message_id, self.db_conn.clone()).await?;
+                return Ok(message::MessageStatus::Sent);
+            }
+            tracing::info!("Delivery of message {} expired", msg.id);
+            db::UpdateOutboxDb {
+                message_id: msg.message_id.clone(),
+                attempts: None,
+                next_attempt_at: None,
+                failed: Some(true),
+            }
+            .update(self.db_conn.clone())
+            .await?;
+            return Ok(message::MessageStatus::Failed);
+        }
+
+        let message: MessageContent = serde_json::from_str(&msg.body)?;
+        match self
+            .send_message_to_peer(&msg.contact_onion_id, &msg.message_id, message)
+            .await
+        {
+            // A write can succeed on a stream the peer already closed,
+            // so the entry is only removed by the delivery receipt.
+            Ok(())
+                if self
+                    .peer_supports(&msg.contact_onion_id, envelope::Capability::Receipts)
+                    .await =>
+            {
+                let until = entry.retry_at(now).max(now + outbox::RECEIPT_TIMEOUT);
+                entry.await_receipt(until, self.db_conn.clone()).await?;
+                Ok(message::MessageStatus::Sent)
+            }
+            Ok(()) => {
+                db::OutboxDb::complete(&msg.message_id, self.db_conn.clone()).await?;
+                Ok(message::MessageStatus::Sent)
+            }
+            Err(e) => {
+                tracing::info!("Delivery of message {} failed: {}", msg.id, e);
+                db::UpdateOutboxDb {
+                    message_id: msg.message_id.clone(),
+                    attempts: Some(entry.attempts + 1),
+                    next_attempt_at: Some(entry.retry_at(now)),
+                    failed: None,
+                }
+                .update(self.db_conn.clone())
+                .await?;
+                Ok(message::MessageStatus::Queued)
+            }
+        }
+    }
+
+    /// Deliver queued messages until the daemon stops.
+    /// Runs when a message is queued, and every `OUTBOX_INTERVAL` for retries.
+    pub async fn process_outbox(
+        self: std::sync::Arc<Self>,
         broadcast_writers: std::sync::Arc<TokioMutex<Vec<UnboundedSender<ipc::MessageToUI>>>>,
-    ) -> Result<(), error::ClientError> {
+    ) {
         loop {
-            let failed_messages = db::MessageDb::failed_messages(self.db_conn.clone()).await?;
-            for msg in &failed_messages {
-                tracing::info!("Retrying message {}", msg.id);
-
-                // Retry sending.
-                let message: MessageContent = serde_json::from_str(&msg.body)?;
-                let retry = self
-                    .send_message_to_peer(&msg.contact_onion_id, message)
-                    .await;
-
-                if retry.is_ok() {
-                    tracing::info!("Retry success message {}", msg.id);
-
-                    // On success update sent_status + update UI.
-                    db::UpdateMessageDb {
-                        id: msg.id,
-                        sent_status: Some(true),
-                    }
-                    .update(self.db_conn.clone())
-                    .await?;
+            if let Err(e) = self.deliver_due_messages(&broadcast_writers).await {
+                tracing::warn!("Failed to process outbox: {}", e);
+            }
+            self.connections.close_idle(self.idle_timeout().await).await;
 
-                    #[derive(serde::Serialize)]
-                    struct SendIncomingMessage {
-                        /// HsId from peer we received this message from.
-                        pub onion_id: String,
-                    }
-                    let incoming_message = SendIncomingMessage {
-                        onion_id: msg.contact_onion_id.clone(),
-                    };
-                    let incoming_message = serde_json::to_string(&incoming_message)? + "\n";
-                    let bw_writers = broadcast_writers.lock().await;
-                    for tx in bw_writers.iter() {
-                        let _ = tx.send(MessageToUI::Broadcast(incoming_message.clone()));
-                    }
-                } else {
-                    tracing::info!("Retry failed message {}", msg.id);
+            tokio::select! {
+                _ = self.outbox_wakeup.notified() => {}
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(outbox::OUTBOX_INTERVAL)) => {}
+            }
+        }
+    }
+
+    /// Start delivery to every peer with a message due, unless one is running for it.
+    /// Peers are served in parallel, so an offline peer does not hold up the others.
+    async fn deliver_due_messages(
+        self: &std::sync::Arc<Self>,
+        broadcast_writers: &std::sync::Arc<TokioMutex<Vec<UnboundedSender<ipc::MessageToUI>>>>,
+    ) -> Result<(), error::ClientError> {
+        for (peer_onion_id, entries) in db::OutboxDb::due(self.db_conn.clone()).await? {
+            // One delivery per peer at a time keeps its messages in order.
+            if !self.outbox_peers.lock().await.insert(peer_onion_id.clone()) {
+                continue;
+            }
+
+            let client = self.clone();
+            let broadcast_writers = broadcast_writers.clone();
+            tokio::spawn(async move {
+                if let Err(e) = client.deliver_in_order(&entries, &broadcast_writers).await {
+                    tracing::warn!("Failed to deliver queued messages: {}", e);
                 }
+                client.outbox_peers.lock().await.remove(&peer_onion_id);
+
+                // Messages queued to peer in the meantime were skipped.
+                client.outbox_wakeup.notify_one();
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Deliver queued messages of one peer in order, and notify UI of their status.
+    /// Stops at the first message which stays queued, so no newer message overtakes it.
+    async fn deliver_in_order(
+        &self,
+        entries: &[db::OutboxDb],
+        broadcast_writers: &TokioMutex<Vec<UnboundedSender<ipc::MessageToUI>>>,
+    ) -> Result<(), error::ClientError> {
+        let now = chrono::Utc::now().timestamp();
+        for (i, entry) in entries.iter().enumerate() {
+            // Written already, and still in time for its receipt.
+            if entry.awaiting_receipt && entry.next_attempt_at > now {
+                continue;
             }
 
-            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+            // Gone if cancelled since.
+            let Some(msg) =
+                db::MessageDb::retrieve_by_message_id(&entry.message_id, self.db_conn.clone())
+                    .await?
+            else {
+                continue;
+            };
+
+            // Shown as sent while it is written again.
+            if !entry.awaiting_receipt {
+                Self::broadcast_status(&msg, message::MessageStatus::Sending, broadcast_writers)
+                    .await?;
+            }
+            let status = self.attempt_delivery(&msg, entry).await?;
+            Self::broadcast_status(&msg, status, broadcast_writers).await?;
+
+            if status == message::MessageStatus::Queued {
+                // Newer messages wait for the retry of this one.
+                let retry_at = entry.retry_at(chrono::Utc::now().timestamp());
+                db::OutboxDb::postpone(&entries[i + 1..], retry_at, self.db_conn.clone()).await?;
+                break;
+            }
         }
+
+        Ok(())
+    }
+
+    /// Notify every UI of the new status of our message.
+    async fn broadcast_status(
+        msg: &db::MessageDb,
+        status: message::MessageStatus,
+        broadcast_writers: &TokioMutex<Vec<UnboundedSender<ipc::MessageToUI>>>,
+    ) -> Result<(), error::ClientError> {
+        let status_changed = ipc::MessageStatusChanged {
+            onion_id: msg.contact_onion_id.clone(),
+            message_ids: vec![msg.message_id.clone()],
+            status,
+        };
+        let status_changed = serde_json::to_string(&status_changed)? + "\n";
+        for tx in broadcast_writers.lock().await.iter() {
+            let _ = tx.send(MessageToUI::Broadcast(status_changed.clone()));
+        }
+        Ok(())
     }
 
     /// Get onion service identity unredacted.
@@ -282,13 +714,222 @@ impl Client {
             .map(|address| safelog::DispUnredacted(address).to_string())
     }
 
+    /// Get our signed prekey bundle, encoded to share with contacts.
+    pub async fn prekey_bundle(&self) -> Result<String, error::ClientError> {
+        let onion_id = self.get_identity_unredacted()?;
+        let prekey = db::PrekeyDb::latest(self.db_conn.clone())
+            .await?
+            .ok_or(error::RatchetError::InvalidPrekeyBundle)?;
+
+        Ok(prekey::bundle_from_db(&onion_id, &prekey)?.encode()?)
+    }
+
     /// Reset TorClient to connect over new circuit.
     pub async fn reset_tor_circuit(&self) -> Result<(), error::ClientError> {
         let mut prefs = arti_client::StreamPrefs::new();
         prefs.new_isolation_group();
 
-        let mut tor_client = self.tor_client.lock().await;
-        *tor_client = tor_client.clone_with_prefs(prefs);
+        // Streams already connecting keep the client they started with.
+        self.tor_client
+            .rcu(|tor_client| tor_client.clone_with_prefs(prefs.clone()));
+        // Open streams still use the old circuits.
+        self.connections.clear().await;
+
+        Ok(())
+    }
+
+    /// Forget ratchet sessions in memory.
+    /// Their rows are removed from the database together with the contact.
+    pub async fn forget_sessions(&self, onion_id: Option<&str>) {
+        let mut ratchets = self.ratchets.lock().await;
+        match onion_id {
+            Some(onion_id) => {
+                ratchets.remove(onion_id);
+            }
+            None => ratchets.clear(),
+        }
+    }
+
+    /// Replace identity key of user and announce it to all contacts.
+    /// The notice is signed by the old and new key, or MACed by both for deniable
+    /// contacts, and queued until delivered.
+    pub async fn rotate_identity_key(
+        &self,
+        new_private_key: SigningKey,
+    ) -> Result<(), error::ClientError> {
+        if self.identity_mode {
+            return Err(error::ClientError::KeyBoundToOnionAddress);
+        }
+        let onion_id = self.get_identity_unredacted()?;
+
+        {
+            let mut private_key = self.private_key.lock().await;
+            let signed_notice = serde_json::to_string(&rotation::KeyRotation::new(
+                &onion_id,
+                &private_key,
+                &new_private_key,
+                None,
+            ))?;
+            let notice_for = |contact: &db::ContactDb| {
+                let peer_public_key = ratchet::verifying_key_from_hex(&contact.public_key);
+                match peer_public_key {
+                    Ok(peer_public_key) if contact.deniable => {
+                        let notice = rotation::KeyRotation::new(
+                            &onion_id,
+                            &private_key,
+                            &new_private_key,
+                            Some(&peer_public_key),
+                        );
+                        serde_json::to_string(&notice)
+                            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+                    }
+                    _ => Ok(signed_notice.clone()),
+                }
+            };
+            db::KeyRotationDb::rotate(
+                &onion_id,
+                &hex::encode(new_private_key.to_bytes()),
+                &hex::encode(new_private_key.verifying_key().to_bytes()),
+                notice_for,
+                self.db_conn.clone(),
+            )
+            .await?;
+            *private_key = new_private_key.clone();
+        }
+
+        // Contacts verify our prekey bundle with the new key from now on.
+        prekey::generate_signed_prekey(&onion_id, &new_private_key)
+            .insert(self.db_conn.clone())
+            .await?;
+
+        tracing::info!("Identity key rotated.");
+        self.send_pending_key_rotations().await
+    }
+
+    /// Replace identity key of user by an imported keypair.
+    /// Does nothing if the key did not change.
+    pub async fn import_identity_key(
+        &self,
+        private_key: &str,
+        public_key: Option<&str>,
+    ) -> Result<(), error::ClientError> {
+        let private_key_b: [u8; SECRET_KEY_LENGTH] = hex::decode(private_key.trim())?
+            .try_into()
+            .map_err(|_| error::ClientError::InvalidKeyLength)?;
+        let new_private_key = SigningKey::from_bytes(&private_key_b);
+
+        if let Some(public_key) = public_key
+            && ratchet::verifying_key_from_hex(public_key.trim())?
+                != new_private_key.verifying_key()
+        {
+            return Err(error::ClientError::KeyPairMismatch);
+        }
+        if self.private_key().await.verifying_key() == new_private_key.verifying_key() {
+            return Ok(());
+        }
+
+        self.rotate_identity_key(new_private_key).await
+    }
+
+    /// Deliver queued key rotation notices until the daemon stops.
+    pub async fn process_key_rotations(&self) {
+        loop {
+            if let Err(e) = self.send_pending_key_rotations().await {
+                tracing::warn!("Failed to send key rotations: {}", e);
+            }
+
+            tokio::time::sleep(tokio::time::Duration::from_secs(outbox::OUTBOX_INTERVAL)).await;
+        }
+    }
+
+    /// Deliver queued key rotation notices, in order per contact.
+    pub async fn send_pending_key_rotations(&self) -> Result<(), error::ClientError> {
+        let pending = db::KeyRotationDb::pending(self.db_conn.clone()).await?;
+
+        let mut unreachable = std::collections::HashSet::new();
+        for notice in &pending {
+            // A later notice can only be verified after the earlier one.
+            if unreachable.contains(&notice.contact_onion_id) {
+                continue;
+            }
+
+            // An unreadable notice can never be delivered, so it must not block later ones.
+            let Some(key_rotation) = rotation::KeyRotation::from_stored(&notice.notice) else {
+                tracing::warn!(
+                    "Dropping unreadable key rotation notice for {}.",
+                    notice.contact_onion_id
+                );
+                db::KeyRotationDb::delete(&notice.id.to_string(), self.db_conn.clone()).await?;
+                continue;
+            };
+            match self
+                .write_to_peer(
+                    &notice.contact_onion_id,
+                    envelope::WireMessage::KeyRotation(key_rotation),
+                )
+                .await
+            {
+                Ok(()) => {
+                    db::KeyRotationDb::delete(&notice.id.to_string(), self.db_conn.clone()).await?;
+                }
+                Err(_) => {
+                    unreachable.insert(notice.contact_onion_id.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if onion service identity is derived from our key.
+    pub fn is_identity_mode(&self) -> bool {
+        self.identity_mode
+    }
+
+    /// Compute safety number of conversation with contact.
+    pub async fn safety_number(
+        &self,
+        peer_onion_id: &str,
+    ) -> Result<safety::SafetyNumber, error::ClientError> {
+        let self_onion_id = self.get_identity_unredacted()?;
+        let peer = db::ContactDb::retrieve(peer_onion_id, self.db_conn.clone()).await?;
+        let peer_public_key = ratchet::verifying_key_from_hex(&peer.public_key)?;
+
+        Ok(safety::SafetyNumber::new(
+            &self_onion_id,
+            &self.private_key().await.verifying_key(),
+            peer_onion_id,
+            &peer_public_key,
+        ))
+    }
+
+    /// Choose between signed and deniable handshakes with contact.
+    /// The current session was set up with the old choice and is dropped,
+    /// so the next message sets up a new one.
+    pub async fn set_contact_deniable(
+        &self,
+        onion_id: &str,
+        deniable: bool,
+    ) -> Result<(), error::ClientError> {
+        let contact = db::ContactDb::retrieve(onion_id, self.db_conn.clone()).await?;
+        if contact.deniable == deniable {
+            return Ok(());
+        }
+
+        db::ContactDb::set_deniable(onion_id, deniable, self.db_conn.clone()).await?;
+        self.drop_session(onion_id).await
+    }
+
+    /// Reset broken session with peer.
+    /// Drops our session and asks peer to re-handshake and resend what we missed.
+    pub async fn reset_session(&self, peer_onion_id: &str) -> Result<(), error::ClientError> {
+        self.drop_session(peer_onion_id).await?;
+        self.sent_resets.lock().await.allow(peer_onion_id);
+
+        // If peer is offline, the next message we send sets up a new session anyway.
+        if let Err(e) = self.send_session_reset(peer_onion_id).await {
+            tracing::warn!("Failed to send session reset: {}", e);
+        }
 
         Ok(())
     }
@@ -305,7 +946,7 @@ impl Client {
     pub async fn is_reachable(&self) -> Result<bool, error::ClientError> {
         let onion_id = self.get_identity_unredacted()?;
         let target = format!("{}:80", onion_id);
-        let tor_client = self.tor_client.lock().await;
+        let tor_client = self.tor_client.load_full();
         let connect_future = tor_client.connect(target);
         let result = tokio::time::timeout(std::time::Duration::from_secs(45), connect_future).await;
 
@@ -331,8 +972,10 @@ impl Client {
     }
 
     /// Launch our hidden service.
+    /// With identity key the service identity is derived from it.
     async fn launch_onion_service(
         client: &ArtiTorClient,
+        identity_key: Option<&SigningKey>,
     ) -> Result<
         (
             std::sync::Arc<tor_hsservice::RunningOnionService>,
@@ -340,17 +983,49 @@ impl Client {
         ),
         error::ClientError,
     > {
+        let nickname = match identity_key {
+            Some(_) => identity::IDENTITY_SERVICE_NICKNAME,
+            None => "arti-chat-service",
+        };
         let config = OnionServiceConfigBuilder::default()
-            .nickname("arti-chat-service".parse()?)
+            .nickname(nickname.parse()?)
             .build()?;
 
-        let (onion_service, request_stream) = match client.launch_onion_service(config)? {
-            Some(v) => v,
-            None => {
-                return Err(error::ClientError::OnionServiceDisabled);
+        // Identity key is only inserted on first launch, after that it is in the keystore.
+        let launched_with_key = match identity_key {
+            Some(key) => {
+                match client
+                    .launch_onion_service_with_hsid(config.clone(), identity::hs_id_keypair(key))
+                {
+                    Ok(launched) => {
+                        Some(launched.map(|(s, r)| (s, Box::new(r) as OnionServiceRequestStream)))
+                    }
+                    Err(e) => {
+                        tracing::debug!("Identity key not inserted, using keystore: {}", e);
+                        None
+                    }
+                }
             }
+            None => None,
+        };
+
+        let launched = match launched_with_key {
+            Some(launched) => launched,
+            None => client
+                .launch_onion_service(config)?
+                .map(|(s, r)| (s, Box::new(r) as OnionServiceRequestStream)),
+        };
+        let Some((onion_service, request_stream)) = launched else {
+            return Err(error::ClientError::OnionServiceDisabled);
         };
-        let request_stream = Box::new(request_stream);
+
+        // Keystore may hold another key, e.g. if it was not inserted for another reason.
+        if let Some(key) = identity_key {
+            let onion_id = Self::get_identity_unredacted_inner(onion_service.onion_address())?;
+            if !identity::is_bound_to_onion_id(&onion_id, &key.verifying_key()) {
+                return Err(error::ClientError::IdentityKeyMismatch);
+            }
+        }
 
         tracing::info!("Onion service launched.");
 
@@ -401,110 +1076,928 @@ impl Client {
         ))
     }
 
-    /// Handle request from client to open new stream to our onion service.
-    async fn handle_request(
-        request: tor_hsservice::StreamRequest,
-        message_tx: tokio::sync::mpsc::UnboundedSender<String>, // Used to send incoming messages
-        // to IPC server.
+    /// Key to derive onion service identity from in identity mode.
+    /// Reuses the key of the user whose onion id is bound to it by a previous launch.
+    async fn identity_private_key(
         db_conn: DatabaseConnection,
-        client_config: ClientConfigType,
-        my_onion_id: String,
-        private_key: SigningKey,
-        ratchets: std::sync::Arc<
-            tokio::sync::Mutex<std::collections::HashMap<String, ratchet::RatchetChain>>,
-        >,
-    ) -> Result<(), error::ClientError> {
-        match request.request() {
-            IncomingStreamRequest::Begin(begin) if begin.port() == 80 => {
-                let mut stream = request.accept(Connected::new_empty()).await?;
-                let body = ratchet::read_null_terminated(&mut stream).await?;
-                if body.is_empty() {
-                    return Ok(());
+    ) -> Result<SigningKey, error::ClientError> {
+        let users = db::UserDb::retrieve_all(None, None, db_conn).await?;
+        for user in users {
+            let (private_key, public_key) =
+                Self::get_validated_keypair(&user.private_key, &user.public_key)?;
+            if identity::is_bound_to_onion_id(&user.onion_id, &public_key) {
+                return Ok(private_key);
+            }
+        }
+        Ok(SigningKey::generate(&mut rand_core::OsRng))
+    }
+
+    /// Load persisted ratchet sessions from database.
+    async fn load_sessions(
+        db_conn: DatabaseConnection,
+    ) -> Result<std::collections::HashMap<String, ratchet::RatchetChain>, error::ClientError> {
+        let sessions = db::SessionDb::retrieve_all(None, None, db_conn).await?;
+
+        let mut ratchets = std::collections::HashMap::new();
+        for mut session in sessions {
+            match serde_json::from_str::<ratchet::RatchetChain>(&session.state) {
+                Ok(ratchet) => {
+                    ratchets.insert(session.contact_onion_id.clone(), ratchet);
                 }
+                Err(e) => tracing::warn!(
+                    "Discarding unreadable session of {}: {}",
+                    session.contact_onion_id,
+                    e
+                ),
+            }
+            session.state.zeroize();
+        }
 
-                // Handshake initiated by sender.
-                if let Ok(handshake) = serde_json::from_str::<ratchet::Handshake>(&body) {
-                    if handshake.to != my_onion_id {
-                        return Ok(());
-                    }
+        tracing::info!("Restored {} ratchet sessions.", ratchets.len());
+        Ok(ratchets)
+    }
 
-                    let peer = db::ContactDb::retrieve(&handshake.from, db_conn.clone()).await?;
-                    let peer_public_key = ratchet::verifying_key_from_hex(&peer.public_key)?;
-
-                    let (response_handshake, ephemeral_secret) =
-                        handshake.accept(&my_onion_id, &peer_public_key, &private_key)?;
-                    let mut response_handshake_payload =
-                        serde_json::to_string(&response_handshake)?;
-                    response_handshake_payload.push('\0');
-                    stream
-                        .write_all(response_handshake_payload.as_bytes())
-                        .await?;
-                    stream.flush().await?;
-
-                    let ratchet_chain = handshake.complete(
-                        &my_onion_id,
-                        &peer_public_key,
-                        &ephemeral_secret,
-                        false,
-                    )?;
-
-                    let mut ratchets = ratchets.lock().await;
-                    ratchets.insert(handshake.from.clone(), ratchet_chain);
-
-                    return Ok(());
-                }
+    /// Current session with peer, if any.
+    async fn session_of(&self, peer_onion_id: &str) -> Option<ratchet::RatchetChain> {
+        self.ratchets.lock().await.get(peer_onion_id).cloned()
+    }
+
+    /// Persist session of peer and make it current, optionally together with a message.
+    /// Callers hold the session lock of peer, so sessions never diverge from the database.
+    async fn keep_session(
+        &self,
+        peer_onion_id: &str,
+        ratchet: ratchet::RatchetChain,
+        message: Option<&db::MessageDb>,
+    ) -> Result<Option<db::InsertId>, error::ClientError> {
+        let insert_id =
+            Self::store_session(peer_onion_id, &ratchet, message, self.db_conn.clone()).await?;
+        self.ratchets
+            .lock()
+            .await
+            .insert(peer_onion_id.into(), ratchet);
+        Ok(insert_id)
+    }
+
+    /// Persist ratchet session of contact, optionally together with a message in one transaction.
+    /// Returns the insert id of the message if one was given.
+    async fn store_session(
+        contact_onion_id: &str,
+        ratchet: &ratchet::RatchetChain,
+        message: Option<&db::MessageDb>,
+        db_conn: DatabaseConnection,
+    ) -> Result<Option<db::InsertId>, error::ClientError> {
+        let mut session = db::SessionDb {
+            contact_onion_id: contact_onion_id.into(),
+            state: serde_json::to_string(ratchet)?,
+            updated_at: chrono::Utc::now().timestamp(),
+        };
+        let result = session.save(message, db_conn).await;
+        session.state.zeroize();
+
+        Ok(result?)
+    }
+
+    /// Signed prekey bundle to attach to our handshakes.
+    async fn own_prekey_bundle(&self) -> Option<prekey::PrekeyBundle> {
+        let onion_id = self.get_identity_unredacted().ok()?;
+        let prekey = db::PrekeyDb::latest(self.db_conn.clone()).await.ok()??;
+        prekey::bundle_from_db(&onion_id, &prekey).ok()
+    }
+
+    /// Remember verified prekey bundle which peer attached to its handshake.
+    async fn store_peer_prekey_bundle(
+        &self,
+        handshake: &ratchet::Handshake,
+        peer_public_key: &VerifyingKey,
+    ) -> Result<(), error::ClientError> {
+        let Some(bundle) = &handshake.prekey_bundle else {
+            return Ok(());
+        };
+        bundle.verify(&handshake.from, peer_public_key)?;
+
+        db::UpdateContactDb {
+            onion_id: handshake.from.clone(),
+            nickname: None,
+            public_key: None,
+            prekey_bundle: Some(bundle.encode()?),
+        }
+        .update(self.db_conn.clone())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Derive session from prekey handshake attached to an incoming message.
+    async fn accept_prekey_handshake(
+        &self,
+        handshake: &ratchet::Handshake,
+        sender_onion_id: &str,
+        existing: Option<&ratchet::RatchetChain>,
+        my_onion_id: &str,
+    ) -> Result<ratchet::RatchetChain, error::ClientError> {
+        let prekey_id = handshake
+            .prekey_id
+            .ok_or(error::RatchetError::InvalidPrekeyBundle)?;
+        if handshake.from != sender_onion_id {
+            return Err(error::RatchetError::InvalidPrekeyBundle.into());
+        }
+
+        // Prekey handshakes live long, so replays are rejected by only
+        // accepting handshakes newer than the one of the current session.
+        if existing.is_some_and(|r| handshake.timestamp <= r.peer_handshake_timestamp) {
+            return Err(error::RatchetError::ReplayedHandshake.into());
+        }
+
+        // Prekey handshakes have no ML-KEM part.
+        if self.config.lock().await.require_post_quantum {
+            replay::log_security_event(sender_onion_id, &error::RatchetError::PostQuantumRequired);
+            return Err(error::RatchetError::PostQuantumRequired.into());
+        }
+
+        let peer = db::ContactDb::retrieve(sender_onion_id, self.db_conn.clone()).await?;
+        let peer_public_key = ratchet::verifying_key_from_hex(&peer.public_key)?;
 
-                // Encrypted message received by sender.
-                let encrypted: ratchet::EncryptedMessage = serde_json::from_str(&body)?;
-                let plaintext = {
-                    let mut ratchets = ratchets.lock().await;
-                    let ratchet = ratchets
-                        .get_mut(&encrypted.from)
-                        .ok_or_else(|| error::ClientError::ArtiBug)?;
-                    ratchet.decrypt(&encrypted)?
-                };
-
-                let client_config = client_config.lock().await;
-
-                let payload: ratchet::PlaintextPayload = serde_json::from_slice(&plaintext)?;
-                let message = match payload.message.clone() {
-                    // Reencode bytes for image and do size checks.
-                    MessageContent::Image { data } => {
-                        if !client_config.enable_attachments {
-                            MessageContent::Error {
-                                message: "Receiving attachments is disabled in settings."
-                                    .to_string(),
-                            }
-                        } else {
-                            MessageContent::Image {
-                                data: attachment::reencode_bytes(&data)?,
-                            }
+        let mut prekey = db::PrekeyDb::retrieve_by_id(prekey_id, self.db_conn.clone()).await?;
+        let prekey_secret = prekey::secret_from_db(&prekey);
+        prekey.private_key.zeroize();
+
+        let ratchet = handshake.accept_with_prekey(
+            my_onion_id,
+            &peer_public_key,
+            &self.private_key().await,
+            &prekey_secret?,
+        )?;
+
+        // Both started a session with a prekey at the same time, only one is kept.
+        if existing.is_some_and(|r| r.pending_handshake.is_some())
+            && collision::initiator_wins(my_onion_id, sender_onion_id)
+        {
+            tracing::info!("Ignoring simultaneous prekey handshake of peer, ours wins.");
+            return Err(error::RatchetError::HandshakeCollision.into());
+        }
+
+        // Nonces outlive the session, so a reset does not allow replaying the handshake.
+        let expires_at = handshake.timestamp + ratchet::MAX_PREKEY_HANDSHAKE_AGE;
+        if !db::PrekeyDb::record_handshake_nonce(&handshake.nonce, expires_at, self.db_conn.clone())
+            .await?
+        {
+            return Err(error::RatchetError::ReplayedHandshake.into());
+        }
+
+        Ok(ratchet)
+    }
+
+    /// Options of handshakes with peer from current config.
+    async fn handshake_options(&self, deniable: bool) -> ratchet::HandshakeOptions {
+        let config = self.config.lock().await;
+        ratchet::HandshakeOptions {
+            post_quantum: config.enable_post_quantum,
+            require_post_quantum: config.require_post_quantum,
+            deniable,
+        }
+    }
+
+    /// Check if we authenticate deniably with peer: if wanted and peer is not known to lack support.
+    async fn is_deniable_with(&self, peer: &db::ContactDb) -> bool {
+        peer.deniable
+            && self
+                .peer_supports(&peer.onion_id, envelope::Capability::Deniable)
+                .await
+    }
+
+    /// Current private key of user.
+    async fn private_key(&self) -> SigningKey {
+        self.private_key.lock().await.clone()
+    }
+
+    /// Check if session with peer allows an optional feature.
+    /// Assumed supported while there is no session yet.
+    pub async fn peer_supports(
+        &self,
+        peer_onion_id: &str,
+        capability: envelope::Capability,
+    ) -> bool {
+        self.ratchets
+            .lock()
+            .await
+            .get(peer_onion_id)
+            .is_none_or(|ratchet| ratchet.peer_supports(capability))
+    }
+
+    /// Write a single frame to peer, over its open stream if any.
+    async fn write_to_peer(
+        &self,
+        peer_onion_id: &str,
+        message: envelope::WireMessage,
+    ) -> Result<(), error::ClientError> {
+        let tor_client = self.tor_client.load_full();
+        let idle_timeout = self.idle_timeout().await;
+
+        self.connections
+            .send(&tor_client, peer_onion_id, idle_timeout, message)
+            .await
+    }
+
+    /// Time an unused stream to a peer is kept open.
+    async fn idle_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.config.lock().await.connection_idle_timeout)
+    }
+
+    /// Handle key rotation of peer: replace its key only if authenticated by old and new key.
+    async fn handle_key_rotation(
+        &self,
+        key_rotation: rotation::KeyRotation,
+    ) -> Result<(), error::ClientError> {
+        let peer = db::ContactDb::retrieve(&key_rotation.from, self.db_conn.clone()).await?;
+        let current_public_key = ratchet::verifying_key_from_hex(&peer.public_key)?;
+        let self_identity = deniable::identity_secret(&self.private_key().await);
+
+        let new_public_key = key_rotation
+            .verify(&current_public_key, &self_identity)
+            .inspect_err(|e| replay::log_security_event(&key_rotation.from, e))?;
+        db::ContactDb::rotate_public_key(
+            &key_rotation.from,
+            &hex::encode(new_public_key.as_bytes()),
+            self.db_conn.clone(),
+        )
+        .await?;
+
+        tracing::info!("Contact rotated its identity key.");
+        Ok(())
+    }
+
+    /// Handle contact request of peer: quarantine it until the user decides, and notify UI.
+    async fn handle_contact_request(
+        &self,
+        request: contact_request::ContactRequest,
+        message_tx: tokio::sync::mpsc::UnboundedSender<String>,
+        my_onion_id: &str,
+    ) -> Result<(), error::ClientError> {
+        // Contacts change keys by rotation only.
+        if db::ContactDb::retrieve(&request.from, self.db_conn.clone())
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+
+        let require_stamp = self.config.lock().await.require_contact_request_stamp;
+        let self_identity = deniable::identity_secret(&self.private_key().await);
+        let public_key = request
+            .verify(my_onion_id, &self_identity, require_stamp)
+            .inspect_err(|e| replay::log_security_event(&request.from, e))?;
+        // Checked after verifying, so forged requests don't block the peer.
+        if !self.received_requests.lock().await.allow(&request.from) {
+            tracing::info!("Ignoring contact request, peer sent one recently.");
+            return Ok(());
+        }
+
+        // Anyone can claim any onion id, so its owner has to confirm the key.
+        let verified = identity::is_bound_to_onion_id(&request.from, &public_key)
+            || self.probe_key_owner(&request).await;
+        if !verified {
+            tracing::info!(
+                "Owner of onion id did not confirm contact request, storing it unverified."
+            );
+        }
+
+        let stored = db::ContactRequestDb {
+            onion_id: request.from.clone(),
+            public_key: hex::encode(public_key.as_bytes()),
+            nickname: request.nickname,
+            note: request.note,
+            received_at: chrono::Utc::now().timestamp(),
+            verified,
+        }
+        .store(contact_request::MAX_PENDING_REQUESTS, self.db_conn.clone())
+        .await?;
+        match stored {
+            db::StoredRequest::Stored => {}
+            db::StoredRequest::ConflictingKey => {
+                let e = error::RatchetError::ConflictingContactRequest;
+                replay::log_security_event(&request.from, &e);
+                return Err(e.into());
+            }
+        }
+
+        let received = ipc::ContactRequestReceived {
+            onion_id: request.from,
+        };
+        let _ = message_tx.send(serde_json::to_string(&received)?);
+        Ok(())
+    }
+
+    /// Ask the onion service a request claims to be from to prove it holds the key.
+    /// Returns false if it did not, e.g. since it is offline or did not send the request.
+    async fn probe_key_owner(&self, request: &contact_request::ContactRequest) -> bool {
+        let private_key = self.private_key().await;
+        let probe = contact_request::KeyProbe::new(request, &private_key.verifying_key());
+        let tor_client = self.tor_client.load_full();
+        let idle_timeout = self.idle_timeout().await;
+
+        let reply = self
+            .connections
+            .request(
+                &tor_client,
+                &request.from,
+                idle_timeout,
+                envelope::WireMessage::KeyProbe(Box::new(probe.clone())),
+            )
+            .await;
+        match reply {
+            Ok(envelope::WireMessage::KeyProof(proof)) => probe
+                .verify(&proof, &deniable::identity_secret(&private_key))
+                .inspect_err(|e| replay::log_security_event(&request.from, e))
+                .is_ok(),
+            Ok(_) => false,
+            Err(e) => {
+                tracing::debug!("Key probe of contact request failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Answer key probe of a peer we recently sent a contact request to.
+    async fn handle_key_probe(
+        &self,
+        probe: contact_request::KeyProbe,
+        stream: &mut frame::WireStream<impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>,
+        my_onion_id: &str,
+    ) -> Result<(), error::ClientError> {
+        if probe.to != my_onion_id {
+            return Err(error::RatchetError::InvalidHandshakeTarget.into());
+        }
+        if !self.sent_requests.lock().await.contains(&probe.from) {
+            return Err(error::ClientError::UnexpectedMessageKind);
+        }
+
+        // A contact gets the proof for the key we know, not the one it claims.
+        let prober_public_key =
+            match db::ContactDb::retrieve(&probe.from, self.db_conn.clone()).await {
+                Ok(contact) => contact.public_key,
+                Err(_) => probe.prober_public_key.clone(),
+            };
+        let proof = probe.prove(
+            &self.private_key().await,
+            &ratchet::verifying_key_from_hex(&prober_public_key)?,
+        )?;
+
+        stream.send(envelope::WireMessage::KeyProof(proof)).await
+    }
+
+    /// Drop session with peer from memory and database.
+    async fn drop_session(&self, peer_onion_id: &str) -> Result<(), error::ClientError> {
+        let _session = self.session_locks.lock(peer_onion_id).await;
+        self.ratchets.lock().await.remove(peer_onion_id);
+        db::SessionDb::delete(peer_onion_id, self.db_conn.clone()).await?;
+
+        Ok(())
+    }
+
+    /// Send session reset to peer, signed or with a MAC if peer is deniable.
+    async fn send_session_reset(&self, peer_onion_id: &str) -> Result<(), error::ClientError> {
+        let self_onion_id = self.get_identity_unredacted()?;
+        let peer = db::ContactDb::retrieve(peer_onion_id, self.db_conn.clone()).await?;
+        let peer_public_key = ratchet::verifying_key_from_hex(&peer.public_key)?;
+        let since =
+            db::MessageDb::last_incoming_timestamp(peer_onion_id, self.db_conn.clone()).await?;
+        let session_reset = reset::SessionReset::new(
+            &self_onion_id,
+            peer_onion_id,
+            since,
+            &self.private_key().await,
+            &peer_public_key,
+            self.is_deniable_with(&peer).await,
+        );
+
+        self.write_to_peer(
+            peer_onion_id,
+            envelope::WireMessage::SessionReset(session_reset),
+        )
+        .await
+    }
+
+    /// Ask peer to reset session after its message could not be decrypted.
+    /// Throttled per peer, so two broken sessions do not reset each other in a loop.
+    async fn request_session_reset(&self, peer_onion_id: &str) {
+        // Sender of an undecryptable message is not authenticated,
+        // so never connect to onion ids which are not a contact.
+        if db::ContactDb::retrieve(peer_onion_id, self.db_conn.clone())
+            .await
+            .is_err()
+        {
+            return;
+        }
+        if !self.sent_resets.lock().await.allow(peer_onion_id) {
+            return;
+        }
+
+        tracing::info!("Requesting session reset from peer.");
+        if let Err(e) = self.send_session_reset(peer_onion_id).await {
+            tracing::warn!("Failed to send session reset: {}", e);
+        }
+    }
+
+    /// Handle session reset of peer: drop session and resend messages peer missed.
+    async fn handle_session_reset(
+        &self,
+        session_reset: reset::SessionReset,
+        my_onion_id: &str,
+    ) -> Result<(), error::ClientError> {
+        let peer = db::ContactDb::retrieve(&session_reset.from, self.db_conn.clone()).await?;
+        let peer_public_key = ratchet::verifying_key_from_hex(&peer.public_key)?;
+        let self_identity = deniable::identity_secret(&self.private_key().await);
+
+        session_reset
+            .verify(my_onion_id, &peer_public_key, &self_identity)
+            .inspect_err(|e| replay::log_security_event(&session_reset.from, e))?;
+        if let Err(e) = self
+            .replay_cache
+            .lock()
+            .await
+            .check_nonce(session_reset.nonce, session_reset.timestamp)
+        {
+            replay::log_security_event(&session_reset.from, &e);
+            return Err(e.into());
+        }
+        if !self.accepted_resets.lock().await.allow(&session_reset.from) {
+            tracing::info!("Ignoring session reset, peer reset recently.");
+            return Ok(());
+        }
+
+        tracing::info!("Session reset requested by peer.");
+        self.drop_session(&session_reset.from).await?;
+
+        // Resend with a new session, which is set up by sending the first message.
+        self.resend_since(&session_reset.from, session_reset.since)
+            .await
+    }
+
+    /// Resend messages sent to peer after timestamp, limited to `MAX_RESEND_AGE`.
+    async fn resend_since(
+        &self,
+        peer_onion_id: &str,
+        since: i64,
+    ) -> Result<(), error::ClientError> {
+        let since = since.max(chrono::Utc::now().timestamp() - reset::MAX_RESEND_AGE);
+        let messages =
+            db::MessageDb::sent_since(peer_onion_id, since, self.db_conn.clone()).await?;
+        for msg in &messages {
+            // Peer lost these messages, so their delivery starts over.
+            self.retry_message(msg).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle handshake initiated by peer and reply with our handshake.
+    async fn handle_handshake(
+        &self,
+        handshake: ratchet::Handshake,
+        stream: &mut frame::WireStream<impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>,
+        my_onion_id: &str,
+    ) -> Result<(), error::ClientError> {
+        let db_conn = self.db_conn.clone();
+
+        if handshake.to != my_onion_id {
+            return Ok(());
+        }
+
+        let peer = db::ContactDb::retrieve(&handshake.from, db_conn.clone()).await?;
+        let peer_public_key = ratchet::verifying_key_from_hex(&peer.public_key)?;
+
+        let options = self.handshake_options(peer.deniable).await;
+        let (mut response_handshake, handshake_secret) = handshake
+            .accept(
+                my_onion_id,
+                &peer_public_key,
+                &self.private_key().await,
+                options,
+            )
+            .inspect_err(|e| replay::log_security_event(&handshake.from, e))?;
+        response_handshake.prekey_bundle = self.own_prekey_bundle().await;
+
+        // A replayed handshake would replace the live session.
+        if let Err(e) = self.replay_cache.lock().await.check_handshake(&handshake) {
+            replay::log_security_event(&handshake.from, &e);
+            return Err(e.into());
+        }
+
+        // Both initiated at the same time, only one of the handshakes may be answered.
+        if self
+            .pending_handshakes
+            .lock()
+            .await
+            .resolve(my_onion_id, &handshake.from)
+            == collision::Resolution::Reject
+        {
+            tracing::info!("Ignoring simultaneous handshake of peer, ours wins.");
+            return Err(error::RatchetError::HandshakeCollision.into());
+        }
+        stream
+            .send(envelope::WireMessage::Handshake(Box::new(
+                response_handshake,
+            )))
+            .await?;
+
+        let ratchet_chain =
+            handshake.complete(my_onion_id, &peer_public_key, &handshake_secret, false)?;
+
+        let session = self.session_locks.lock(&handshake.from).await;
+        self.keep_session(&handshake.from, ratchet_chain, None)
+            .await?;
+        drop(session);
+
+        if let Err(e) = self
+            .store_peer_prekey_bundle(&handshake, &peer_public_key)
+            .await
+        {
+            tracing::warn!("Ignoring prekey bundle of peer: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Handle encrypted message of peer.
+    async fn handle_encrypted_message(
+        &self,
+        encrypted: ratchet::EncryptedMessage,
+        message_tx: tokio::sync::mpsc::UnboundedSender<String>,
+        my_onion_id: &str,
+    ) -> Result<(), error::ClientError> {
+        let db_conn = self.db_conn.clone();
+
+        let (enable_attachments, enable_notifications) = {
+            let client_config = self.config.lock().await;
+            (
+                client_config.enable_attachments,
+                client_config.enable_notifications,
+            )
+        };
+
+        // Hold the session lock of peer until the advanced session and the message
+        // are stored, so memory and database never diverge.
+        let session = self.session_locks.lock(&encrypted.header.from).await;
+        let existing = self.session_of(&encrypted.header.from).await;
+        let (mut ratchet, superseded_since, from_handshake) = match &encrypted.prekey_handshake {
+            // Sender started a new session with our signed prekey.
+            Some(handshake)
+                if existing
+                    .as_ref()
+                    .is_none_or(|r| r.handshake_nonce != handshake.nonce) =>
+            {
+                // Our unanswered prekey session is replaced if the peer won a collision.
+                let superseded_since = existing
+                    .as_ref()
+                    .and_then(|r| r.pending_handshake.as_ref())
+                    .map(|h| h.timestamp);
+                let ratchet = self
+                    .accept_prekey_handshake(
+                        handshake,
+                        &encrypted.header.from,
+                        existing.as_ref(),
+                        my_onion_id,
+                    )
+                    .await
+                    .inspect_err(|e| {
+                        if let error::ClientError::RatchetError(e) = e {
+                            replay::log_security_event(&encrypted.header.from, e);
                         }
+                    })?;
+                (ratchet, superseded_since, true)
+            }
+            _ => match existing {
+                Some(ratchet) => (ratchet, None, false),
+                None => {
+                    drop(session);
+                    self.request_session_reset(&encrypted.header.from).await;
+                    return Err(error::RatchetError::NoSession.into());
+                }
+            },
+        };
+        let plaintext = match ratchet.decrypt(&encrypted) {
+            Ok(plaintext) => plaintext,
+            Err(error::RatchetError::VersionMismatch) => {
+                // The version is checked before the message authenticates, so the frame
+                // is discarded and the session kept. An outdated session is replaced by
+                // the authenticated handshake of whoever sends next.
+                tracing::debug!("Discarding message of other ratchet version.");
+                return Err(error::RatchetError::VersionMismatch.into());
+            }
+            Err(error::RatchetError::MessageDecryptError) => {
+                let e = error::RatchetError::MessageDecryptError;
+                replay::log_security_event(&encrypted.header.from, &e);
+                drop(session);
+                // The message does not authenticate, so a working session is only reset
+                // after repeated failures or if the peer's own signed handshake failed.
+                if self
+                    .decrypt_failures
+                    .lock()
+                    .await
+                    .record(&encrypted.header.from)
+                    || from_handshake
+                {
+                    self.request_session_reset(&encrypted.header.from).await;
+                }
+                return Err(e.into());
+            }
+            Err(e) => {
+                replay::log_security_event(&encrypted.header.from, &e);
+                return Err(e.into());
+            }
+        };
+
+        self.decrypt_failures
+            .lock()
+            .await
+            .clear(&encrypted.header.from);
+
+        let plaintext = padding::unpad(plaintext)?;
+        let payload: ratchet::PlaintextPayload = ciborium::from_reader(plaintext.as_slice())?;
+        payload.check_version()?;
+        payload
+            .check_sender(&encrypted.header)
+            .inspect_err(|e| replay::log_security_event(&encrypted.header.from, e))?;
+        if !message::is_valid_message_id(&payload.message_id) {
+            return Err(error::ClientError::InvalidMessageId);
+        }
+
+        // Receipts only update the messages we sent.
+        if let MessageContent::Receipt { kind, message_ids } = payload.message {
+            self.keep_session(&encrypted.header.from, ratchet, None)
+                .await?;
+            drop(session);
+
+            if let Some(since) = superseded_since {
+                self.resend_since(&encrypted.header.from, since - 1).await?;
+            }
+            return self
+                .handle_receipt(&encrypted.header.from, kind, &message_ids, &message_tx)
+                .await;
+        }
+
+        // Only verified if the session was set up with the key the user verified.
+        let verified_status = db::ContactDb::retrieve(&encrypted.header.from, db_conn.clone())
+            .await
+            .is_ok_and(|contact| {
+                contact.is_verified() && contact.public_key == ratchet.peer_public_key
+            });
+        let message = match payload.message.clone() {
+            // Reencode bytes for image and do size checks.
+            MessageContent::Image { data } => {
+                if !enable_attachments {
+                    MessageContent::Error {
+                        message: "Receiving attachments is disabled in settings.".to_string(),
+                    }
+                } else {
+                    MessageContent::Image {
+                        data: attachment::reencode_bytes(&data)?,
                     }
-                    other => other,
-                };
-
-                db::MessageDb {
-                    id: 0,
-                    contact_onion_id: payload.onion_id.clone(),
-                    body: serde_json::to_string(&message)?,
-                    timestamp: payload.timestamp as i32,
-                    is_incoming: true,
-                    sent_status: false,
-                    verified_status: true,
                 }
-                .insert(db_conn.clone())
+            }
+            other => other,
+        };
+
+        let message = db::MessageDb {
+            id: 0,
+            message_id: payload.message_id.clone(),
+            contact_onion_id: encrypted.header.from.clone(),
+            body: serde_json::to_string(&message)?,
+            timestamp: payload.timestamp as i32,
+            is_incoming: true,
+            sent_status: false,
+            verified_status,
+            delivered_at: None,
+            read_at: None,
+            failed: false,
+        };
+        // Session is stored either way, a retried message is encrypted with a new key.
+        let insert_id = self
+            .keep_session(&encrypted.header.from, ratchet, Some(&message))
+            .await?;
+        drop(session);
+
+        // Peer retried a message we already received.
+        let is_duplicate = insert_id.is_none();
+        if is_duplicate {
+            tracing::debug!("Ignoring duplicate message.");
+        } else {
+            let _ = message_tx.send(serde_json::to_string(&payload)?);
+        }
+
+        // Peer discarded what we sent with our superseded session.
+        // Messages are stored just before the session is set up.
+        if let Some(since) = superseded_since {
+            self.resend_since(&encrypted.header.from, since - 1).await?;
+        }
+
+        // Also acknowledge duplicates, our previous receipt may have been lost.
+        self.queue_receipt(
+            &encrypted.header.from,
+            message::ReceiptKind::Delivered,
+            vec![payload.message_id],
+        )
+        .await;
+
+        // Show notifcation for new message if user
+        // is not actively using the app.
+        if enable_notifications && !is_duplicate && !ui_focus::is_focussed() {
+            let _ = Notification::new()
+                .summary("Arti chat")
+                .body("You received a new message.")
+                .show();
+        }
+
+        Ok(())
+    }
+
+    /// Handle control message of peer.
+    /// Undecryptable control messages are dropped without requesting a session reset.
+    async fn handle_control_message(
+        &self,
+        encrypted: ratchet::EncryptedMessage,
+        message_tx: tokio::sync::mpsc::UnboundedSender<String>,
+    ) -> Result<(), error::ClientError> {
+        // Control messages never set up a session.
+        if encrypted.prekey_handshake.is_some() {
+            return Err(error::ClientError::UnexpectedMessageKind);
+        }
+
+        let plaintext = {
+            let _session = self.session_locks.lock(&encrypted.header.from).await;
+            let mut ratchet = self
+                .session_of(&encrypted.header.from)
+                .await
+                .ok_or(error::RatchetError::NoSession)?;
+            let plaintext = ratchet
+                .decrypt(&encrypted)
+                .inspect_err(|e| replay::log_security_event(&encrypted.header.from, e))?;
+
+            self.keep_session(&encrypted.header.from, ratchet, None)
                 .await?;
+            plaintext
+        };
 
-                let _ = message_tx.send(serde_json::to_string(&payload)?);
+        let plaintext = padding::unpad(plaintext)?;
+        match ciborium::from_reader(plaintext.as_slice())? {
+            control::ControlMessage::Typing { typing } => {
+                if self.config.lock().await.enable_typing_indicators {
+                    let typing_changed = ipc::TypingChanged {
+                        onion_id: encrypted.header.from,
+                        typing,
+                    };
+                    let _ = message_tx.send(serde_json::to_string(&typing_changed)?);
+                }
+            }
+        }
 
-                // Show notifcation for new message if user
-                // is not actively using the app.
-                if client_config.enable_notifications && !ui_focus::is_focussed() {
-                    let _ = Notification::new()
-                        .summary("Arti chat")
-                        .body("You received a new message.")
-                        .show();
+        Ok(())
+    }
+
+    /// Apply receipt of peer and notify UI of changed messages.
+    async fn handle_receipt(
+        &self,
+        peer_onion_id: &str,
+        kind: message::ReceiptKind,
+        message_ids: &[String],
+        message_tx: &tokio::sync::mpsc::UnboundedSender<String>,
+    ) -> Result<(), error::ClientError> {
+        if !message_ids
+            .iter()
+            .all(|message_id| message::is_valid_message_id(message_id))
+        {
+            return Err(error::ClientError::InvalidMessageId);
+        }
+
+        let changed =
+            db::MessageDb::apply_receipt(peer_onion_id, kind, message_ids, self.db_conn.clone())
+                .await?;
+        if !changed.is_empty() {
+            let status_changed = ipc::MessageStatusChanged {
+                onion_id: peer_onion_id.into(),
+                message_ids: changed,
+                status: kind.into(),
+            };
+            let _ = message_tx.send(serde_json::to_string(&status_changed)?);
+        }
+
+        Ok(())
+    }
+
+    /// Queue receipt for messages of peer, if peer supports receipts.
+    /// Receipts are not retried, failures are only logged.
+    async fn queue_receipt(
+        &self,
+        peer_onion_id: &str,
+        kind: message::ReceiptKind,
+        message_ids: Vec<String>,
+    ) {
+        if !self
+            .peer_supports(peer_onion_id, envelope::Capability::Receipts)
+            .await
+        {
+            return;
+        }
+
+        self.receipts
+            .lock()
+            .await
+            .push(peer_onion_id, kind, message_ids);
+        self.receipt_wakeup.notify_one();
+    }
+
+    /// Send queued receipts until the daemon stops.
+    /// Waits shortly after the first receipt, so receipts queued meanwhile are batched.
+    pub async fn process_receipts(self: std::sync::Arc<Self>) {
+        loop {
+            self.receipt_wakeup.notified().await;
+            tokio::time::sleep(tokio::time::Duration::from_millis(
+                receipt::RECEIPT_BATCH_DELAY,
+            ))
+            .await;
+
+            let receipts = self.receipts.lock().await.take();
+            for (peer_onion_id, kind, message_ids) in receipts {
+                // Peers are served in parallel, so an offline peer does not hold up the others.
+                let client = self.clone();
+                tokio::spawn(async move {
+                    let receipt = MessageContent::Receipt { kind, message_ids };
+                    if let Err(e) = client
+                        .send_message_to_peer(&peer_onion_id, &message::new_message_id(), receipt)
+                        .await
+                    {
+                        tracing::debug!("Failed to send receipt: {}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Mark chat with peer as read when the user is looking at it,
+    /// and send a read receipt if enabled.
+    pub async fn mark_chat_read(&self, peer_onion_id: &str) -> Result<(), error::ClientError> {
+        if !ui_focus::is_focussed() {
+            return Ok(());
+        }
+
+        // Marked read even if receipts are disabled, so enabling them
+        // later does not reveal when older messages were read.
+        let unread = db::MessageDb::mark_read(peer_onion_id, self.db_conn.clone()).await?;
+        let enable_read_receipts = self.config.lock().await.enable_read_receipts;
+        if enable_read_receipts && !unread.is_empty() {
+            self.queue_receipt(peer_onion_id, message::ReceiptKind::Read, unread)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Handle request from client to open new stream to our onion service.
+    async fn handle_request(
+        &self,
+        request: tor_hsservice::StreamRequest,
+        message_tx: tokio::sync::mpsc::UnboundedSender<String>, // Used to send incoming messages
+                                                                // to IPC server.
+    ) -> Result<(), error::ClientError> {
+        let my_onion_id = self.get_identity_unredacted()?;
+
+        match request.request() {
+            IncomingStreamRequest::Begin(begin) if begin.port() == 80 => {
+                let mut stream = frame::framed(request.accept(Connected::new_empty()).await?);
+
+                // Peer keeps the stream open for more frames until it is idle.
+                let idle_timeout =
+                    std::time::Duration::from_secs(connection::INCOMING_IDLE_TIMEOUT);
+                while let Ok(Some(message)) =
+                    tokio::time::timeout(idle_timeout, stream.next()).await
+                {
+                    // Undecodable frames are skipped by the codec, so this is broken framing.
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(e) => {
+                            tracing::debug!("Failed to read frame of peer: {}", e);
+                            continue;
+                        }
+                    };
+                    let expects_reply = matches!(
+                        message,
+                        envelope::WireMessage::Handshake(_) | envelope::WireMessage::KeyProbe(_)
+                    );
+
+                    // Other frames of the stream are still handled after a failed one,
+                    // the peer already considers them delivered.
+                    if let Err(e) = self
+                        .handle_frame(message, &mut stream, &message_tx, &my_onion_id)
+                        .await
+                    {
+                        // A frame we don't answer must close the stream, so the peer stops waiting.
+                        if expects_reply {
+                            tracing::debug!("Closing stream after unanswered frame: {}", e);
+                            break;
+                        }
+                        tracing::debug!("Failed to handle frame of peer: {}", e);
+                    }
                 }
 
                 Ok(())
@@ -517,43 +2010,166 @@ impl Client {
         }
     }
 
+    /// Handle a single frame of peer.
+    async fn handle_frame(
+        &self,
+        message: envelope::WireMessage,
+        stream: &mut frame::WireStream<impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>,
+        message_tx: &tokio::sync::mpsc::UnboundedSender<String>,
+        my_onion_id: &str,
+    ) -> Result<(), error::ClientError> {
+        match message {
+            // Handshake initiated by sender.
+            envelope::WireMessage::Handshake(handshake) => {
+                self.handle_handshake(*handshake, stream, my_onion_id).await
+            }
+            // Peer could not decrypt our message and requests a new session.
+            envelope::WireMessage::SessionReset(session_reset) => {
+                self.handle_session_reset(session_reset, my_onion_id).await
+            }
+            // Peer replaced its identity key.
+            envelope::WireMessage::KeyRotation(key_rotation) => {
+                self.handle_key_rotation(key_rotation).await
+            }
+            // Encrypted message received by sender.
+            envelope::WireMessage::Message(encrypted) => {
+                self.handle_encrypted_message(*encrypted, message_tx.clone(), my_onion_id)
+                    .await
+            }
+            // Ephemeral control message, e.g. typing indicator.
+            envelope::WireMessage::Control(encrypted) => {
+                self.handle_control_message(*encrypted, message_tx.clone())
+                    .await
+            }
+            // Peer which is not a contact introduces itself.
+            envelope::WireMessage::ContactRequest(request) => {
+                self.handle_contact_request(*request, message_tx.clone(), my_onion_id)
+                    .await
+            }
+            // Peer we sent a contact request asks us to prove our key.
+            envelope::WireMessage::KeyProbe(probe) => {
+                self.handle_key_probe(*probe, stream, my_onion_id).await
+            }
+            // Only read as reply to our own probe.
+            envelope::WireMessage::KeyProof(_) => Err(error::ClientError::UnexpectedMessageKind),
+        }
+    }
+
+    /// Send our handshake to peer and read its reply.
+    async fn exchange_handshake(
+        &self,
+        peer_onion_id: &str,
+        handshake: ratchet::Handshake,
+    ) -> Result<Box<ratchet::Handshake>, error::ClientError> {
+        let tor_client = self.tor_client.load_full();
+        let idle_timeout = self.idle_timeout().await;
+
+        let reply = self
+            .connections
+            .request(
+                &tor_client,
+                peer_onion_id,
+                idle_timeout,
+                envelope::WireMessage::Handshake(Box::new(handshake)),
+            )
+            .await?;
+        match reply {
+            envelope::WireMessage::Handshake(handshake_response) => Ok(handshake_response),
+            _ => Err(error::ClientError::UnexpectedMessageKind),
+        }
+    }
+
     /// Ensure a ratchet for message encryption exists for specific contact.
     async fn ensure_ratchet_exists(&self, peer_onion_id: &str) -> Result<(), error::ClientError> {
         let self_onion_id = self.get_identity_unredacted()?;
 
+        // Concurrent sends wait for the session the first one sets up, instead of
+        // replacing each other's session with handshakes of the same second.
+        let _setup = self.session_setups.lock(peer_onion_id).await;
+
+        // Sessions from an older key derivation scheme are renegotiated.
+        if self
+            .session_of(peer_onion_id)
+            .await
+            .is_some_and(|ratchet| ratchet.is_current())
         {
-            let ratchets = self.ratchets.lock().await;
-            if ratchets.contains_key(peer_onion_id) {
-                return Ok(());
-            }
+            return Ok(());
         }
 
         let peer = db::ContactDb::retrieve(peer_onion_id, self.db_conn.clone()).await?;
         let peer_public_key = ratchet::verifying_key_from_hex(&peer.public_key)?;
 
-        let (initiating_handshake, self_ephemeral_secret) =
-            ratchet::Handshake::initiate(&self_onion_id, peer_onion_id, &self.private_key);
-
-        let target = format!("{}:80", peer_onion_id);
-        let tor_client = self.tor_client.lock().await;
-        let mut stream = tor_client.connect(&target).await?;
-
-        let mut init_handshake_payload = serde_json::to_string(&initiating_handshake)?;
-        init_handshake_payload.push('\0');
-        stream.write_all(init_handshake_payload.as_bytes()).await?;
-        stream.flush().await?;
+        // Without round trip if contact shared a signed prekey, so it may be offline.
+        // Prekeys have no ML-KEM part, so a post-quantum session needs the round trip.
+        let deniable = self.is_deniable_with(&peer).await;
+        let options = self.handshake_options(deniable).await;
+        if let Some(bundle) = peer
+            .prekey_bundle
+            .as_deref()
+            .and_then(|b| prekey::PrekeyBundle::decode(b).ok())
+            .filter(|_| !options.post_quantum && !options.require_post_quantum)
+        {
+            let ratchet = ratchet::Handshake::initiate_with_prekey(
+                &self_onion_id,
+                peer_onion_id,
+                &bundle,
+                &peer_public_key,
+                &self.private_key().await,
+                deniable,
+            )?;
+
+            let _session = self.session_locks.lock(peer_onion_id).await;
+            self.keep_session(peer_onion_id, ratchet, None).await?;
+
+            return Ok(());
+        }
 
-        let handshake_response_raw = ratchet::read_null_terminated(&mut stream).await?;
-        let handshake_response: ratchet::Handshake = serde_json::from_str(&handshake_response_raw)?;
-        let ratchet = handshake_response.complete(
+        let (mut initiating_handshake, handshake_secret) = ratchet::Handshake::initiate(
             &self_onion_id,
+            peer_onion_id,
             &peer_public_key,
-            &self_ephemeral_secret,
-            true,
-        )?;
+            &self.private_key().await,
+            options,
+        );
+        initiating_handshake.prekey_bundle = self.own_prekey_bundle().await;
+
+        let nonce = initiating_handshake.nonce;
+        self.pending_handshakes
+            .lock()
+            .await
+            .start(peer_onion_id, nonce);
+        let handshake_response = self
+            .exchange_handshake(peer_onion_id, initiating_handshake)
+            .await;
+        if !self
+            .pending_handshakes
+            .lock()
+            .await
+            .finish(peer_onion_id, nonce)
+        {
+            // Peer initiated at the same time and won, we answered its handshake instead.
+            tracing::info!("Discarding own handshake, simultaneous handshake of peer wins.");
+            return Ok(());
+        }
 
-        let mut ratchets = self.ratchets.lock().await;
-        ratchets.insert(peer_onion_id.into(), ratchet);
+        let handshake_response = handshake_response?;
+        let ratchet = handshake_response
+            .complete(&self_onion_id, &peer_public_key, &handshake_secret, true)
+            .inspect_err(|e| replay::log_security_event(peer_onion_id, e))?;
+        if options.post_quantum && !ratchet.post_quantum {
+            tracing::warn!("Peer declined post-quantum handshake, session is X25519 only.");
+        }
+
+        let session = self.session_locks.lock(peer_onion_id).await;
+        self.keep_session(peer_onion_id, ratchet, None).await?;
+        drop(session);
+
+        if let Err(e) = self
+            .store_peer_prekey_bundle(&handshake_response, &peer_public_key)
+            .await
+        {
+            tracing::warn!("Ignoring prekey bundle of peer: {}", e);
+        }
 
         Ok(())
     }
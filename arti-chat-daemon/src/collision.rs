@@ -0,0 +1,75 @@
+//! Resolution of simultaneous handshakes.
+//! If both peers initiate a session at the same time, the initiation of the peer
+//! with the lowest onion id wins. The other peer answers it as responder and
+//! discards its own initiation, so both converge on one session.
+
+/// Check if our initiation wins a collision with the initiation of peer.
+pub fn initiator_wins(self_onion_id: &str, peer_onion_id: &str) -> bool {
+    self_onion_id < peer_onion_id
+}
+
+/// What to do with a handshake of peer.
+#[non_exhaustive]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// Answer handshake as responder.
+    Accept,
+    /// Ignore handshake, our own initiation wins.
+    Reject,
+}
+
+/// Initiation of ours waiting for the reply of peer.
+struct PendingInitiation {
+    /// Nonce of our handshake.
+    nonce: [u8; 16],
+    /// Peer won a collision, so our initiation is discarded.
+    superseded: bool,
+}
+
+/// Our initiations waiting for a reply.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct PendingHandshakes {
+    /// Onion id of peer -> pending initiation.
+    pending: std::collections::HashMap<String, PendingInitiation>,
+}
+
+impl PendingHandshakes {
+    /// Remember our initiation to peer before sending it.
+    pub fn start(&mut self, peer_onion_id: &str, nonce: [u8; 16]) {
+        self.pending.insert(
+            peer_onion_id.into(),
+            PendingInitiation {
+                nonce,
+                superseded: false,
+            },
+        );
+    }
+
+    /// Decide on a handshake initiated by peer.
+    /// If we lose a collision, our pending initiation is marked superseded.
+    pub fn resolve(&mut self, self_onion_id: &str, peer_onion_id: &str) -> Resolution {
+        match self.pending.get_mut(peer_onion_id) {
+            Some(_) if initiator_wins(self_onion_id, peer_onion_id) => Resolution::Reject,
+            Some(pending) => {
+                pending.superseded = true;
+                Resolution::Accept
+            }
+            None => Resolution::Accept,
+        }
+    }
+
+    /// Forget our initiation once it completed or failed.
+    /// Returns if the session of the initiation should be kept.
+    pub fn finish(&mut self, peer_onion_id: &str, nonce: [u8; 16]) -> bool {
+        match self.pending.get(peer_onion_id) {
+            Some(pending) if pending.nonce == nonce => {
+                let keep = !pending.superseded;
+                self.pending.remove(peer_onion_id);
+                keep
+            }
+            // Replaced by a newer initiation of ours.
+            _ => false,
+        }
+    }
+}
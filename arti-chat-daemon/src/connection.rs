@@ -0,0 +1,160 @@
+//! Persistent streams to peers.
+//! Frames to a peer share one stream instead of opening a stream per message.
+//! A stream is closed after it was idle for a while, and replaced transparently
+//! when the peer closed it in the meantime.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{FutureExt, SinkExt, StreamExt};
+use tokio::sync::Mutex as TokioMutex;
+use tokio_util::bytes::Bytes;
+
+use crate::{envelope::WireMessage, error::ClientError, frame};
+
+/// Default seconds an unused stream to a peer is kept open.
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 120;
+
+/// Max seconds an unused stream to a peer is kept open.
+pub const MAX_IDLE_TIMEOUT: u64 = 10 * 60;
+
+/// Seconds a stream of a peer may stay silent before we close it.
+/// Longer than any peer keeps its stream open, so the peer closes it first.
+pub const INCOMING_IDLE_TIMEOUT: u64 = MAX_IDLE_TIMEOUT + 60;
+
+/// Seconds to wait for the reply of peer to a request.
+pub const REPLY_TIMEOUT: u64 = 60;
+
+/// Type for TorClient with runtime.
+type ArtiTorClient = arti_client::TorClient<tor_rtcompat::PreferredRuntime>;
+
+/// Open stream to a peer.
+struct OpenStream {
+    /// Frames exchanged with peer.
+    stream: frame::WireStream<arti_client::DataStream>,
+    /// Last time a frame was written.
+    last_used: Instant,
+}
+
+/// Stream to a peer, locked while in use so frames keep their order.
+type Slot = Arc<TokioMutex<Option<OpenStream>>>;
+
+/// Open streams to peers.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct ConnectionPool {
+    /// Onion id of peer -> its stream.
+    slots: TokioMutex<HashMap<String, Slot>>,
+}
+
+impl ConnectionPool {
+    /// Write frame to peer, over its open stream if any.
+    pub async fn send(
+        &self,
+        tor_client: &ArtiTorClient,
+        peer_onion_id: &str,
+        idle_timeout: Duration,
+        message: WireMessage,
+    ) -> Result<(), ClientError> {
+        let slot = self.slot(peer_onion_id).await;
+        let mut slot = slot.lock().await;
+
+        Self::write(&mut slot, tor_client, peer_onion_id, idle_timeout, message).await?;
+        Ok(())
+    }
+
+    /// Write frame to peer and read its reply.
+    /// The stream is closed if the peer does not reply, so no late reply is read later.
+    pub async fn request(
+        &self,
+        tor_client: &ArtiTorClient,
+        peer_onion_id: &str,
+        idle_timeout: Duration,
+        message: WireMessage,
+    ) -> Result<WireMessage, ClientError> {
+        let slot = self.slot(peer_onion_id).await;
+        let mut slot = slot.lock().await;
+
+        let open = Self::write(&mut slot, tor_client, peer_onion_id, idle_timeout, message).await?;
+        let reply =
+            tokio::time::timeout(Duration::from_secs(REPLY_TIMEOUT), open.stream.next()).await;
+
+        match reply {
+            Ok(Some(Ok(reply))) => Ok(reply),
+            Ok(Some(Err(e))) => {
+                *slot = None;
+                Err(e)
+            }
+            Ok(None) => {
+                *slot = None;
+                Err(ClientError::UnexpectedMessageKind)
+            }
+            Err(_) => {
+                *slot = None;
+                Err(ClientError::PeerTimeout)
+            }
+        }
+    }
+
+    /// Close streams unused for longer than `idle_timeout`.
+    pub async fn close_idle(&self, idle_timeout: Duration) {
+        // Slots in use elsewhere are kept, so a peer never has two streams at once.
+        self.slots.lock().await.retain(|_, slot| {
+            Arc::strong_count(slot) > 1
+                || slot.try_lock().is_ok_and(|open| {
+                    open.as_ref()
+                        .is_some_and(|open| open.last_used.elapsed() < idle_timeout)
+                })
+        });
+    }
+
+    /// Close all streams, e.g. when switching to new circuits.
+    /// Streams in use are closed once their frame is written.
+    pub async fn clear(&self) {
+        self.slots.lock().await.clear();
+    }
+
+    /// Get slot of peer, add an empty one if none.
+    async fn slot(&self, peer_onion_id: &str) -> Slot {
+        self.slots
+            .lock()
+            .await
+            .entry(peer_onion_id.into())
+            .or_default()
+            .clone()
+    }
+
+    /// Write frame to the stream in slot, reconnect if it is idle, closed or broken.
+    async fn write<'a>(
+        slot: &'a mut Option<OpenStream>,
+        tor_client: &ArtiTorClient,
+        peer_onion_id: &str,
+        idle_timeout: Duration,
+        message: WireMessage,
+    ) -> Result<&'a mut OpenStream, ClientError> {
+        let envelope = Bytes::from(message.encode()?);
+
+        // Peers never write unasked, so anything readable means the stream was closed.
+        let reusable = slot
+            .take()
+            .filter(|open| open.last_used.elapsed() < idle_timeout)
+            .and_then(|mut open| open.stream.next().now_or_never().is_none().then_some(open));
+
+        if let Some(mut open) = reusable
+            && open.stream.send(envelope.clone()).await.is_ok()
+        {
+            open.last_used = Instant::now();
+            return Ok(slot.insert(open));
+        }
+
+        let target = format!("{peer_onion_id}:80");
+        let mut stream = frame::framed(tor_client.connect(&target).await?);
+        stream.send(envelope).await?;
+
+        Ok(slot.insert(OpenStream {
+            stream,
+            last_used: Instant::now(),
+        }))
+    }
+}
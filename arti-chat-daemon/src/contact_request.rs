@@ -0,0 +1,498 @@
+//! Contact requests of peers we don't know yet.
+//! A request introduces the sender with its public key, a nickname and a note,
+//! signed by that key. It is kept apart from contacts until the user accepts it.
+//! A request to a deniable contact, whose key the sender already knows, carries
+//! a MAC for that contact instead of a signature.
+//! A proof-of-work stamp makes sending many requests expensive.
+//!
+//! Anyone can put any onion id in a request, so the receiver probes that onion
+//! service, and its owner proves it holds the key of the request with a MAC.
+//! Senders whose onion id is derived from their key need no probe.
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+use x25519_dalek::StaticSecret;
+
+use crate::{deniable, error::RatchetError, ratchet};
+
+/// Domain separator of request signature.
+const SIGNATURE_LABEL: &[u8] = b"arti-chat/contact-request";
+
+/// Domain separator of key proof.
+const PROOF_LABEL: &[u8] = b"arti-chat/contact-request-proof";
+
+/// Domain separator of proof-of-work stamp.
+const STAMP_LABEL: &[u8] = b"arti-chat/contact-request-stamp";
+
+/// Leading zero bits of the stamp hash.
+pub const STAMP_DIFFICULTY: u32 = 20;
+
+/// Max length in characters of nickname.
+pub const MAX_NICKNAME_LENGTH: usize = 64;
+
+/// Max length in characters of note.
+pub const MAX_NOTE_LENGTH: usize = 280;
+
+/// Max amount of requests waiting for the user, the oldest ones are evicted.
+pub const MAX_PENDING_REQUESTS: i64 = 50;
+
+/// Min interval in seconds between requests accepted from the same peer.
+pub const REQUEST_INTERVAL: i64 = 60;
+
+/// Seconds after sending a request during which we answer key probes of the peer.
+pub const PROBE_WINDOW: i64 = 10 * 60;
+
+/// Introduction of peer asking to become a contact.
+#[non_exhaustive]
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct ContactRequest {
+    /// Onion id of sender.
+    pub from: String,
+    /// Onion id of receiver.
+    pub to: String,
+    /// Hex encoded public key of sender.
+    pub public_key: String,
+    /// Nickname sender introduces itself with.
+    pub nickname: String,
+    /// Short note to receiver.
+    pub note: String,
+    /// Timestamp of creation.
+    pub timestamp: i64,
+    /// Proof-of-work nonce, see [`STAMP_DIFFICULTY`].
+    pub stamp: Option<u64>,
+    /// Request is authenticated with a MAC instead of a signature.
+    #[serde(default)]
+    pub deniable: bool,
+    /// Signature with key of sender, or MAC if deniable.
+    pub signature: String,
+}
+
+impl ContactRequest {
+    /// Create signed request for peer, with a stamp if asked.
+    /// With the public key of a deniable peer, the request carries a MAC for it instead.
+    /// Computing the stamp takes a while, so callers run it on a blocking thread.
+    pub fn new(
+        from: &str,
+        to: &str,
+        nickname: &str,
+        note: &str,
+        self_private_key: &SigningKey,
+        deniable_peer: Option<&VerifyingKey>,
+        with_stamp: bool,
+    ) -> Self {
+        let mut request = Self {
+            from: from.into(),
+            to: to.into(),
+            public_key: hex::encode(self_private_key.verifying_key().as_bytes()),
+            nickname: nickname.chars().take(MAX_NICKNAME_LENGTH).collect(),
+            note: note.chars().take(MAX_NOTE_LENGTH).collect(),
+            timestamp: chrono::Utc::now().timestamp(),
+            stamp: None,
+            deniable: deniable_peer.is_some(),
+            signature: String::new(),
+        };
+
+        if with_stamp {
+            let hasher = stamp_hasher(&request.stamped_data());
+            let mut stamp = rand::random::<u64>();
+            while stamp_bits(&hasher, stamp) < STAMP_DIFFICULTY {
+                stamp = stamp.wrapping_add(1);
+            }
+            request.stamp = Some(stamp);
+        }
+        request.signature = match deniable_peer {
+            Some(peer_public_key) => {
+                let self_identity = deniable::identity_secret(self_private_key);
+                deniable::mac(&request.transcript(), &self_identity, peer_public_key)
+            }
+            None => self_private_key.sign(&request.transcript()).to_string(),
+        };
+        request
+    }
+
+    /// Data covered by stamp.
+    fn stamped_data(&self) -> Vec<u8> {
+        let mut t = Vec::new();
+        t.extend_from_slice(SIGNATURE_LABEL);
+        t.extend_from_slice(self.from.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.to.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.public_key.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.nickname.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.note.as_bytes());
+        t.push(0);
+        t.extend_from_slice(&self.timestamp.to_be_bytes());
+        t
+    }
+
+    /// Data covered by signature or MAC, including the stamp.
+    fn transcript(&self) -> Vec<u8> {
+        let mut t = self.stamped_data();
+        if let Some(stamp) = self.stamp {
+            t.extend_from_slice(&stamp.to_be_bytes());
+        }
+        t
+    }
+
+    /// Check target, size, freshness, stamp and signature or MAC of received request.
+    /// Returns the public key of sender.
+    pub fn verify(
+        &self,
+        self_onion_id: &str,
+        self_identity: &StaticSecret,
+        require_stamp: bool,
+    ) -> Result<VerifyingKey, RatchetError> {
+        if self.to != self_onion_id {
+            return Err(RatchetError::InvalidHandshakeTarget);
+        }
+
+        if self.nickname.trim().is_empty()
+            || self.nickname.chars().count() > MAX_NICKNAME_LENGTH
+            || self.note.chars().count() > MAX_NOTE_LENGTH
+        {
+            return Err(RatchetError::InvalidContactRequest);
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        if now.abs_diff(self.timestamp) > ratchet::MAX_HANDSHAKE_AGE.unsigned_abs() {
+            return Err(RatchetError::StaleHandshake);
+        }
+
+        // Checked before the signature, since it is cheaper.
+        if require_stamp && !self.has_valid_stamp() {
+            return Err(RatchetError::InvalidContactRequest);
+        }
+
+        let public_key = ratchet::verifying_key_from_hex(&self.public_key)?;
+        if self.deniable {
+            deniable::verify_mac(
+                &self.transcript(),
+                &self.signature,
+                self_identity,
+                &public_key,
+            )?;
+        } else {
+            let signature: ed25519_dalek::Signature = self.signature.parse()?;
+            public_key.verify_strict(&self.transcript(), &signature)?;
+        }
+
+        Ok(public_key)
+    }
+
+    /// Check if request carries a stamp of enough work.
+    pub fn has_valid_stamp(&self) -> bool {
+        self.stamp.is_some_and(|stamp| {
+            stamp_bits(&stamp_hasher(&self.stamped_data()), stamp) >= STAMP_DIFFICULTY
+        })
+    }
+}
+
+/// Question of receiver of a request to the onion id the request claims to be from.
+#[non_exhaustive]
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct KeyProbe {
+    /// Onion id of receiver of the request, who probes.
+    pub from: String,
+    /// Onion id the request claims to be from.
+    pub to: String,
+    /// Hex encoded public key of the request.
+    pub public_key: String,
+    /// Hex encoded public key of prober, the proof is only verifiable with it.
+    pub prober_public_key: String,
+    /// Random nonce, so a proof can't be replayed.
+    pub nonce: [u8; 16],
+}
+
+impl KeyProbe {
+    /// Create probe of request, answered by the owner of its onion id only.
+    pub fn new(request: &ContactRequest, self_public_key: &VerifyingKey) -> Self {
+        Self {
+            from: request.to.clone(),
+            to: request.from.clone(),
+            public_key: request.public_key.clone(),
+            prober_public_key: hex::encode(self_public_key.as_bytes()),
+            nonce: rand::random::<[u8; 16]>(),
+        }
+    }
+
+    /// Data covered by proof.
+    fn transcript(&self) -> Vec<u8> {
+        let mut t = Vec::new();
+        t.extend_from_slice(PROOF_LABEL);
+        t.extend_from_slice(self.from.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.to.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.public_key.as_bytes());
+        t.push(0);
+        t.extend_from_slice(&self.nonce);
+        t
+    }
+
+    /// Answer probe with a MAC for prober, proving we hold the key it asks about.
+    pub fn prove(
+        &self,
+        self_private_key: &SigningKey,
+        prober_public_key: &VerifyingKey,
+    ) -> Result<KeyProof, RatchetError> {
+        if self.public_key != hex::encode(self_private_key.verifying_key().as_bytes()) {
+            return Err(RatchetError::InvalidContactRequest);
+        }
+
+        let self_identity = deniable::identity_secret(self_private_key);
+        Ok(KeyProof {
+            nonce: self.nonce,
+            mac: deniable::mac(&self.transcript(), &self_identity, prober_public_key),
+        })
+    }
+
+    /// Check if proof answers this probe with the key of the request.
+    /// Returns the proven public key.
+    pub fn verify(
+        &self,
+        proof: &KeyProof,
+        self_identity: &StaticSecret,
+    ) -> Result<VerifyingKey, RatchetError> {
+        if proof.nonce != self.nonce {
+            return Err(RatchetError::InvalidContactRequest);
+        }
+
+        let public_key = ratchet::verifying_key_from_hex(&self.public_key)?;
+        deniable::verify_mac(&self.transcript(), &proof.mac, self_identity, &public_key)?;
+        Ok(public_key)
+    }
+}
+
+/// Answer of owner of an onion id to a key probe.
+#[non_exhaustive]
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct KeyProof {
+    /// Nonce of the probe.
+    pub nonce: [u8; 16],
+    /// MAC over the probe, see [`KeyProbe::prove`].
+    pub mac: String,
+}
+
+/// Peers we recently sent a request to, only their key probes are answered.
+/// Otherwise anyone could ask which key belongs to our onion id.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct SentRequests {
+    /// Onion id of peer -> timestamp of last request.
+    sent_at: std::collections::HashMap<String, i64>,
+}
+
+impl SentRequests {
+    /// Remember request sent to peer.
+    pub fn insert(&mut self, peer_onion_id: &str) {
+        let now = chrono::Utc::now().timestamp();
+        self.sent_at.retain(|_, ts| now - *ts < PROBE_WINDOW);
+        self.sent_at.insert(peer_onion_id.into(), now);
+    }
+
+    /// Check if we sent a request to peer within the probe window.
+    pub fn contains(&self, peer_onion_id: &str) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.sent_at
+            .get(peer_onion_id)
+            .is_some_and(|ts| now - *ts < PROBE_WINDOW)
+    }
+}
+
+/// Limits requests per peer, so one peer can't evict the requests of others quickly.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct RequestThrottle {
+    /// Onion id of peer -> timestamp of last request.
+    last_request: std::collections::HashMap<String, i64>,
+}
+
+impl RequestThrottle {
+    /// Check if a request of peer is allowed now and remember it if so.
+    pub fn allow(&mut self, peer_onion_id: &str) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.last_request
+            .retain(|_, ts| now - *ts < REQUEST_INTERVAL);
+
+        if self.last_request.contains_key(peer_onion_id) {
+            return false;
+        }
+        self.last_request.insert(peer_onion_id.into(), now);
+        true
+    }
+}
+
+/// Hash state of the stamped data, so each attempt only hashes the nonce.
+fn stamp_hasher(data: &[u8]) -> Sha256 {
+    Sha256::new().chain_update(STAMP_LABEL).chain_update(data)
+}
+
+/// Leading zero bits of the stamp hash of data and nonce.
+fn stamp_bits(hasher: &Sha256, stamp: u64) -> u32 {
+    let hash = hasher.clone().chain_update(stamp.to_be_bytes()).finalize();
+
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Request of alice to bob, without stamp.
+    fn request(alice: &SigningKey) -> ContactRequest {
+        ContactRequest::new("alice.onion", "bob.onion", "Alice", "", alice, None, false)
+    }
+
+    #[test]
+    fn stamp_is_required_only_if_asked() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_identity = deniable::identity_secret(&bob);
+
+        let stamped =
+            ContactRequest::new("alice.onion", "bob.onion", "Alice", "", &alice, None, true);
+        assert!(stamped.has_valid_stamp());
+        let public_key = stamped
+            .verify("bob.onion", &bob_identity, true)
+            .expect("verify stamped request");
+        assert_eq!(public_key, alice.verifying_key());
+
+        let unstamped = request(&alice);
+        assert!(!unstamped.has_valid_stamp());
+        assert!(matches!(
+            unstamped.verify("bob.onion", &bob_identity, true),
+            Err(RatchetError::InvalidContactRequest)
+        ));
+        assert!(unstamped.verify("bob.onion", &bob_identity, false).is_ok());
+    }
+
+    #[test]
+    fn stamp_covers_request() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let mut stamped =
+            ContactRequest::new("alice.onion", "bob.onion", "Alice", "", &alice, None, true);
+
+        // Moving the stamp to another request would need a new one.
+        stamped.note = "hi".into();
+        assert!(!stamped.has_valid_stamp());
+    }
+
+    #[test]
+    fn rejects_request_for_other_peer_or_oversized() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_identity = deniable::identity_secret(&SigningKey::generate(&mut rand_core::OsRng));
+
+        assert!(matches!(
+            request(&alice).verify("carol.onion", &bob_identity, false),
+            Err(RatchetError::InvalidHandshakeTarget)
+        ));
+
+        let mut oversized = request(&alice);
+        oversized.note = "x".repeat(MAX_NOTE_LENGTH + 1);
+        assert!(matches!(
+            oversized.verify("bob.onion", &bob_identity, false),
+            Err(RatchetError::InvalidContactRequest)
+        ));
+    }
+
+    #[test]
+    fn deniable_request_verifies_for_its_peer_only() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let carol = SigningKey::generate(&mut rand_core::OsRng);
+        let deniable_request = ContactRequest::new(
+            "alice.onion",
+            "bob.onion",
+            "Alice",
+            "",
+            &alice,
+            Some(&bob.verifying_key()),
+            false,
+        );
+
+        let bob_identity = deniable::identity_secret(&bob);
+        assert!(
+            deniable_request
+                .verify("bob.onion", &bob_identity, false)
+                .is_ok()
+        );
+        let carol_identity = deniable::identity_secret(&carol);
+        assert!(
+            deniable_request
+                .verify("bob.onion", &carol_identity, false)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn owner_of_key_answers_probe() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let probe = KeyProbe::new(&request(&alice), &bob.verifying_key());
+
+        let proof = probe
+            .prove(&alice, &bob.verifying_key())
+            .expect("prove key");
+        let public_key = probe
+            .verify(&proof, &deniable::identity_secret(&bob))
+            .expect("verify proof");
+        assert_eq!(public_key, alice.verifying_key());
+    }
+
+    #[test]
+    fn rejects_proof_of_other_key_or_probe() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let mallory = SigningKey::generate(&mut rand_core::OsRng);
+        let probe = KeyProbe::new(&request(&alice), &bob.verifying_key());
+
+        // Owner of the onion id holds another key than the request claims.
+        assert!(probe.prove(&mallory, &bob.verifying_key()).is_err());
+
+        // A proof of another probe can't be replayed.
+        let other_probe = KeyProbe::new(&request(&alice), &bob.verifying_key());
+        let proof = other_probe
+            .prove(&alice, &bob.verifying_key())
+            .expect("prove key");
+        assert!(
+            probe
+                .verify(&proof, &deniable::identity_secret(&bob))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_stale_or_changed_request() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_identity = deniable::identity_secret(&SigningKey::generate(&mut rand_core::OsRng));
+
+        let mut stale = request(&alice);
+        stale.timestamp -= ratchet::MAX_HANDSHAKE_AGE + 1;
+        assert!(matches!(
+            stale.verify("bob.onion", &bob_identity, false),
+            Err(RatchetError::StaleHandshake)
+        ));
+
+        let mut changed = request(&alice);
+        changed.nickname = "Mallory".into();
+        assert!(changed.verify("bob.onion", &bob_identity, false).is_err());
+    }
+
+    #[test]
+    fn throttles_requests_per_peer() {
+        let mut throttle = RequestThrottle::default();
+        assert!(throttle.allow("alice.onion"));
+        assert!(!throttle.allow("alice.onion"));
+        assert!(throttle.allow("bob.onion"));
+    }
+}
@@ -0,0 +1,85 @@
+//! Ephemeral control messages, such as typing indicators.
+//! They are encrypted with the session like chat messages, but are never
+//! stored and only sent when a session with the peer already exists.
+
+/// Min seconds between two typing notifications to the same peer.
+/// The UI of the peer hides the indicator if it is not renewed in time.
+pub const TYPING_INTERVAL: i64 = 5;
+
+/// Control message, plaintext of a control frame.
+#[non_exhaustive]
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", content = "content")]
+pub enum ControlMessage {
+    /// User started or stopped typing.
+    Typing {
+        /// User is typing.
+        typing: bool,
+    },
+}
+
+/// Limits typing notifications per peer.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct TypingThrottle {
+    /// Onion id of peer -> last sent typing state and its timestamp.
+    last_sent: std::collections::HashMap<String, (bool, i64)>,
+}
+
+impl TypingThrottle {
+    /// Check if typing state may be sent to peer now and remember it if so.
+    /// Starts are repeated at most once per interval, a stop only follows a start.
+    pub fn allow(&mut self, peer_onion_id: &str, typing: bool) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let allowed = match self.last_sent.get(peer_onion_id) {
+            Some((true, ts)) if typing => now - ts >= TYPING_INTERVAL,
+            Some((true, _)) => true,
+            _ => typing,
+        };
+
+        if allowed {
+            self.last_sent.insert(peer_onion_id.into(), (typing, now));
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing_start_is_repeated_once_per_interval() {
+        let mut throttle = TypingThrottle::default();
+        assert!(throttle.allow("alice.onion", true));
+        assert!(!throttle.allow("alice.onion", true));
+        // Other peers are throttled on their own.
+        assert!(throttle.allow("bob.onion", true));
+
+        // Renewed once the interval passed.
+        throttle.last_sent.insert(
+            "alice.onion".into(),
+            (true, chrono::Utc::now().timestamp() - TYPING_INTERVAL),
+        );
+        assert!(throttle.allow("alice.onion", true));
+    }
+
+    #[test]
+    fn typing_stop_only_follows_start() {
+        let mut throttle = TypingThrottle::default();
+        assert!(!throttle.allow("alice.onion", false));
+        assert!(throttle.allow("alice.onion", true));
+        assert!(throttle.allow("alice.onion", false));
+        assert!(!throttle.allow("alice.onion", false));
+    }
+
+    #[test]
+    fn control_message_round_trips() {
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&ControlMessage::Typing { typing: true }, &mut encoded)
+            .expect("encode control message");
+        let decoded: ControlMessage =
+            ciborium::from_reader(encoded.as_slice()).expect("decode control message");
+        assert!(matches!(decoded, ControlMessage::Typing { typing: true }));
+    }
+}
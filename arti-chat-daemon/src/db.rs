@@ -1,9 +1,11 @@
 //! Logic to connect with database.
 
 use crate::error;
+use crate::message::ReceiptKind;
+use crate::outbox;
 use async_trait::async_trait;
 use rand::RngCore;
-use rusqlite::{Connection, Row, ToSql, params, params_from_iter};
+use rusqlite::{Connection, OptionalExtension, Row, ToSql, params, params_from_iter};
 use tokio::sync::Mutex as TokioMutex;
 
 /// Type for rusqlite database connection.
@@ -47,6 +49,15 @@ pub async fn init_database(
 
     let db_key = retrieve_db_encryption_key()?;
     conn.pragma_update(None, "key", &db_key)?;
+    create_tables(&conn)?;
+
+    tracing::debug!("Database connection established");
+
+    Ok(conn)
+}
+
+/// Create tables missing in the database.
+fn create_tables(conn: &Connection) -> Result<(), error::DatabaseError> {
     conn.execute_batch(
         r#"
         PRAGMA cipher_memory_security = ON;
@@ -68,7 +79,16 @@ pub async fn init_database(
             config (key, value)
         VALUES
             ('enable_notifications', 'true'),
-            ('enable_attachments', 'true')
+            ('enable_attachments', 'true'),
+            ('enable_onion_identity', 'false'),
+            ('padding_policy', 'buckets'),
+            ('enable_post_quantum', 'false'),
+            ('require_post_quantum', 'false'),
+            ('enable_read_receipts', 'false'),
+            ('enable_typing_indicators', 'true'),
+            ('message_expiry', '604800'),
+            ('connection_idle_timeout', '120'),
+            ('require_contact_request_stamp', 'true')
         ON CONFLICT(key) DO NOTHING;
 
         CREATE TABLE IF NOT EXISTS contact (
@@ -92,12 +112,111 @@ pub async fn init_database(
                 contact(onion_id)
             ON DELETE CASCADE
         );
+
+        CREATE TABLE IF NOT EXISTS session (
+            contact_onion_id TEXT PRIMARY KEY,
+            state TEXT NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY
+                (contact_onion_id)
+            REFERENCES
+                contact(onion_id)
+            ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS prekey (
+            id INTEGER PRIMARY KEY,
+            private_key TEXT NOT NULL,
+            public_key TEXT NOT NULL,
+            signature TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS prekey_handshake (
+            nonce TEXT PRIMARY KEY,
+            expires_at INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS key_rotation (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            contact_onion_id TEXT NOT NULL,
+            notice TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY
+                (contact_onion_id)
+            REFERENCES
+                contact(onion_id)
+            ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS contact_request (
+            onion_id TEXT PRIMARY KEY,
+            public_key TEXT NOT NULL,
+            nickname TEXT NOT NULL,
+            note TEXT NOT NULL,
+            received_at INTEGER NOT NULL
+        );
         "#,
     )?;
 
-    tracing::debug!("Database connection established");
+    // Columns added after the initial release of a table.
+    add_column_if_missing(conn, "contact", "prekey_bundle", "TEXT")?;
+    add_column_if_missing(conn, "contact", "verified_key", "TEXT")?;
+    add_column_if_missing(conn, "contact", "deniable", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "message", "message_id", "TEXT")?;
+    add_column_if_missing(conn, "message", "delivered_at", "INTEGER")?;
+    add_column_if_missing(conn, "message", "read_at", "INTEGER")?;
+    add_column_if_missing(
+        conn,
+        "contact_request",
+        "verified",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
 
-    Ok(conn)
+    // Messages stored before message ids existed get a random one.
+    // Ids are unique per contact, so a peer can't make us drop messages of
+    // other contacts by reusing their ids.
+    conn.execute_batch(
+        r#"
+        UPDATE message SET message_id = lower(hex(randomblob(16))) WHERE message_id IS NULL;
+        CREATE UNIQUE INDEX IF NOT EXISTS message_contact_message_id
+            ON message (contact_onion_id, message_id);
+        "#,
+    )?;
+
+    // Entries belong to outgoing messages, removed together with their message.
+    // Unsent messages of releases without outbox are failed, the user decides to retry them.
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS outbox (
+            message_id TEXT PRIMARY KEY,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at INTEGER NOT NULL,
+            expires_at INTEGER,
+            failed INTEGER NOT NULL DEFAULT 0,
+            awaiting_receipt INTEGER NOT NULL DEFAULT 0
+        );
+
+        CREATE TRIGGER IF NOT EXISTS outbox_delete_message
+        AFTER DELETE ON message
+        WHEN OLD.is_incoming = 0
+        BEGIN
+            DELETE FROM outbox WHERE message_id = OLD.message_id;
+        END;
+
+        INSERT OR IGNORE INTO
+            outbox (message_id, attempts, next_attempt_at, failed)
+        SELECT
+            message_id, 0, timestamp, 1
+        FROM message
+        WHERE
+            is_incoming = 0
+          AND
+            sent_status = 0;
+        "#,
+    )?;
+
+    Ok(())
 }
 
 // --- User ---
@@ -203,6 +322,15 @@ pub struct ContactDb {
     /// Column public_key.
     pub public_key: String,
 
+    /// Column prekey_bundle.
+    pub prekey_bundle: Option<String>,
+
+    /// Column verified_key, public key the user verified with the safety number.
+    pub verified_key: Option<String>,
+
+    /// Column deniable, set up sessions with the deniable handshake.
+    pub deniable: bool,
+
     /// Computed field showing timestamp of last message with this contact..
     pub last_message_at: i32,
 
@@ -225,6 +353,9 @@ pub struct UpdateContactDb {
 
     /// Optional update for public_key column.
     pub public_key: Option<String>,
+
+    /// Optional update for prekey_bundle column.
+    pub prekey_bundle: Option<String>,
 }
 
 impl ContactDb {
@@ -243,6 +374,9 @@ impl ContactDb {
                 contact.onion_id,
                 contact.nickname,
                 contact.public_key,
+                contact.prekey_bundle,
+                contact.verified_key,
+                contact.deniable,
                 COALESCE(MAX(message.timestamp), 0) AS last_message_at,
                 contact.last_viewed_at,
                 COUNT(message.id) AS amount_unread_messages
@@ -272,6 +406,51 @@ impl ContactDb {
 
         Ok(results)
     }
+
+    /// Set or clear public key the user verified for contact.
+    pub async fn set_verified_key(
+        onion_id: &str,
+        verified_key: Option<&str>,
+        conn: DatabaseConnection,
+    ) -> Result<usize, error::DatabaseError> {
+        let conn = conn.lock().await;
+        Ok(conn.execute(
+            "UPDATE contact SET verified_key = ? WHERE onion_id = ?",
+            params![verified_key, onion_id],
+        )?)
+    }
+
+    /// Set if sessions with contact use the deniable handshake.
+    pub async fn set_deniable(
+        onion_id: &str,
+        deniable: bool,
+        conn: DatabaseConnection,
+    ) -> Result<usize, error::DatabaseError> {
+        let conn = conn.lock().await;
+        Ok(conn.execute(
+            "UPDATE contact SET deniable = ? WHERE onion_id = ?",
+            params![deniable, onion_id],
+        )?)
+    }
+
+    /// Replace public key of contact after a verified rotation.
+    /// Its prekey bundle was signed with the old key and is dropped.
+    pub async fn rotate_public_key(
+        onion_id: &str,
+        public_key: &str,
+        conn: DatabaseConnection,
+    ) -> Result<usize, error::DatabaseError> {
+        let conn = conn.lock().await;
+        Ok(conn.execute(
+            "UPDATE contact SET public_key = ?, prekey_bundle = NULL WHERE onion_id = ?",
+            params![public_key, onion_id],
+        )?)
+    }
+
+    /// Check if the current public key of contact is verified.
+    pub fn is_verified(&self) -> bool {
+        self.verified_key.as_deref() == Some(self.public_key.as_str())
+    }
 }
 
 impl DbModel for ContactDb {
@@ -292,6 +471,9 @@ impl DbModel for ContactDb {
             ("onion_id", &self.onion_id),
             ("nickname", &self.nickname),
             ("public_key", &self.public_key),
+            ("prekey_bundle", &self.prekey_bundle),
+            ("verified_key", &self.verified_key),
+            ("deniable", &self.deniable),
             ("last_viewed_at", &self.last_viewed_at),
         ]
     }
@@ -301,6 +483,9 @@ impl DbModel for ContactDb {
             onion_id: row.get("onion_id")?,
             nickname: row.get("nickname")?,
             public_key: row.get("public_key")?,
+            prekey_bundle: row.get("prekey_bundle")?,
+            verified_key: row.get("verified_key")?,
+            deniable: row.get("deniable")?,
             last_message_at: row.get("last_message_at").unwrap_or(0),
             last_viewed_at: row.get("last_viewed_at")?,
             amount_unread_messages: row.get("amount_unread_messages").unwrap_or(0),
@@ -324,6 +509,10 @@ impl DbUpdateModel<ContactDb> for UpdateContactDb {
                 "public_key",
                 self.public_key.as_ref().map(|v| v as &dyn ToSql),
             ),
+            (
+                "prekey_bundle",
+                self.prekey_bundle.as_ref().map(|v| v as &dyn ToSql),
+            ),
         ]
     }
 }
@@ -337,6 +526,9 @@ pub struct MessageDb {
     /// PK Id of message.
     pub id: i64,
 
+    /// Column message_id, random id shared with peer.
+    pub message_id: String,
+
     /// Column contact_onion_id.
     pub contact_onion_id: String,
 
@@ -354,8 +546,22 @@ pub struct MessageDb {
 
     /// Column verified_status.
     pub verified_status: bool,
+
+    /// Column delivered_at, when peer acknowledged our message.
+    pub delivered_at: Option<i64>,
+
+    /// Column read_at, when peer read our message or we read its message.
+    pub read_at: Option<i64>,
+
+    /// Delivery gave up, from the outbox entry of the message.
+    pub failed: bool,
 }
 
+/// Select of messages joined with the state of their outbox entry.
+const SELECT_MESSAGES: &str = "SELECT message.*, COALESCE(outbox.failed, 0) AS failed
+     FROM message
+     LEFT JOIN outbox ON outbox.message_id = message.message_id AND message.is_incoming = 0";
+
 /// Type allowing to update a message.
 #[non_exhaustive]
 #[derive(serde::Serialize)]
@@ -382,24 +588,31 @@ impl DbModel for MessageDb {
 
     fn insert_values(&self) -> Vec<(&'static str, &dyn ToSql)> {
         vec![
+            ("message_id", &self.message_id),
             ("contact_onion_id", &self.contact_onion_id),
             ("body", &self.body),
             ("timestamp", &self.timestamp),
             ("is_incoming", &self.is_incoming),
             ("sent_status", &self.sent_status),
             ("verified_status", &self.verified_status),
+            ("delivered_at", &self.delivered_at),
+            ("read_at", &self.read_at),
         ]
     }
 
     fn from_row(row: &Row) -> rusqlite::Result<Self> {
         Ok(Self {
             id: row.get("id")?,
+            message_id: row.get("message_id")?,
             contact_onion_id: row.get("contact_onion_id")?,
             body: row.get("body")?,
             timestamp: row.get("timestamp")?,
             is_incoming: row.get("is_incoming")?,
             sent_status: row.get("sent_status")?,
             verified_status: row.get("verified_status")?,
+            delivered_at: row.get("delivered_at")?,
+            read_at: row.get("read_at")?,
+            failed: row.get("failed")?,
         })
     }
 }
@@ -422,6 +635,71 @@ impl DbUpdateModel<MessageDb> for UpdateMessageDb {
 }
 
 impl MessageDb {
+    /// Insert message unless a message of the same contact with the same message id exists.
+    /// Returns `None` for a duplicate.
+    pub fn insert_unique_with(
+        &self,
+        conn: &Connection,
+    ) -> Result<Option<InsertId>, error::DatabaseError> {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM message WHERE contact_onion_id = ? AND message_id = ?)",
+            params![self.contact_onion_id, self.message_id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(None);
+        }
+
+        Ok(Some(self.insert_with(conn)?))
+    }
+
+    /// Insert outgoing message together with its outbox entry.
+    pub async fn insert_queued(
+        &self,
+        entry: &OutboxDb,
+        conn: DatabaseConnection,
+    ) -> Result<InsertId, error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+        let insert_id = self.insert_with(&tx)?;
+        entry.queue_with(&tx)?;
+        tx.commit()?;
+        Ok(insert_id)
+    }
+
+    /// Retrieve outgoing message by its message id.
+    pub async fn retrieve_by_message_id(
+        message_id: &str,
+        conn: DatabaseConnection,
+    ) -> Result<Option<Self>, error::DatabaseError> {
+        let conn = conn.lock().await;
+        let result = conn.query_row(
+            &format!("{SELECT_MESSAGES} WHERE message.message_id = ? AND message.is_incoming = 0"),
+            [message_id],
+            Self::from_row,
+        );
+
+        match result {
+            Ok(message) => Ok(Some(message)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Delete outgoing message which was not sent yet, together with its outbox entry.
+    /// Returns if a message was deleted.
+    pub async fn cancel(
+        message_id: &str,
+        conn: DatabaseConnection,
+    ) -> Result<bool, error::DatabaseError> {
+        let conn = conn.lock().await;
+        let deleted = conn.execute(
+            "DELETE FROM message WHERE message_id = ? AND is_incoming = 0 AND sent_status = 0",
+            [message_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
     /// Retrieve messages for chat.
     pub async fn retrieve_messages(
         onion_id: &str,
@@ -435,12 +713,13 @@ impl MessageDb {
         let mut stmt = conn.prepare("UPDATE contact SET last_viewed_at = ? WHERE onion_id = ?")?;
         stmt.execute(params![ts, onion_id])?;
 
-        let mut sql = "SELECT * FROM MESSAGE
+        let mut sql = format!(
+            "{SELECT_MESSAGES}
              WHERE
                 contact_onion_id = ?
              ORDER BY
                 timestamp DESC"
-            .to_string();
+        );
 
         if limit.is_some() {
             sql.push_str(" LIMIT ?");
@@ -475,27 +754,130 @@ impl MessageDb {
         Ok(results)
     }
 
-    /// Retrieve failed chat message.
-    pub async fn failed_messages(
+    /// Apply receipt of peer to the messages we sent it.
+    /// Returns the ids of messages whose status changed.
+    pub async fn apply_receipt(
+        onion_id: &str,
+        kind: ReceiptKind,
+        message_ids: &[String],
+        conn: DatabaseConnection,
+    ) -> Result<Vec<String>, error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+
+        // A read message is delivered as well, and delivered messages need no retry.
+        let sql = match kind {
+            ReceiptKind::Delivered => {
+                "UPDATE message
+                 SET delivered_at = ?1, sent_status = 1
+                 WHERE
+                    contact_onion_id = ?2
+                  AND
+                    message_id = ?3
+                  AND
+                    is_incoming = 0
+                  AND
+                    delivered_at IS NULL"
+            }
+            ReceiptKind::Read => {
+                "UPDATE message
+                 SET read_at = ?1, delivered_at = COALESCE(delivered_at, ?1), sent_status = 1
+                 WHERE
+                    contact_onion_id = ?2
+                  AND
+                    message_id = ?3
+                  AND
+                    is_incoming = 0
+                  AND
+                    read_at IS NULL"
+            }
+        };
+
+        let ts = chrono::Utc::now().timestamp();
+        let mut changed = Vec::new();
+        {
+            let mut stmt = tx.prepare(sql)?;
+            let mut dequeue = tx.prepare(
+                "DELETE FROM outbox WHERE message_id IN (
+                    SELECT message_id FROM message
+                    WHERE
+                        contact_onion_id = ?1
+                      AND
+                        message_id = ?2
+                      AND
+                        is_incoming = 0
+                 )",
+            )?;
+            for message_id in message_ids {
+                if stmt.execute(params![ts, onion_id, message_id])? > 0 {
+                    changed.push(message_id.clone());
+                }
+                dequeue.execute(params![onion_id, message_id])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// Mark incoming messages of contact as read.
+    /// Returns the ids of messages which were unread.
+    pub async fn mark_read(
+        onion_id: &str,
+        conn: DatabaseConnection,
+    ) -> Result<Vec<String>, error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+
+        let unread = {
+            let mut stmt = tx.prepare(
+                "SELECT message_id FROM message
+                 WHERE
+                    contact_onion_id = ?
+                  AND
+                    is_incoming = 1
+                  AND
+                    read_at IS NULL",
+            )?;
+            let rows = stmt.query_map(params![onion_id], |row| row.get(0))?;
+
+            let mut results = Vec::new();
+            for row in rows {
+                results.push(row?);
+            }
+            results
+        };
+
+        tx.execute(
+            "UPDATE message SET read_at = ? WHERE contact_onion_id = ? AND is_incoming = 1 AND read_at IS NULL",
+            params![chrono::Utc::now().timestamp(), onion_id],
+        )?;
+
+        tx.commit()?;
+        Ok(unread)
+    }
+
+    /// Retrieve outgoing messages to contact sent after timestamp, oldest first.
+    pub async fn sent_since(
+        onion_id: &str,
+        since: i64,
         conn: DatabaseConnection,
     ) -> Result<Vec<Self>, error::DatabaseError> {
         let conn = conn.lock().await;
 
-        let mut stmt = conn.prepare(
-            "SELECT * FROM MESSAGE
+        let mut stmt = conn.prepare(&format!(
+            "{SELECT_MESSAGES}
              WHERE
-                sent_status = 0
+                contact_onion_id = ?
               AND
                 is_incoming = 0
               AND
-                timestamp <= strftime('%s','now') - 30
-              AND
-                timestamp >= strftime('%s','now') - 300
+                timestamp > ?
              ORDER BY
-                timestamp DESC",
-        )?;
+                timestamp ASC"
+        ))?;
 
-        let rows = stmt.query_map([], Self::from_row)?;
+        let rows = stmt.query_map(params![onion_id, since], Self::from_row)?;
 
         let mut results = Vec::new();
         for row in rows {
@@ -504,127 +886,842 @@ impl MessageDb {
 
         Ok(results)
     }
+
+    /// Timestamp of last message received from contact, 0 if none.
+    pub async fn last_incoming_timestamp(
+        onion_id: &str,
+        conn: DatabaseConnection,
+    ) -> Result<i64, error::DatabaseError> {
+        let conn = conn.lock().await;
+        Ok(conn.query_row(
+            "SELECT COALESCE(MAX(timestamp), 0) FROM message
+             WHERE contact_onion_id = ? AND is_incoming = 1",
+            [onion_id],
+            |row| row.get(0),
+        )?)
+    }
 }
 
-/// Type to get and set configuration.
+// --- Outbox ---
+
+/// Represents row in outbox table, an outgoing message waiting for delivery.
 #[non_exhaustive]
-pub struct ConfigDb;
+pub struct OutboxDb {
+    /// PK message id of queued message.
+    pub message_id: String,
 
-impl ConfigDb {
-    /// Insert or update config value.
-    pub async fn set(
-        key: &str,
-        value: &str,
-        conn: DatabaseConnection,
-    ) -> Result<(), error::DatabaseError> {
-        let conn = conn.lock().await;
-        conn.execute(
-            r#"
-                INSERT INTO
-                   config
-                VALUES
-                    (?, ?)
-                ON CONFLICT(key) DO UPDATE
-                    SET value=excluded.value
-            "#,
-            params![key, value],
-        )?;
+    /// Column attempts, delivery attempts without confirmed delivery so far.
+    pub attempts: i64,
 
-        Ok(())
-    }
+    /// Column next_attempt_at.
+    pub next_attempt_at: i64,
 
-    /// Get config value by key.
-    pub async fn get(
-        key: &str,
-        conn: DatabaseConnection,
-    ) -> Result<Option<String>, error::DatabaseError> {
-        let conn = conn.lock().await;
-        let result = conn.query_row("SELECT value FROM config WHERE key = ?1", [key], |row| {
-            row.get::<_, String>(0)
-        });
+    /// Column expires_at, delivery gives up after it.
+    pub expires_at: Option<i64>,
 
-        match result {
-            Ok(v) => Ok(Some(v)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e.into()),
-        }
-    }
+    /// Column failed, delivery gave up until the user retries.
+    pub failed: bool,
 
-    // --- Type specific getters and setters ---
+    /// Column awaiting_receipt, message was written and is sent again
+    /// if no delivery receipt arrives until `next_attempt_at`.
+    pub awaiting_receipt: bool,
+}
 
-    /// Set config value as bool.
-    pub async fn set_bool(
-        key: &str,
-        value: bool,
-        conn: DatabaseConnection,
-    ) -> Result<(), error::DatabaseError> {
-        Self::set(key, &value.to_string(), conn).await
-    }
-    /// Get config value as bool.
-    pub async fn get_bool(
-        key: &str,
-        conn: DatabaseConnection,
-    ) -> Result<bool, error::DatabaseError> {
-        Ok(Self::get(key, conn).await?.as_deref() == Some("true"))
-    }
+/// Type allowing to update an outbox entry.
+#[non_exhaustive]
+pub struct UpdateOutboxDb {
+    /// PK of entry to update.
+    pub message_id: String,
+
+    /// Optional update for attempts column.
+    pub attempts: Option<i64>,
+
+    /// Optional update for next_attempt_at column.
+    pub next_attempt_at: Option<i64>,
+
+    /// Optional update for failed column.
+    pub failed: Option<bool>,
 }
 
-/// Public trait implementing default methods (insert, retrieve, update) for Db types.
-#[async_trait]
-pub trait DbModel: Sized {
-    /// Return table name.
-    fn table() -> &'static str;
+impl DbModel for OutboxDb {
+    fn table() -> &'static str {
+        "outbox"
+    }
 
-    /// Primary key can be known or is autoincrement.
-    fn primary_key(&self) -> PrimaryKey;
+    fn primary_key(&self) -> PrimaryKey {
+        PrimaryKey::Provided(&self.message_id)
+    }
 
-    /// Column used to delete rows.
-    fn delete_by() -> &'static str;
+    fn delete_by() -> &'static str {
+        "message_id"
+    }
 
-    /// List of (column -> values) for INSERT (..column) VALUES (..values).
-    fn insert_values(&self) -> Vec<(&'static str, &dyn ToSql)>;
+    fn insert_values(&self) -> Vec<(&'static str, &dyn ToSql)> {
+        vec![
+            ("message_id", &self.message_id),
+            ("attempts", &self.attempts),
+            ("next_attempt_at", &self.next_attempt_at),
+            ("expires_at", &self.expires_at),
+            ("failed", &self.failed),
+            ("awaiting_receipt", &self.awaiting_receipt),
+        ]
+    }
 
-    /// Row to self type.
-    fn from_row(row: &Row) -> rusqlite::Result<Self>;
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            message_id: row.get("message_id")?,
+            attempts: row.get("attempts")?,
+            next_attempt_at: row.get("next_attempt_at")?,
+            expires_at: row.get("expires_at")?,
+            failed: row.get("failed")?,
+            awaiting_receipt: row.get("awaiting_receipt")?,
+        })
+    }
+}
 
-    /// Default insert behavior.
-    async fn insert(&self, conn: DatabaseConnection) -> Result<InsertId, error::DatabaseError> {
-        let conn = conn.lock().await;
+impl DbUpdateModel<OutboxDb> for UpdateOutboxDb {
+    fn pk_column() -> &'static str {
+        "message_id"
+    }
 
-        let columns: Vec<&str> = self.insert_values().iter().map(|(c, _)| *c).collect();
-        let values: Vec<&dyn ToSql> = self.insert_values().iter().map(|(_, v)| *v).collect();
+    fn pk_value(&self) -> &dyn ToSql {
+        &self.message_id
+    }
 
-        let sql = format!(
-            "INSERT INTO {} ({}) VALUES ({})",
-            Self::table(),
-            columns.join(","),
-            vec!["?"; values.len()].join(","),
-        );
+    fn update_values(&self) -> Vec<(&'static str, Option<&dyn ToSql>)> {
+        vec![
+            ("attempts", self.attempts.as_ref().map(|v| v as &dyn ToSql)),
+            (
+                "next_attempt_at",
+                self.next_attempt_at.as_ref().map(|v| v as &dyn ToSql),
+            ),
+            ("failed", self.failed.as_ref().map(|v| v as &dyn ToSql)),
+        ]
+    }
+}
 
-        conn.execute(&sql, values.as_slice())?;
-        match self.primary_key() {
-            PrimaryKey::Provided(id) => Ok(InsertId::Text(id.into())),
-            PrimaryKey::AutoIncrement => Ok(InsertId::Integer(conn.last_insert_rowid())),
+impl OutboxDb {
+    /// New entry for a message, due right away.
+    /// Expiry is in seconds from now, `None` to retry until cancelled.
+    pub fn new(message_id: &str, expiry: Option<i64>) -> Self {
+        let now = chrono::Utc::now().timestamp();
+        Self {
+            message_id: message_id.into(),
+            attempts: 0,
+            next_attempt_at: now,
+            expires_at: expiry.map(|expiry| now + expiry),
+            failed: false,
+            awaiting_receipt: false,
         }
     }
 
-    /// Default select behavior.
-    async fn retrieve(
-        onion_id: &str,
-        conn: DatabaseConnection,
-    ) -> Result<Self, error::DatabaseError> {
-        let conn = conn.lock().await;
-        let sql = format!("SELECT * FROM {} WHERE onion_id = ?", Self::table());
+    /// Insert entry, or start over an existing entry of the message.
+    pub fn queue_with(&self, conn: &Connection) -> Result<(), error::DatabaseError> {
+        conn.execute(
+            "INSERT INTO outbox (
+                message_id, attempts, next_attempt_at, expires_at, failed, awaiting_receipt
+             )
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(message_id) DO UPDATE
+             SET
+                attempts = excluded.attempts,
+                next_attempt_at = excluded.next_attempt_at,
+                expires_at = excluded.expires_at,
+                failed = excluded.failed,
+                awaiting_receipt = excluded.awaiting_receipt",
+            params![
+                self.message_id,
+                self.attempts,
+                self.next_attempt_at,
+                self.expires_at,
+                self.failed,
+                self.awaiting_receipt
+            ],
+        )?;
+        Ok(())
+    }
 
-        Ok(conn.query_row(&sql, [onion_id], Self::from_row)?)
+    /// Queue stored message again, it is unsent until delivered.
+    pub async fn requeue(&self, conn: DatabaseConnection) -> Result<(), error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+        self.queue_with(&tx)?;
+        tx.execute(
+            "UPDATE message SET sent_status = 0 WHERE message_id = ? AND is_incoming = 0",
+            [&self.message_id],
+        )?;
+        tx.commit()?;
+        Ok(())
     }
 
-    /// Default select all behavior.
-    async fn retrieve_all(
-        order_column: Option<&str>,
-        desc: Option<bool>,
+    /// Retrieve all queued entries of peers with an entry due, per onion id of peer.
+    /// Entries of a peer are in the order their messages were written.
+    pub async fn due(
         conn: DatabaseConnection,
-    ) -> Result<Vec<Self>, error::DatabaseError> {
+    ) -> Result<std::collections::HashMap<String, Vec<Self>>, error::DatabaseError> {
+        let conn = conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT outbox.*, message.contact_onion_id FROM outbox
+             JOIN message ON message.message_id = outbox.message_id AND message.is_incoming = 0
+             WHERE
+                outbox.failed = 0
+              AND
+                message.contact_onion_id IN (
+                    SELECT message.contact_onion_id FROM outbox
+                    JOIN message ON message.message_id = outbox.message_id AND message.is_incoming = 0
+                    WHERE
+                        outbox.failed = 0
+                      AND
+                        outbox.next_attempt_at <= ?
+                )
+             ORDER BY
+                message.id ASC",
+        )?;
+        let rows = stmt.query_map([chrono::Utc::now().timestamp()], |row| {
+            Ok((
+                row.get::<_, String>("contact_onion_id")?,
+                Self::from_row(row)?,
+            ))
+        })?;
+
+        let mut results = std::collections::HashMap::<String, Vec<Self>>::new();
+        for row in rows {
+            let (onion_id, entry) = row?;
+            results.entry(onion_id).or_default().push(entry);
+        }
+
+        Ok(results)
+    }
+
+    /// Timestamp of the next attempt, if an attempt failed now.
+    pub fn retry_at(&self, now: i64) -> i64 {
+        outbox::next_attempt_at(now, self.attempts + 1, self.expires_at)
+    }
+
+    /// Make entries wait at least until timestamp.
+    pub async fn postpone(
+        entries: &[Self],
+        until: i64,
+        conn: DatabaseConnection,
+    ) -> Result<(), error::DatabaseError> {
+        let conn = conn.lock().await;
+        let mut stmt = conn.prepare(
+            "UPDATE outbox SET next_attempt_at = MAX(next_attempt_at, ?) WHERE message_id = ?",
+        )?;
+        for entry in entries {
+            stmt.execute(params![until, entry.message_id])?;
+        }
+
+        Ok(())
+    }
+
+    /// Mark message sent, but keep its entry until the receipt of peer arrives.
+    /// The message is sent again at `until` if no receipt arrived by then.
+    pub async fn await_receipt(
+        &self,
+        until: i64,
+        conn: DatabaseConnection,
+    ) -> Result<(), error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE outbox
+             SET awaiting_receipt = 1, attempts = ?, next_attempt_at = ?
+             WHERE message_id = ?",
+            params![self.attempts + 1, until, self.message_id],
+        )?;
+        tx.execute(
+            "UPDATE message SET sent_status = 1 WHERE message_id = ? AND is_incoming = 0",
+            [&self.message_id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Remove entry of delivered message and mark the message sent.
+    pub async fn complete(
+        message_id: &str,
+        conn: DatabaseConnection,
+    ) -> Result<(), error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM outbox WHERE message_id = ?", [message_id])?;
+        tx.execute(
+            "UPDATE message SET sent_status = 1 WHERE message_id = ? AND is_incoming = 0",
+            [message_id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+// --- Prekey ---
+
+/// Represents row in prekey table.
+#[non_exhaustive]
+pub struct PrekeyDb {
+    /// Column id.
+    pub id: u32,
+
+    /// Column private_key.
+    pub private_key: String,
+
+    /// Column public_key.
+    pub public_key: String,
+
+    /// Column signature.
+    pub signature: String,
+
+    /// Column created_at.
+    pub created_at: i64,
+}
+
+impl DbModel for PrekeyDb {
+    fn table() -> &'static str {
+        "prekey"
+    }
+
+    fn primary_key(&self) -> PrimaryKey {
+        PrimaryKey::AutoIncrement
+    }
+
+    fn delete_by() -> &'static str {
+        "id"
+    }
+
+    fn insert_values(&self) -> Vec<(&'static str, &dyn ToSql)> {
+        vec![
+            ("id", &self.id),
+            ("private_key", &self.private_key),
+            ("public_key", &self.public_key),
+            ("signature", &self.signature),
+            ("created_at", &self.created_at),
+        ]
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            private_key: row.get("private_key")?,
+            public_key: row.get("public_key")?,
+            signature: row.get("signature")?,
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+impl PrekeyDb {
+    /// Retrieve most recent signed prekey.
+    pub async fn latest(conn: DatabaseConnection) -> Result<Option<Self>, error::DatabaseError> {
+        let conn = conn.lock().await;
+        let result = conn.query_row(
+            "SELECT * FROM prekey ORDER BY created_at DESC LIMIT 1",
+            [],
+            Self::from_row,
+        );
+
+        match result {
+            Ok(v) => Ok(Some(v)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Retrieve signed prekey by id.
+    pub async fn retrieve_by_id(
+        id: u32,
+        conn: DatabaseConnection,
+    ) -> Result<Self, error::DatabaseError> {
+        let conn = conn.lock().await;
+        Ok(conn.query_row("SELECT * FROM prekey WHERE id = ?", [id], Self::from_row)?)
+    }
+
+    /// Remember nonce of accepted prekey handshake until it expires.
+    /// Returns false if the nonce was seen before, so the handshake is replayed.
+    pub async fn record_handshake_nonce(
+        nonce: &[u8; 16],
+        expires_at: i64,
+        conn: DatabaseConnection,
+    ) -> Result<bool, error::DatabaseError> {
+        let conn = conn.lock().await;
+        conn.execute(
+            "DELETE FROM prekey_handshake WHERE expires_at < ?",
+            [chrono::Utc::now().timestamp()],
+        )?;
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO prekey_handshake (nonce, expires_at) VALUES (?, ?)",
+            params![hex::encode(nonce), expires_at],
+        )?;
+        Ok(inserted > 0)
+    }
+}
+
+// --- Key rotation ---
+
+/// Represents row in key_rotation table, a rotation notice not yet delivered to contact.
+#[non_exhaustive]
+pub struct KeyRotationDb {
+    /// PK Id of notice.
+    pub id: i64,
+
+    /// Column contact_onion_id.
+    pub contact_onion_id: String,
+
+    /// Column notice containing serialized rotation notice.
+    pub notice: String,
+
+    /// Column created_at.
+    pub created_at: i64,
+}
+
+impl DbModel for KeyRotationDb {
+    fn table() -> &'static str {
+        "key_rotation"
+    }
+
+    fn primary_key(&self) -> PrimaryKey {
+        PrimaryKey::AutoIncrement
+    }
+
+    fn delete_by() -> &'static str {
+        "id"
+    }
+
+    fn insert_values(&self) -> Vec<(&'static str, &dyn ToSql)> {
+        vec![
+            ("contact_onion_id", &self.contact_onion_id),
+            ("notice", &self.notice),
+            ("created_at", &self.created_at),
+        ]
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            contact_onion_id: row.get("contact_onion_id")?,
+            notice: row.get("notice")?,
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+impl KeyRotationDb {
+    /// Retrieve undelivered notices, oldest first.
+    pub async fn pending(conn: DatabaseConnection) -> Result<Vec<Self>, error::DatabaseError> {
+        let conn = conn.lock().await;
+        let mut stmt = conn.prepare("SELECT * FROM key_rotation ORDER BY id ASC")?;
+        let rows = stmt.query_map([], Self::from_row)?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+
+        Ok(results)
+    }
+
+    /// Queue notice for every contact and replace keys of user in one transaction.
+    /// Deniable contacts each get their own notice, so it is created per contact.
+    pub async fn rotate(
+        onion_id: &str,
+        private_key: &str,
+        public_key: &str,
+        notice_for: impl Fn(&ContactDb) -> rusqlite::Result<String>,
+        conn: DatabaseConnection,
+    ) -> Result<(), error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE user SET private_key = ?, public_key = ? WHERE onion_id = ?",
+            params![private_key, public_key, onion_id],
+        )?;
+
+        let contacts = {
+            let mut stmt = tx.prepare("SELECT * FROM contact")?;
+            let rows = stmt.query_map([], ContactDb::from_row)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        let created_at = chrono::Utc::now().timestamp();
+        for contact in &contacts {
+            tx.execute(
+                "INSERT INTO key_rotation (contact_onion_id, notice, created_at) VALUES (?, ?, ?)",
+                params![contact.onion_id, notice_for(contact)?, created_at],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+// --- Contact request ---
+
+/// Represents row in contact_request table, a peer asking to become a contact.
+/// Kept apart from contacts, so nothing of the peer is trusted until accepted.
+#[non_exhaustive]
+#[derive(serde::Serialize)]
+pub struct ContactRequestDb {
+    /// Column onion_id.
+    pub onion_id: String,
+
+    /// Column public_key.
+    pub public_key: String,
+
+    /// Column nickname, as introduced by the peer.
+    pub nickname: String,
+
+    /// Column note.
+    pub note: String,
+
+    /// Column received_at.
+    pub received_at: i64,
+
+    /// Column verified, the peer proved that it owns the onion id and key.
+    pub verified: bool,
+}
+
+impl DbModel for ContactRequestDb {
+    fn table() -> &'static str {
+        "contact_request"
+    }
+
+    fn primary_key(&self) -> PrimaryKey {
+        PrimaryKey::Provided(&self.onion_id)
+    }
+
+    fn delete_by() -> &'static str {
+        "onion_id"
+    }
+
+    fn insert_values(&self) -> Vec<(&'static str, &dyn ToSql)> {
+        vec![
+            ("onion_id", &self.onion_id),
+            ("public_key", &self.public_key),
+            ("nickname", &self.nickname),
+            ("note", &self.note),
+            ("received_at", &self.received_at),
+            ("verified", &self.verified),
+        ]
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            onion_id: row.get("onion_id")?,
+            public_key: row.get("public_key")?,
+            nickname: row.get("nickname")?,
+            note: row.get("note")?,
+            received_at: row.get("received_at")?,
+            verified: row.get("verified")?,
+        })
+    }
+}
+
+/// Outcome of storing a contact request.
+#[non_exhaustive]
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoredRequest {
+    /// Request is stored, or refreshed the pending one of the peer.
+    Stored,
+
+    /// Peer already has a pending request with another key, which is kept.
+    /// Only a verified request replaces an unverified one, whose sender may have posed as peer.
+    ConflictingKey,
+}
+
+impl ContactRequestDb {
+    /// Store request, refreshing a pending one of the same peer and key.
+    /// A pending key is never replaced, so a later request can't swap it.
+    /// Oldest requests of other peers are evicted to keep at most `max_pending`.
+    pub async fn store(
+        &self,
+        max_pending: i64,
+        conn: DatabaseConnection,
+    ) -> Result<StoredRequest, error::DatabaseError> {
+        let conn = conn.lock().await;
+        let pending: Option<(String, bool)> = conn
+            .query_row(
+                "SELECT public_key, verified FROM contact_request WHERE onion_id = ?",
+                [&self.onion_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if let Some((pending_key, pending_verified)) = pending
+            && pending_key != self.public_key
+        {
+            if pending_verified || !self.verified {
+                return Ok(StoredRequest::ConflictingKey);
+            }
+            conn.execute(
+                "DELETE FROM contact_request WHERE onion_id = ?",
+                [&self.onion_id],
+            )?;
+        }
+
+        conn.execute(
+            r#"
+                DELETE FROM contact_request
+                WHERE onion_id IN (
+                    SELECT onion_id FROM contact_request
+                    WHERE onion_id != ?
+                    ORDER BY received_at DESC
+                    LIMIT -1 OFFSET ?
+                )
+            "#,
+            params![self.onion_id, max_pending - 1],
+        )?;
+
+        conn.execute(
+            r#"
+                INSERT INTO
+                    contact_request (onion_id, public_key, nickname, note, received_at, verified)
+                VALUES
+                    (?, ?, ?, ?, ?, ?)
+                ON CONFLICT(onion_id) DO UPDATE
+                    SET nickname=excluded.nickname, note=excluded.note,
+                        received_at=excluded.received_at,
+                        verified=(verified OR excluded.verified)
+            "#,
+            params![
+                self.onion_id,
+                self.public_key,
+                self.nickname,
+                self.note,
+                self.received_at,
+                self.verified
+            ],
+        )?;
+        Ok(StoredRequest::Stored)
+    }
+
+    /// Add peer of request as contact and remove the request in one transaction.
+    /// Returns false if there is no request of peer.
+    pub async fn accept(
+        onion_id: &str,
+        nickname: Option<&str>,
+        conn: DatabaseConnection,
+    ) -> Result<bool, error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+
+        let inserted = tx.execute(
+            r#"
+                INSERT INTO
+                    contact (onion_id, nickname, public_key, last_viewed_at)
+                SELECT
+                    onion_id, COALESCE(?, nickname), public_key, ?
+                FROM contact_request
+                WHERE onion_id = ?
+            "#,
+            params![nickname, chrono::Utc::now().timestamp(), onion_id],
+        )?;
+        tx.execute("DELETE FROM contact_request WHERE onion_id = ?", [onion_id])?;
+        tx.commit()?;
+
+        Ok(inserted > 0)
+    }
+}
+
+// --- Session ---
+
+/// Represents row in session table.
+#[non_exhaustive]
+pub struct SessionDb {
+    /// Column contact_onion_id.
+    pub contact_onion_id: String,
+
+    /// Column state containing serialized ratchet.
+    pub state: String,
+
+    /// Column updated_at.
+    pub updated_at: i64,
+}
+
+impl DbModel for SessionDb {
+    fn table() -> &'static str {
+        "session"
+    }
+
+    fn primary_key(&self) -> PrimaryKey {
+        PrimaryKey::Provided(&self.contact_onion_id)
+    }
+
+    fn delete_by() -> &'static str {
+        "contact_onion_id"
+    }
+
+    fn insert_values(&self) -> Vec<(&'static str, &dyn ToSql)> {
+        vec![
+            ("contact_onion_id", &self.contact_onion_id),
+            ("state", &self.state),
+            ("updated_at", &self.updated_at),
+        ]
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            contact_onion_id: row.get("contact_onion_id")?,
+            state: row.get("state")?,
+            updated_at: row.get("updated_at")?,
+        })
+    }
+}
+
+impl SessionDb {
+    /// Insert or update session, optionally together with a message in one transaction.
+    /// Returns the insert id of the message if one was given and it was not a duplicate.
+    pub async fn save(
+        &self,
+        message: Option<&MessageDb>,
+        conn: DatabaseConnection,
+    ) -> Result<Option<InsertId>, error::DatabaseError> {
+        let mut conn = conn.lock().await;
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            r#"
+                INSERT INTO
+                    session (contact_onion_id, state, updated_at)
+                VALUES
+                    (?, ?, ?)
+                ON CONFLICT(contact_onion_id) DO UPDATE
+                    SET state=excluded.state, updated_at=excluded.updated_at
+            "#,
+            params![self.contact_onion_id, self.state, self.updated_at],
+        )?;
+
+        let insert_id = match message {
+            Some(message) => message.insert_unique_with(&tx)?,
+            None => None,
+        };
+
+        tx.commit()?;
+        Ok(insert_id)
+    }
+}
+
+/// Type to get and set configuration.
+#[non_exhaustive]
+pub struct ConfigDb;
+
+impl ConfigDb {
+    /// Insert or update config value.
+    pub async fn set(
+        key: &str,
+        value: &str,
+        conn: DatabaseConnection,
+    ) -> Result<(), error::DatabaseError> {
+        let conn = conn.lock().await;
+        conn.execute(
+            r#"
+                INSERT INTO
+                   config
+                VALUES
+                    (?, ?)
+                ON CONFLICT(key) DO UPDATE
+                    SET value=excluded.value
+            "#,
+            params![key, value],
+        )?;
+
+        Ok(())
+    }
+
+    /// Get config value by key.
+    pub async fn get(
+        key: &str,
+        conn: DatabaseConnection,
+    ) -> Result<Option<String>, error::DatabaseError> {
+        let conn = conn.lock().await;
+        let result = conn.query_row("SELECT value FROM config WHERE key = ?1", [key], |row| {
+            row.get::<_, String>(0)
+        });
+
+        match result {
+            Ok(v) => Ok(Some(v)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // --- Type specific getters and setters ---
+
+    /// Set config value as bool.
+    pub async fn set_bool(
+        key: &str,
+        value: bool,
+        conn: DatabaseConnection,
+    ) -> Result<(), error::DatabaseError> {
+        Self::set(key, &value.to_string(), conn).await
+    }
+    /// Get config value as bool.
+    pub async fn get_bool(
+        key: &str,
+        conn: DatabaseConnection,
+    ) -> Result<bool, error::DatabaseError> {
+        Ok(Self::get(key, conn).await?.as_deref() == Some("true"))
+    }
+}
+
+/// Public trait implementing default methods (insert, retrieve, update) for Db types.
+#[async_trait]
+pub trait DbModel: Sized {
+    /// Return table name.
+    fn table() -> &'static str;
+
+    /// Primary key can be known or is autoincrement.
+    fn primary_key(&self) -> PrimaryKey;
+
+    /// Column used to delete rows.
+    fn delete_by() -> &'static str;
+
+    /// List of (column -> values) for INSERT (..column) VALUES (..values).
+    fn insert_values(&self) -> Vec<(&'static str, &dyn ToSql)>;
+
+    /// Row to self type.
+    fn from_row(row: &Row) -> rusqlite::Result<Self>;
+
+    /// Default insert behavior.
+    async fn insert(&self, conn: DatabaseConnection) -> Result<InsertId, error::DatabaseError> {
+        let conn = conn.lock().await;
+        self.insert_with(&conn)
+    }
+
+    /// Insert on an already locked connection or transaction.
+    fn insert_with(&self, conn: &Connection) -> Result<InsertId, error::DatabaseError> {
+        let columns: Vec<&str> = self.insert_values().iter().map(|(c, _)| *c).collect();
+        let values: Vec<&dyn ToSql> = self.insert_values().iter().map(|(_, v)| *v).collect();
+
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            Self::table(),
+            columns.join(","),
+            vec!["?"; values.len()].join(","),
+        );
+
+        conn.execute(&sql, values.as_slice())?;
+        match self.primary_key() {
+            PrimaryKey::Provided(id) => Ok(InsertId::Text(id.into())),
+            PrimaryKey::AutoIncrement => Ok(InsertId::Integer(conn.last_insert_rowid())),
+        }
+    }
+
+    /// Default select behavior.
+    async fn retrieve(
+        onion_id: &str,
+        conn: DatabaseConnection,
+    ) -> Result<Self, error::DatabaseError> {
+        let conn = conn.lock().await;
+        let sql = format!("SELECT * FROM {} WHERE onion_id = ?", Self::table());
+
+        Ok(conn.query_row(&sql, [onion_id], Self::from_row)?)
+    }
+
+    /// Default select all behavior.
+    async fn retrieve_all(
+        order_column: Option<&str>,
+        desc: Option<bool>,
+        conn: DatabaseConnection,
+    ) -> Result<Vec<Self>, error::DatabaseError> {
         let conn = conn.lock().await;
         let mut sql = format!("SELECT * FROM {}", Self::table());
 
@@ -718,6 +1815,31 @@ pub trait DbUpdateModel<R: DbModel> {
     }
 }
 
+/// Add column to existing table if it does not exist yet.
+/// `CREATE TABLE IF NOT EXISTS` does not update tables of existing databases.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), error::DatabaseError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>("name"))?
+        .filter_map(Result::ok)
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+        tracing::info!("Added column {}.{} to database.", table, column);
+    }
+
+    Ok(())
+}
+
 /// Helper method to get path do .db file in project_dir.
 fn database_path(project_dir: &std::path::Path) -> std::path::PathBuf {
     project_dir.join("arti-chat.db")
@@ -739,3 +1861,613 @@ fn retrieve_db_encryption_key() -> Result<String, error::DatabaseError> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// In-memory database with all tables and contacts alice and bob.
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().expect("open database");
+        create_tables(&conn).expect("create tables");
+        conn.execute_batch(
+            "INSERT INTO contact (onion_id, nickname, public_key) VALUES
+                ('alice.onion', 'Alice', ''),
+                ('bob.onion', 'Bob', '');",
+        )
+        .expect("insert contacts");
+        conn
+    }
+
+    /// Message of contact with message id.
+    fn message(contact_onion_id: &str, message_id: &str, is_incoming: bool) -> MessageDb {
+        MessageDb {
+            id: 0,
+            message_id: message_id.into(),
+            contact_onion_id: contact_onion_id.into(),
+            body: "{}".into(),
+            timestamp: 0,
+            is_incoming,
+            sent_status: false,
+            verified_status: false,
+            delivered_at: None,
+            read_at: None,
+            failed: false,
+        }
+    }
+
+    /// Queue outgoing message to alice.
+    fn queue(conn: &Connection, message_id: &str, next_attempt_at: i64) {
+        message("alice.onion", message_id, false)
+            .insert_with(conn)
+            .expect("insert message");
+        let mut entry = OutboxDb::new(message_id, None);
+        entry.next_attempt_at = next_attempt_at;
+        entry.queue_with(conn).expect("queue message");
+    }
+
+    /// Amount of rows in table.
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .expect("count rows")
+    }
+
+    /// Session of alice with bob, and the session of bob.
+    fn ratchets() -> (crate::ratchet::RatchetChain, crate::ratchet::RatchetChain) {
+        use crate::ratchet::{Handshake, HandshakeOptions};
+        let alice_key = ed25519_dalek::SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = ed25519_dalek::SigningKey::generate(&mut rand_core::OsRng);
+
+        let (handshake, alice_secret) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            HandshakeOptions::default(),
+        );
+        let (reply, bob_secret) = handshake
+            .accept(
+                "bob.onion",
+                &alice_key.verifying_key(),
+                &bob_key,
+                HandshakeOptions::default(),
+            )
+            .expect("accept handshake");
+        let bob = handshake
+            .complete("bob.onion", &alice_key.verifying_key(), &bob_secret, false)
+            .expect("complete as responder");
+        let alice = reply
+            .complete("alice.onion", &bob_key.verifying_key(), &alice_secret, true)
+            .expect("complete as initiator");
+        (alice, bob)
+    }
+
+    #[tokio::test]
+    async fn session_survives_restart() {
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(test_db()));
+        let (mut alice, bob) = ratchets();
+        let session = SessionDb {
+            contact_onion_id: "alice.onion".into(),
+            state: serde_json::to_string(&bob).expect("serialize session"),
+            updated_at: 0,
+        };
+        session
+            .save(None, conn.clone())
+            .await
+            .expect("save session");
+        drop(bob);
+
+        let stored = SessionDb::retrieve_all(None, None, conn.clone())
+            .await
+            .expect("retrieve sessions");
+        assert_eq!(stored.len(), 1);
+        let mut bob: crate::ratchet::RatchetChain =
+            serde_json::from_str(&stored[0].state).expect("parse session");
+
+        let msg = alice.encrypt(b"hello", "alice.onion".into());
+        assert_eq!(bob.decrypt(&msg).expect("decrypt"), b"hello");
+    }
+
+    #[tokio::test]
+    async fn session_is_saved_with_its_message() {
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(test_db()));
+        let message = message("alice.onion", "id", true);
+        let mut session = SessionDb {
+            contact_onion_id: "alice.onion".into(),
+            state: "first".into(),
+            updated_at: 0,
+        };
+        let insert_id = session
+            .save(Some(&message), conn.clone())
+            .await
+            .expect("save session");
+        assert!(insert_id.is_some());
+
+        // Saved again, the session is replaced.
+        session.state = "second".into();
+        session
+            .save(None, conn.clone())
+            .await
+            .expect("save session");
+        let conn = conn.lock().await;
+        assert_eq!(count(&conn, "session"), 1);
+        assert_eq!(count(&conn, "message"), 1);
+        let state: String = conn
+            .query_row("SELECT state FROM session", [], |row| row.get(0))
+            .expect("stored state");
+        assert_eq!(state, "second");
+
+        // Removed together with its contact.
+        conn.execute("DELETE FROM contact WHERE onion_id = 'alice.onion'", [])
+            .expect("delete contact");
+        assert_eq!(count(&conn, "session"), 0);
+    }
+
+    #[tokio::test]
+    async fn resends_outgoing_messages_after_reset_timestamp() {
+        let conn = test_db();
+        for (timestamp, is_incoming) in [(10, false), (20, true), (30, false), (5, false)] {
+            let mut message = message("alice.onion", &timestamp.to_string(), is_incoming);
+            message.body = timestamp.to_string();
+            message.timestamp = timestamp;
+            message.insert_with(&conn).expect("insert message");
+        }
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+
+        let last = MessageDb::last_incoming_timestamp("alice.onion", conn.clone())
+            .await
+            .expect("last incoming");
+        assert_eq!(last, 20);
+        let none = MessageDb::last_incoming_timestamp("bob.onion", conn.clone())
+            .await
+            .expect("last incoming");
+        assert_eq!(none, 0);
+
+        let resend = MessageDb::sent_since("alice.onion", 5, conn.clone())
+            .await
+            .expect("sent since");
+        let bodies: Vec<&str> = resend.iter().map(|msg| msg.body.as_str()).collect();
+        assert_eq!(bodies, ["10", "30"]);
+    }
+
+    #[tokio::test]
+    async fn rotation_replaces_user_keys_and_queues_notice_per_contact() {
+        let conn = test_db();
+        conn.execute_batch(
+            "INSERT INTO user (onion_id, nickname, public_key, private_key)
+                VALUES ('self.onion', 'Self', 'old public', 'old private');",
+        )
+        .expect("insert user");
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+
+        KeyRotationDb::rotate(
+            "self.onion",
+            "new private",
+            "new public",
+            |contact| Ok(format!("notice for {}", contact.onion_id)),
+            conn.clone(),
+        )
+        .await
+        .expect("rotate");
+
+        let pending = KeyRotationDb::pending(conn.clone())
+            .await
+            .expect("pending notices");
+        let contacts: Vec<&str> = pending
+            .iter()
+            .map(|notice| notice.contact_onion_id.as_str())
+            .collect();
+        assert_eq!(contacts, ["alice.onion", "bob.onion"]);
+        assert_eq!(pending[0].notice, "notice for alice.onion");
+        let public_key: String = conn
+            .lock()
+            .await
+            .query_row("SELECT public_key FROM user", [], |row| row.get(0))
+            .expect("public key");
+        assert_eq!(public_key, "new public");
+    }
+
+    #[test]
+    fn message_ids_are_unique_per_contact() {
+        let conn = test_db();
+
+        let first = message("alice.onion", "id", true).insert_unique_with(&conn);
+        assert!(first.expect("insert").is_some());
+
+        // Same id of another contact is another message.
+        let other = message("bob.onion", "id", true).insert_unique_with(&conn);
+        assert!(other.expect("insert").is_some());
+
+        // Same id of same contact is a duplicate delivery.
+        let duplicate = message("alice.onion", "id", true).insert_unique_with(&conn);
+        assert!(duplicate.expect("insert").is_none());
+        assert_eq!(count(&conn, "message"), 2);
+    }
+
+    #[tokio::test]
+    async fn duplicate_message_still_saves_session() {
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(test_db()));
+        let message = message("alice.onion", "id", true);
+        let mut session = SessionDb {
+            contact_onion_id: "alice.onion".into(),
+            state: "first".into(),
+            updated_at: 0,
+        };
+        let first = session.save(Some(&message), conn.clone()).await;
+        assert!(first.expect("save session").is_some());
+
+        session.state = "second".into();
+        let duplicate = session.save(Some(&message), conn.clone()).await;
+        assert!(duplicate.expect("save session").is_none());
+        let conn = conn.lock().await;
+        assert_eq!(count(&conn, "message"), 1);
+        let state: String = conn
+            .query_row("SELECT state FROM session", [], |row| row.get(0))
+            .expect("stored state");
+        assert_eq!(state, "second");
+    }
+
+    #[tokio::test]
+    async fn receipt_updates_outgoing_messages_of_its_contact_only() {
+        let conn = test_db();
+        message("alice.onion", "sent", false)
+            .insert_with(&conn)
+            .expect("insert message");
+        message("alice.onion", "received", true)
+            .insert_with(&conn)
+            .expect("insert message");
+        message("bob.onion", "other", false)
+            .insert_with(&conn)
+            .expect("insert message");
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+        let ids: Vec<String> = ["sent", "received", "other"].map(String::from).into();
+
+        let changed =
+            MessageDb::apply_receipt("alice.onion", ReceiptKind::Delivered, &ids, conn.clone())
+                .await
+                .expect("apply receipt");
+        assert_eq!(changed, ["sent"]);
+
+        // Repeated receipts change nothing.
+        let changed =
+            MessageDb::apply_receipt("alice.onion", ReceiptKind::Delivered, &ids, conn.clone())
+                .await
+                .expect("apply receipt");
+        assert!(changed.is_empty());
+
+        let changed =
+            MessageDb::apply_receipt("alice.onion", ReceiptKind::Read, &ids, conn.clone())
+                .await
+                .expect("apply receipt");
+        assert_eq!(changed, ["sent"]);
+
+        let conn = conn.lock().await;
+        let (sent_status, delivered, read): (bool, Option<i64>, Option<i64>) = conn
+            .query_row(
+                "SELECT sent_status, delivered_at, read_at FROM message WHERE message_id = 'sent'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .expect("sent message");
+        assert!(sent_status && delivered.is_some() && read.is_some());
+        let untouched: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM message WHERE message_id != 'sent' AND delivered_at IS NULL AND read_at IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .expect("untouched messages");
+        assert_eq!(untouched, 2);
+    }
+
+    #[tokio::test]
+    async fn read_receipt_implies_delivery() {
+        let conn = test_db();
+        message("alice.onion", "sent", false)
+            .insert_with(&conn)
+            .expect("insert message");
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+        let ids = ["sent".to_string()];
+
+        MessageDb::apply_receipt("alice.onion", ReceiptKind::Read, &ids, conn.clone())
+            .await
+            .expect("apply receipt");
+        let changed =
+            MessageDb::apply_receipt("alice.onion", ReceiptKind::Delivered, &ids, conn.clone())
+                .await
+                .expect("apply receipt");
+        assert!(changed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn marks_unread_incoming_messages_read_once() {
+        let conn = test_db();
+        message("alice.onion", "received", true)
+            .insert_with(&conn)
+            .expect("insert message");
+        message("alice.onion", "sent", false)
+            .insert_with(&conn)
+            .expect("insert message");
+        message("bob.onion", "other", true)
+            .insert_with(&conn)
+            .expect("insert message");
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+
+        let unread = MessageDb::mark_read("alice.onion", conn.clone())
+            .await
+            .expect("mark read");
+        assert_eq!(unread, ["received"]);
+        let unread = MessageDb::mark_read("alice.onion", conn.clone())
+            .await
+            .expect("mark read");
+        assert!(unread.is_empty());
+        let unread = MessageDb::mark_read("bob.onion", conn.clone())
+            .await
+            .expect("mark read");
+        assert_eq!(unread, ["other"]);
+    }
+
+    #[test]
+    fn outbox_entry_is_deleted_with_outgoing_message_only() {
+        let conn = test_db();
+        message("alice.onion", "id", false)
+            .insert_with(&conn)
+            .expect("insert outgoing");
+        message("bob.onion", "id", true)
+            .insert_with(&conn)
+            .expect("insert incoming");
+        OutboxDb::new("id", None).queue_with(&conn).expect("queue");
+
+        conn.execute(
+            "DELETE FROM message WHERE contact_onion_id = 'bob.onion'",
+            [],
+        )
+        .expect("delete incoming");
+        assert_eq!(count(&conn, "outbox"), 1);
+
+        conn.execute("DELETE FROM contact WHERE onion_id = 'alice.onion'", [])
+            .expect("delete contact");
+        assert_eq!(count(&conn, "outbox"), 0);
+    }
+
+    #[tokio::test]
+    async fn outbox_entry_can_be_postponed_retried_and_cancelled() {
+        let conn = test_db();
+        queue(&conn, "id", 0);
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+
+        let due = OutboxDb::due(conn.clone()).await.expect("due");
+        OutboxDb::postpone(&due["alice.onion"], i64::MAX, conn.clone())
+            .await
+            .expect("postpone");
+        assert!(OutboxDb::due(conn.clone()).await.expect("due").is_empty());
+
+        // Manual retry makes it due right away.
+        let mut entry = OutboxDb::new("id", None);
+        entry.next_attempt_at = 0;
+        entry.requeue(conn.clone()).await.expect("requeue");
+        assert_eq!(OutboxDb::due(conn.clone()).await.expect("due").len(), 1);
+
+        assert!(MessageDb::cancel("id", conn.clone()).await.expect("cancel"));
+        assert_eq!(count(&*conn.lock().await, "outbox"), 0);
+    }
+
+    #[tokio::test]
+    async fn sent_message_can_not_be_cancelled() {
+        let conn = test_db();
+        queue(&conn, "id", 0);
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+
+        OutboxDb::complete("id", conn.clone())
+            .await
+            .expect("complete");
+        let msg = MessageDb::retrieve_by_message_id("id", conn.clone())
+            .await
+            .expect("retrieve")
+            .expect("message exists");
+        assert!(msg.sent_status);
+        assert!(!MessageDb::cancel("id", conn.clone()).await.expect("cancel"));
+    }
+
+    #[tokio::test]
+    async fn outbox_entry_waits_for_receipt() {
+        let conn = test_db();
+        queue(&conn, "id", 0);
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+
+        let due = OutboxDb::due(conn.clone()).await.expect("due");
+        due["alice.onion"][0]
+            .await_receipt(i64::MAX, conn.clone())
+            .await
+            .expect("await receipt");
+
+        // Shown as sent, but kept until the receipt arrives.
+        let msg = MessageDb::retrieve_by_message_id("id", conn.clone())
+            .await
+            .expect("retrieve")
+            .expect("message exists");
+        assert!(msg.sent_status);
+        assert!(OutboxDb::due(conn.clone()).await.expect("due").is_empty());
+        assert_eq!(count(&*conn.lock().await, "outbox"), 1);
+
+        let changed = MessageDb::apply_receipt(
+            "alice.onion",
+            ReceiptKind::Delivered,
+            &["id".to_string()],
+            conn.clone(),
+        )
+        .await
+        .expect("apply receipt");
+        assert_eq!(changed, ["id"]);
+        assert_eq!(count(&*conn.lock().await, "outbox"), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_message_is_due_right_away() {
+        let conn = test_db();
+        message("alice.onion", "id", false)
+            .insert_with(&conn)
+            .expect("insert message");
+        OutboxDb::new("id", Some(60))
+            .queue_with(&conn)
+            .expect("queue message");
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+
+        let due = OutboxDb::due(conn.clone()).await.expect("due");
+        let entry = &due["alice.onion"][0];
+        assert_eq!(entry.attempts, 0);
+        assert!(entry.expires_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn due_outbox_entries_keep_their_order_per_peer() {
+        let conn = test_db();
+        queue(&conn, "first", i64::MAX);
+        queue(&conn, "second", 0);
+        queue(&conn, "third", i64::MAX);
+        message("bob.onion", "later", false)
+            .insert_with(&conn)
+            .expect("insert message");
+        let mut entry = OutboxDb::new("later", None);
+        entry.next_attempt_at = i64::MAX;
+        entry.queue_with(&conn).expect("queue message");
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(conn));
+
+        // One due entry makes all entries of the peer due, in the order they were written,
+        // so a later message is never delivered before an earlier one.
+        let due = OutboxDb::due(conn.clone()).await.expect("due");
+        let ids: Vec<_> = due["alice.onion"]
+            .iter()
+            .map(|entry| entry.message_id.as_str())
+            .collect();
+        assert_eq!(ids, ["first", "second", "third"]);
+        // Peers without due entries wait.
+        assert!(!due.contains_key("bob.onion"));
+    }
+
+    #[tokio::test]
+    async fn prekey_handshake_nonce_is_accepted_once() {
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(test_db()));
+        let expires_at = chrono::Utc::now().timestamp() + 60;
+
+        let nonce = [1_u8; 16];
+        assert!(
+            PrekeyDb::record_handshake_nonce(&nonce, expires_at, conn.clone())
+                .await
+                .expect("record nonce")
+        );
+        assert!(
+            !PrekeyDb::record_handshake_nonce(&nonce, expires_at, conn.clone())
+                .await
+                .expect("record replayed nonce")
+        );
+
+        // Expired nonces are forgotten, those handshakes are stale anyway.
+        PrekeyDb::record_handshake_nonce(&[2_u8; 16], 0, conn.clone())
+            .await
+            .expect("record expired nonce");
+        PrekeyDb::record_handshake_nonce(&[3_u8; 16], expires_at, conn.clone())
+            .await
+            .expect("record nonce");
+        assert_eq!(count(&*conn.lock().await, "prekey_handshake"), 2);
+    }
+
+    /// Store contact request of peer with key, received at timestamp.
+    async fn store_request(
+        conn: &DatabaseConnection,
+        onion_id: &str,
+        public_key: &str,
+        received_at: i64,
+        verified: bool,
+        max_pending: i64,
+    ) -> StoredRequest {
+        ContactRequestDb {
+            onion_id: onion_id.into(),
+            public_key: public_key.into(),
+            nickname: "Carol".into(),
+            note: String::new(),
+            received_at,
+            verified,
+        }
+        .store(max_pending, conn.clone())
+        .await
+        .expect("store request")
+    }
+
+    /// Public key of pending request of peer.
+    fn pending_key(conn: &Connection, onion_id: &str) -> String {
+        conn.query_row(
+            "SELECT public_key FROM contact_request WHERE onion_id = ?",
+            [onion_id],
+            |row| row.get(0),
+        )
+        .expect("pending request")
+    }
+
+    #[tokio::test]
+    async fn pending_contact_request_keeps_its_key() {
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(test_db()));
+
+        let first = store_request(&conn, "carol.onion", "key", 1, false, 10).await;
+        assert_eq!(first, StoredRequest::Stored);
+
+        // Same key refreshes the request.
+        let refreshed = store_request(&conn, "carol.onion", "key", 2, false, 10).await;
+        assert_eq!(refreshed, StoredRequest::Stored);
+
+        let other_key = store_request(&conn, "carol.onion", "other", 3, false, 10).await;
+        assert_eq!(other_key, StoredRequest::ConflictingKey);
+        assert_eq!(pending_key(&*conn.lock().await, "carol.onion"), "key");
+        assert_eq!(count(&*conn.lock().await, "contact_request"), 1);
+    }
+
+    #[tokio::test]
+    async fn oldest_contact_requests_are_evicted() {
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(test_db()));
+        for (i, onion_id) in ["a.onion", "b.onion", "c.onion"].iter().enumerate() {
+            store_request(&conn, onion_id, "key", i as i64, false, 2).await;
+        }
+
+        let conn = conn.lock().await;
+        assert_eq!(count(&conn, "contact_request"), 2);
+        let evicted: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM contact_request WHERE onion_id = 'a.onion'",
+                [],
+                |row| row.get(0),
+            )
+            .expect("count evicted");
+        assert_eq!(evicted, 0);
+    }
+
+    #[tokio::test]
+    async fn verified_contact_request_replaces_unverified_one() {
+        let conn: DatabaseConnection = std::sync::Arc::new(TokioMutex::new(test_db()));
+
+        store_request(&conn, "carol.onion", "forged", 1, false, 10).await;
+        let verified = store_request(&conn, "carol.onion", "key", 2, true, 10).await;
+        assert_eq!(verified, StoredRequest::Stored);
+        assert_eq!(pending_key(&*conn.lock().await, "carol.onion"), "key");
+
+        // Neither an unverified nor another verified request replaces it.
+        let forged = store_request(&conn, "carol.onion", "forged", 3, false, 10).await;
+        assert_eq!(forged, StoredRequest::ConflictingKey);
+        let other = store_request(&conn, "carol.onion", "other", 4, true, 10).await;
+        assert_eq!(other, StoredRequest::ConflictingKey);
+
+        // Refreshing with the same key keeps it verified.
+        store_request(&conn, "carol.onion", "key", 5, false, 10).await;
+        let still_verified: bool = conn
+            .lock()
+            .await
+            .query_row(
+                "SELECT verified FROM contact_request WHERE onion_id = 'carol.onion'",
+                [],
+                |row| row.get(0),
+            )
+            .expect("pending request");
+        assert!(still_verified);
+    }
+}
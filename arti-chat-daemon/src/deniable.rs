@@ -0,0 +1,114 @@
+//! Deniable authentication of handshakes (triple-DH).
+//! Instead of a signature, a handshake carries a MAC keyed by the DH of both
+//! identity keys, and the session key also depends on DHs with the identity keys.
+//! Both peers can compute the MAC, so it proves nothing to a third party.
+//! Session resets, key rotations and contact requests to deniable contacts
+//! carry the same MAC instead of a signature.
+//!
+//! The MAC key only depends on the two static identity keys, so the MAC allows
+//! key-compromise impersonation: whoever steals our identity key computes the
+//! same MAC key as any contact, and can send us resets and rotations in its name.
+//! A session key still needs the DH with the ephemeral key of the contact, so
+//! messages can't be forged that way. Signatures do not have this weakness.
+//!
+//! The X25519 identity keys are the birational maps of the ed25519 keys,
+//! so contacts need no additional key.
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+use crate::error::RatchetError;
+
+/// HKDF info label of MAC key.
+const MAC_KEY_LABEL: &[u8] = b"arti-chat/deniable/mac-key";
+
+/// HMAC over handshake transcripts.
+type HmacSha256 = Hmac<sha2::Sha256>;
+
+/// X25519 identity secret of our ed25519 key.
+pub fn identity_secret(private_key: &SigningKey) -> StaticSecret {
+    let mut scalar_bytes = private_key.to_scalar_bytes();
+    let secret = StaticSecret::from(scalar_bytes);
+    scalar_bytes.zeroize();
+    secret
+}
+
+/// X25519 identity public key of ed25519 key of peer.
+pub fn identity_public(public_key: &VerifyingKey) -> PublicKey {
+    PublicKey::from(public_key.to_montgomery().to_bytes())
+}
+
+/// MAC key shared by us and peer only.
+fn mac_key(self_identity: &StaticSecret, peer_public_key: &VerifyingKey) -> [u8; 32] {
+    let shared_secret = self_identity.diffie_hellman(&identity_public(peer_public_key));
+    let hk = hkdf::Hkdf::<sha2::Sha256>::new(None, shared_secret.as_bytes());
+    let mut key = [0_u8; 32];
+    hk.expand(MAC_KEY_LABEL, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+/// Authenticate transcript for peer.
+pub fn mac(
+    transcript: &[u8],
+    self_identity: &StaticSecret,
+    peer_public_key: &VerifyingKey,
+) -> String {
+    let mut key = mac_key(self_identity, peer_public_key);
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    key.zeroize();
+
+    mac.update(transcript);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Verify MAC of peer over transcript.
+pub fn verify_mac(
+    transcript: &[u8],
+    tag: &str,
+    self_identity: &StaticSecret,
+    peer_public_key: &VerifyingKey,
+) -> Result<(), RatchetError> {
+    let tag = hex::decode(tag)?;
+    let mut key = mac_key(self_identity, peer_public_key);
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    key.zeroize();
+
+    mac.update(transcript);
+    mac.verify_slice(&tag)
+        .map_err(|_| RatchetError::InvalidHandshakeMac)
+}
+
+/// DHs between identity and ephemeral keys, in the same order on both sides.
+/// The responder uses its reply ephemeral key or its signed prekey.
+pub fn identity_dh(
+    self_identity: &StaticSecret,
+    self_ephemeral: &StaticSecret,
+    peer_public_key: &VerifyingKey,
+    peer_ephemeral_pub_key: [u8; 32],
+    is_initiator: bool,
+) -> [u8; 64] {
+    let peer_identity = identity_public(peer_public_key);
+    let peer_ephemeral = PublicKey::from(peer_ephemeral_pub_key);
+
+    // DH(identity of initiator, ephemeral of responder) first,
+    // DH(ephemeral of initiator, identity of responder) second.
+    let (first, second) = if is_initiator {
+        (
+            self_identity.diffie_hellman(&peer_ephemeral),
+            self_ephemeral.diffie_hellman(&peer_identity),
+        )
+    } else {
+        (
+            self_ephemeral.diffie_hellman(&peer_identity),
+            self_identity.diffie_hellman(&peer_ephemeral),
+        )
+    };
+
+    let mut output = [0_u8; 64];
+    output[..32].copy_from_slice(first.as_bytes());
+    output[32..].copy_from_slice(second.as_bytes());
+    output
+}
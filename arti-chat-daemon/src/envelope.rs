@@ -0,0 +1,227 @@
+//! Versioned envelope around everything sent to a peer.
+//! Every frame carries the protocol version and the kind of its body, so a peer
+//! on an older release can ignore kinds it does not know instead of failing.
+//! Optional features are announced as capabilities in the handshake.
+//! Envelopes are encoded as CBOR and sent in length-prefixed frames, see [`crate::frame`].
+
+use crate::{contact_request, error::ClientError, ratchet, reset, rotation};
+
+/// Version of the wire protocol.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Oldest protocol version we still understand.
+/// Version 1 sent null-terminated JSON instead of binary frames.
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+/// Optional features a peer can support.
+/// Exchanged by name, so unknown capabilities of newer peers are ignored.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Receiving image attachments.
+    Attachments,
+    /// Receiving delivery and read receipts.
+    Receipts,
+    /// Receiving typing indicators.
+    Typing,
+    /// Padding plaintext before encryption, see [`crate::padding`].
+    Padding,
+    /// Deniable handshakes and control messages, see [`crate::deniable`].
+    Deniable,
+}
+
+impl Capability {
+    /// Name of capability on the wire.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Attachments => "attachments",
+            Self::Receipts => "receipts",
+            Self::Typing => "typing",
+            Self::Padding => "padding",
+            Self::Deniable => "deniable",
+        }
+    }
+}
+
+/// Capabilities of this release.
+pub const SUPPORTED_CAPABILITIES: &[Capability] = &[
+    Capability::Attachments,
+    Capability::Receipts,
+    Capability::Typing,
+    Capability::Padding,
+    Capability::Deniable,
+];
+
+/// Names of capabilities of this release, announced in our handshakes.
+pub fn supported_capabilities() -> Vec<String> {
+    SUPPORTED_CAPABILITIES
+        .iter()
+        .map(|capability| capability.name().to_string())
+        .collect()
+}
+
+/// Envelope as sent on the wire.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Envelope {
+    /// Protocol version of sender.
+    version: u8,
+    /// Kind of body.
+    kind: String,
+    /// Body, decoded according to kind.
+    body: ciborium::Value,
+}
+
+/// Message exchanged with a peer.
+#[non_exhaustive]
+pub enum WireMessage {
+    /// Handshake to establish or accept a session.
+    Handshake(Box<ratchet::Handshake>),
+    /// Encrypted chat message.
+    Message(Box<ratchet::EncryptedMessage>),
+    /// Encrypted control message, see [`crate::control`].
+    Control(Box<ratchet::EncryptedMessage>),
+    /// Request to reset the session.
+    SessionReset(reset::SessionReset),
+    /// Notice of replaced identity key.
+    KeyRotation(rotation::KeyRotation),
+    /// Introduction of a peer which is not a contact yet.
+    ContactRequest(Box<contact_request::ContactRequest>),
+    /// Question whether the sender of a contact request owns its onion id.
+    KeyProbe(Box<contact_request::KeyProbe>),
+    /// Answer to a key probe.
+    KeyProof(contact_request::KeyProof),
+}
+
+impl WireMessage {
+    /// Kind of message on the wire.
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Handshake(_) => "handshake",
+            Self::Message(_) => "message",
+            Self::Control(_) => "control",
+            Self::SessionReset(_) => "session_reset",
+            Self::KeyRotation(_) => "key_rotation",
+            Self::ContactRequest(_) => "contact_request",
+            Self::KeyProbe(_) => "key_probe",
+            Self::KeyProof(_) => "key_proof",
+        }
+    }
+
+    /// Encode message in envelope.
+    pub fn encode(&self) -> Result<Vec<u8>, ClientError> {
+        let body = match self {
+            Self::Handshake(handshake) => ciborium::Value::serialized(handshake)?,
+            Self::Message(encrypted) | Self::Control(encrypted) => {
+                ciborium::Value::serialized(encrypted)?
+            }
+            Self::SessionReset(session_reset) => ciborium::Value::serialized(session_reset)?,
+            Self::KeyRotation(key_rotation) => ciborium::Value::serialized(key_rotation)?,
+            Self::ContactRequest(request) => ciborium::Value::serialized(request)?,
+            Self::KeyProbe(probe) => ciborium::Value::serialized(probe)?,
+            Self::KeyProof(proof) => ciborium::Value::serialized(proof)?,
+        };
+
+        let mut encoded = Vec::new();
+        ciborium::into_writer(
+            &Envelope {
+                version: PROTOCOL_VERSION,
+                kind: self.kind().into(),
+                body,
+            },
+            &mut encoded,
+        )?;
+        Ok(encoded)
+    }
+
+    /// Decode message from envelope.
+    /// Returns `None` for kinds of a newer release, which are ignored.
+    pub fn decode(raw: &[u8]) -> Result<Option<Self>, ClientError> {
+        let envelope: Envelope = ciborium::from_reader(raw)?;
+        if envelope.version < MIN_PROTOCOL_VERSION {
+            return Err(ClientError::UnsupportedProtocolVersion(envelope.version));
+        }
+
+        let message = match envelope.kind.as_str() {
+            "handshake" => Self::Handshake(envelope.body.deserialized()?),
+            "message" => Self::Message(envelope.body.deserialized()?),
+            "control" => Self::Control(envelope.body.deserialized()?),
+            "session_reset" => Self::SessionReset(envelope.body.deserialized()?),
+            "key_rotation" => Self::KeyRotation(envelope.body.deserialized()?),
+            "contact_request" => Self::ContactRequest(envelope.body.deserialized()?),
+            "key_probe" => Self::KeyProbe(envelope.body.deserialized()?),
+            "key_proof" => Self::KeyProof(envelope.body.deserialized()?),
+            kind => {
+                tracing::debug!(
+                    "Ignoring unknown message kind {} of protocol version {}.",
+                    kind,
+                    envelope.version
+                );
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    /// Envelope with raw body, as a peer of another release could send it.
+    fn raw_envelope(version: u8, kind: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        ciborium::into_writer(
+            &Envelope {
+                version,
+                kind: kind.into(),
+                body: ciborium::Value::Map(Vec::new()),
+            },
+            &mut encoded,
+        )
+        .expect("encode envelope");
+        encoded
+    }
+
+    #[test]
+    fn decodes_encoded_message() {
+        let private_key = SigningKey::generate(&mut rand_core::OsRng);
+        let (handshake, _) = ratchet::Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &private_key.verifying_key(),
+            &private_key,
+            ratchet::HandshakeOptions::default(),
+        );
+        let encoded = WireMessage::Handshake(Box::new(handshake))
+            .encode()
+            .expect("encode");
+
+        let decoded = WireMessage::decode(&encoded).expect("decode");
+        let Some(WireMessage::Handshake(handshake)) = decoded else {
+            panic!("decoded another kind");
+        };
+        assert_eq!(handshake.capabilities, supported_capabilities());
+    }
+
+    #[test]
+    fn ignores_unknown_kind_of_newer_release() {
+        let decoded = WireMessage::decode(&raw_envelope(PROTOCOL_VERSION + 1, "future"));
+        assert!(decoded.expect("decode").is_none());
+    }
+
+    #[test]
+    fn rejects_outdated_protocol_version() {
+        let decoded = WireMessage::decode(&raw_envelope(MIN_PROTOCOL_VERSION - 1, "message"));
+        assert!(matches!(
+            decoded,
+            Err(ClientError::UnsupportedProtocolVersion(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_known_kind_with_malformed_body() {
+        assert!(WireMessage::decode(&raw_envelope(PROTOCOL_VERSION, "message")).is_err());
+    }
+}
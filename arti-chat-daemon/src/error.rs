@@ -50,6 +50,10 @@ pub enum ClientError {
     #[error("Empty Hsid.")]
     EmptyHsid,
 
+    /// Onion address or user key does not match the identity key in identity mode.
+    #[error("Onion address does not match identity key.")]
+    IdentityKeyMismatch,
+
     /// Database Error.
     #[error("Database error: {0}")]
     DatabaseError(#[from] DatabaseError),
@@ -62,10 +66,38 @@ pub enum ClientError {
     #[error("Key length is not 32 bytes.")]
     InvalidKeyLength,
 
+    /// Message id of peer is not 128-bit hex.
+    #[error("Invalid message id.")]
+    InvalidMessageId,
+
     /// Ed25519 error.
     #[error("ed25519 error: {0}")]
     Ed25519Error(#[from] ed25519_dalek::ed25519::Error),
 
+    /// Public key does not belong to private key.
+    #[error("Public key does not match private key.")]
+    KeyPairMismatch,
+
+    /// Key cannot be replaced since it is bound to the onion address.
+    #[error("Key is bound to onion address in identity mode.")]
+    KeyBoundToOnionAddress,
+
+    /// Peer uses a protocol version we no longer understand.
+    #[error("Unsupported protocol version {0}.")]
+    UnsupportedProtocolVersion(u8),
+
+    /// Peer sent a message of another kind than expected.
+    #[error("Unexpected message kind.")]
+    UnexpectedMessageKind,
+
+    /// Peer did not reply in time.
+    #[error("Peer did not reply in time.")]
+    PeerTimeout,
+
+    /// Blocking task failed to complete.
+    #[error("Blocking task failed: {0}")]
+    TaskError(#[from] tokio::task::JoinError),
+
     /// Internal Arti bug.
     #[error("Internal Arti bug")]
     ArtiBug,
@@ -82,6 +114,18 @@ pub enum ClientError {
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
 
+    /// CBOR encode error.
+    #[error("CBOR encode error: {0}")]
+    CborEncodeError(#[from] ciborium::ser::Error<std::io::Error>),
+
+    /// CBOR decode error.
+    #[error("CBOR decode error: {0}")]
+    CborDecodeError(#[from] ciborium::de::Error<std::io::Error>),
+
+    /// CBOR value error.
+    #[error("CBOR value error: {0}")]
+    CborValueError(#[from] ciborium::value::Error),
+
     /// Error related to message.
     #[error("Message error: {0}")]
     MessageError(#[from] MessageError),
@@ -203,6 +247,86 @@ pub enum RatchetError {
     #[error("Failed to decrypt message.")]
     MessageDecryptError,
 
+    /// Handshake uses an unsupported version of the key derivation scheme.
+    #[error("Unsupported ratchet version: {0}.")]
+    UnsupportedVersion(u8),
+
+    /// Decrypted payload uses a newer layout than we support.
+    #[error("Unsupported payload version: {0}.")]
+    UnsupportedPayloadVersion(u8),
+
+    /// Decrypted payload names another sender than the message header.
+    #[error("Sender of payload does not match message header.")]
+    SenderMismatch,
+
+    /// Handshake timestamp is outside the allowed window.
+    #[error("Stale handshake.")]
+    StaleHandshake,
+
+    /// Handshake with this nonce was already received.
+    #[error("Replayed handshake.")]
+    ReplayedHandshake,
+
+    /// Message with this counter was already received.
+    #[error("Replayed message.")]
+    ReplayedMessage,
+
+    /// Key rotation notice does not match the current key of contact.
+    #[error("Invalid key rotation.")]
+    InvalidKeyRotation,
+
+    /// Contact request is malformed or lacks a valid stamp.
+    #[error("Invalid contact request.")]
+    InvalidContactRequest,
+
+    /// Contact request has another key than the pending request of the same peer.
+    #[error("Contact request conflicts with pending request of peer.")]
+    ConflictingContactRequest,
+
+    /// Peer initiated at the same time as us and our initiation wins.
+    #[error("Simultaneous handshake of peer rejected.")]
+    HandshakeCollision,
+
+    /// MAC of deniable handshake is invalid.
+    #[error("Invalid handshake MAC.")]
+    InvalidHandshakeMac,
+
+    /// Peer replied with a signed handshake to our deniable one.
+    #[error("Peer refused deniable handshake.")]
+    DeniabilityMismatch,
+
+    /// Peer did not use the hybrid handshake we require.
+    #[error("Peer refused post-quantum handshake.")]
+    PostQuantumRequired,
+
+    /// ML-KEM key or ciphertext of peer is invalid.
+    #[error("Invalid ML-KEM encoding.")]
+    InvalidKemEncoding,
+
+    /// Decrypted plaintext is not padded correctly.
+    #[error("Invalid padding.")]
+    InvalidPadding,
+
+    /// Onion address could not be parsed.
+    #[error("Invalid onion address.")]
+    InvalidOnionAddress,
+
+    /// No session exists with peer.
+    #[error("No session with peer.")]
+    NoSession,
+
+    /// Prekey bundle is malformed or does not belong to contact.
+    #[error("Invalid prekey bundle.")]
+    InvalidPrekeyBundle,
+
+    /// Message skips more messages than allowed.
+    #[error("Too many skipped messages.")]
+    TooManySkippedMessages,
+
+    /// Message was encrypted with a session of another version.
+    #[error("Ratchet version of message does not match session.")]
+    VersionMismatch,
+
     /// Invalid key length.
     #[error("Key length is not 32 bytes.")]
     InvalidKeyLength,
@@ -243,4 +367,8 @@ pub enum AttachmentError {
     /// Attachments disabled in settings.
     #[error("Sending and receiving attachments is disabled in settings.")]
     DisabledInSettings,
+
+    /// Release of contact does not support attachments.
+    #[error("Contact does not support attachments.")]
+    UnsupportedByContact,
 }
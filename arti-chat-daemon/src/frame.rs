@@ -0,0 +1,198 @@
+//! Length-prefixed framing of envelopes on Tor streams.
+//! Every frame starts with the length of the envelope as a 32 bit big-endian
+//! integer, followed by the CBOR encoded envelope. The length is checked
+//! before the frame is buffered, so a peer can't exhaust our memory.
+
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Decoder, Encoder, Framed, LengthDelimitedCodec},
+};
+
+use crate::{envelope::WireMessage, error::ClientError};
+
+/// Max size of a single frame.
+/// Large enough for an attachment at the max file size, after padding.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Stream of frames exchanged with a peer.
+pub type WireStream<S> = Framed<S, WireCodec>;
+
+/// Codec encoding envelopes in length-prefixed frames.
+#[non_exhaustive]
+pub struct WireCodec {
+    /// Length prefix of frames.
+    frames: LengthDelimitedCodec,
+}
+
+impl Default for WireCodec {
+    fn default() -> Self {
+        Self {
+            frames: LengthDelimitedCodec::builder()
+                .length_field_type::<u32>()
+                .max_frame_length(MAX_FRAME_SIZE)
+                .new_codec(),
+        }
+    }
+}
+
+impl Decoder for WireCodec {
+    type Item = WireMessage;
+    type Error = ClientError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Frames of kinds of a newer release or which fail to decode are skipped,
+        // since an error ends the stream. Only broken framing ends it.
+        while let Some(frame) = self.frames.decode(src)? {
+            match WireMessage::decode(&frame) {
+                Ok(Some(message)) => return Ok(Some(message)),
+                Ok(None) => {}
+                Err(e) => tracing::debug!("Skipping undecodable frame: {}", e),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Encoder<WireMessage> for WireCodec {
+    type Error = ClientError;
+
+    fn encode(&mut self, item: WireMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        Ok(self.frames.encode(item.encode()?.into(), dst)?)
+    }
+}
+
+/// Envelope encoded ahead, so a failed write can be repeated on another stream.
+impl Encoder<Bytes> for WireCodec {
+    type Error = ClientError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        Ok(self.frames.encode(item, dst)?)
+    }
+}
+
+/// Exchange frames over stream.
+pub fn framed<S: tokio::io::AsyncRead + tokio::io::AsyncWrite>(stream: S) -> WireStream<S> {
+    Framed::new(stream, WireCodec::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reset::SessionReset;
+
+    /// Small message to frame.
+    fn reset(since: i64) -> WireMessage {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        WireMessage::SessionReset(SessionReset::new(
+            "alice.onion",
+            "bob.onion",
+            since,
+            &key,
+            &key.verifying_key(),
+            false,
+        ))
+    }
+
+    /// Timestamp of decoded reset.
+    fn since(message: Option<WireMessage>) -> i64 {
+        match message {
+            Some(WireMessage::SessionReset(reset)) => reset.since,
+            _ => panic!("decoded no reset"),
+        }
+    }
+
+    #[test]
+    fn decodes_frames_and_skips_unknown_kinds() {
+        let mut codec = WireCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(reset(1), &mut buf).expect("encode");
+
+        let mut unknown = Vec::new();
+        ciborium::into_writer(
+            &serde_json::json!({"version": 99, "kind": "future", "body": 1}),
+            &mut unknown,
+        )
+        .expect("encode envelope");
+        codec
+            .frames
+            .encode(unknown.into(), &mut buf)
+            .expect("encode unknown");
+        codec.encode(reset(2), &mut buf).expect("encode");
+
+        assert_eq!(since(codec.decode(&mut buf).expect("decode")), 1);
+        assert_eq!(since(codec.decode(&mut buf).expect("decode")), 2);
+        assert!(codec.decode(&mut buf).expect("decode").is_none());
+    }
+
+    #[tokio::test]
+    async fn undecodable_frame_does_not_end_stream() {
+        use futures::{SinkExt, StreamExt};
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (mut ours, mut theirs) = (framed(ours), framed(theirs));
+
+        ours.send(reset(1)).await.expect("send");
+        ours.send(Bytes::from_static(b"not cbor"))
+            .await
+            .expect("send");
+        ours.send(reset(2)).await.expect("send");
+        drop(ours);
+
+        let mut received = Vec::new();
+        while let Some(message) = theirs.next().await {
+            received.push(since(Some(message.expect("decode"))));
+        }
+        assert_eq!(received, [1, 2]);
+    }
+
+    #[test]
+    fn waits_for_complete_frame() {
+        let mut codec = WireCodec::default();
+        let mut encoded = BytesMut::new();
+        codec.encode(reset(1), &mut encoded).expect("encode");
+
+        let mut buf = BytesMut::new();
+        let (head, tail) = encoded.split_at(encoded.len() - 1);
+        buf.extend_from_slice(head);
+        assert!(codec.decode(&mut buf).expect("decode").is_none());
+        buf.extend_from_slice(tail);
+        assert_eq!(since(codec.decode(&mut buf).expect("decode")), 1);
+    }
+
+    #[test]
+    fn rejects_oversized_frame_by_its_length() {
+        let mut codec = WireCodec::default();
+        let length = u32::try_from(MAX_FRAME_SIZE + 1).expect("length fits u32");
+        let mut buf = BytesMut::from(&length.to_be_bytes()[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn rejects_null_terminated_json() {
+        let mut codec = WireCodec::default();
+        let mut buf = BytesMut::from(&b"{\"kind\":\"message\"}\0"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn many_frames_share_one_stream() {
+        use futures::{SinkExt, StreamExt};
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (mut ours, mut theirs) = (framed(ours), framed(theirs));
+
+        // Frames encoded ahead and encoded on write are mixed freely.
+        ours.send(reset(1)).await.expect("send");
+        let encoded = Bytes::from(reset(2).encode().expect("encode"));
+        ours.send(encoded).await.expect("send");
+        ours.send(reset(3)).await.expect("send");
+        drop(ours);
+
+        let mut received = Vec::new();
+        while let Some(message) = theirs.next().await {
+            received.push(since(Some(message.expect("decode"))));
+        }
+        assert_eq!(received, [1, 2, 3]);
+    }
+}
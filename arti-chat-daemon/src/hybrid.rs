@@ -0,0 +1,58 @@
+//! ML-KEM-768 encapsulation for the hybrid post-quantum handshake.
+//! The KEM secret is combined with the X25519 secret, so a session stays
+//! confidential as long as either of them is not broken.
+
+use ml_kem::{
+    EncodedSizeUser, KemCore, MlKem768,
+    kem::{Decapsulate, Encapsulate},
+};
+use zeroize::Zeroize;
+
+use crate::error::RatchetError;
+
+/// Decapsulation key kept by initiator until the reply arrives.
+pub type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+/// Encapsulation key of initiator.
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// Generate keypair and return the encoded encapsulation key to send to peer.
+pub fn generate() -> (DecapsulationKey, Vec<u8>) {
+    let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut rand_core::OsRng);
+    (decapsulation_key, encapsulation_key.as_bytes().to_vec())
+}
+
+/// Encapsulate a shared secret to the encoded key of peer.
+/// Returns (ciphertext, shared secret).
+pub fn encapsulate(encapsulation_key: &[u8]) -> Result<(Vec<u8>, [u8; 32]), RatchetError> {
+    let encoded = encapsulation_key
+        .try_into()
+        .map_err(|_| RatchetError::InvalidKemEncoding)?;
+    let encapsulation_key = EncapsulationKey::from_bytes(&encoded);
+
+    let (ciphertext, mut shared_key) = encapsulation_key
+        .encapsulate(&mut rand_core::OsRng)
+        .map_err(|_| RatchetError::InvalidKemEncoding)?;
+    let shared_secret: [u8; 32] = shared_key.into();
+    shared_key.zeroize();
+
+    Ok((ciphertext.to_vec(), shared_secret))
+}
+
+/// Decapsulate shared secret from ciphertext of peer.
+pub fn decapsulate(
+    decapsulation_key: &DecapsulationKey,
+    ciphertext: &[u8],
+) -> Result<[u8; 32], RatchetError> {
+    let ciphertext = ciphertext
+        .try_into()
+        .map_err(|_| RatchetError::InvalidKemEncoding)?;
+
+    let mut shared_key = decapsulation_key
+        .decapsulate(&ciphertext)
+        .map_err(|_| RatchetError::InvalidKemEncoding)?;
+    let shared_secret: [u8; 32] = shared_key.into();
+    shared_key.zeroize();
+
+    Ok(shared_secret)
+}
@@ -0,0 +1,73 @@
+//! Identity mode binds the chat key to the onion address.
+//! The onion service is launched with an identity key derived from the chat key,
+//! so contacts get our public key from the address and a mismatched key is impossible.
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::error::RatchetError;
+
+/// Nickname of onion service in identity mode.
+/// Differs from the regular service since its key is provided by us.
+pub const IDENTITY_SERVICE_NICKNAME: &str = "arti-chat-identity";
+
+/// Get the ed25519 public key encoded in a v3 onion address.
+pub fn public_key_from_onion_id(onion_id: &str) -> Result<VerifyingKey, RatchetError> {
+    let hs_id: tor_hscrypto::pk::HsId = onion_id
+        .trim()
+        .parse()
+        .map_err(|_| RatchetError::InvalidOnionAddress)?;
+    let public_key: &[u8; 32] = hs_id.as_ref();
+
+    Ok(VerifyingKey::from_bytes(public_key)?)
+}
+
+/// Check if public key is the key encoded in onion address.
+pub fn is_bound_to_onion_id(onion_id: &str, public_key: &VerifyingKey) -> bool {
+    public_key_from_onion_id(onion_id).is_ok_and(|key| key == *public_key)
+}
+
+/// Onion service identity keypair derived from chat key.
+pub fn hs_id_keypair(private_key: &SigningKey) -> tor_hscrypto::pk::HsIdKeypair {
+    let keypair = tor_llcrypto::pk::ed25519::Keypair::from_bytes(private_key.as_bytes());
+    tor_llcrypto::pk::ed25519::ExpandedKeypair::from(&keypair).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safelog::DisplayRedacted;
+
+    /// Onion address of the identity mode service of chat key.
+    fn onion_id(private_key: &SigningKey) -> String {
+        let keypair = hs_id_keypair(private_key);
+        let hs_id = tor_hscrypto::pk::HsId::from(tor_hscrypto::pk::HsIdKey::from(&keypair));
+        hs_id.display_unredacted().to_string()
+    }
+
+    #[test]
+    fn onion_id_of_identity_service_carries_chat_key() {
+        let private_key = SigningKey::generate(&mut rand_core::OsRng);
+        let onion_id = onion_id(&private_key);
+
+        let public_key = public_key_from_onion_id(&onion_id).expect("key of onion id");
+        assert_eq!(public_key, private_key.verifying_key());
+        assert!(is_bound_to_onion_id(
+            &onion_id,
+            &private_key.verifying_key()
+        ));
+    }
+
+    #[test]
+    fn other_key_is_not_bound_to_onion_id() {
+        let private_key = SigningKey::generate(&mut rand_core::OsRng);
+        let other_key = SigningKey::generate(&mut rand_core::OsRng);
+        assert!(!is_bound_to_onion_id(
+            &onion_id(&private_key),
+            &other_key.verifying_key()
+        ));
+        assert!(matches!(
+            public_key_from_onion_id("not-an-onion-address"),
+            Err(RatchetError::InvalidOnionAddress)
+        ));
+    }
+}
@@ -63,6 +63,39 @@ pub enum MessageToUI {
     Rpc(String),
 }
 
+/// Broadcast to UI when the status of messages we sent changed.
+#[non_exhaustive]
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename = "message_status")]
+pub struct MessageStatusChanged {
+    /// Contact the messages were sent to.
+    pub onion_id: String,
+    /// Ids of changed messages.
+    pub message_ids: Vec<String>,
+    /// New status of messages.
+    pub status: crate::message::MessageStatus,
+}
+
+/// Broadcast to UI when a contact started or stopped typing.
+#[non_exhaustive]
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename = "typing")]
+pub struct TypingChanged {
+    /// Contact who is typing.
+    pub onion_id: String,
+    /// Contact is typing.
+    pub typing: bool,
+}
+
+/// Broadcast to UI when a peer asked to become a contact.
+#[non_exhaustive]
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename = "contact_request")]
+pub struct ContactRequestReceived {
+    /// Peer asking to become a contact.
+    pub onion_id: String,
+}
+
 /// Run our IPC server.
 pub async fn run_ipc_server(
     mut message_rx: tokio::sync::mpsc::UnboundedReceiver<String>, // Receives incoming chat messages
@@ -91,12 +124,17 @@ pub async fn run_ipc_server(
         UnboundedSender<MessageToUI>,
     >::new()));
 
-    // Spawn task to retry failed messages.
+    // Spawn tasks to deliver queued messages, key rotations and receipts.
     let bw_clone = broadcast_writers.clone();
     let client_clone = client.clone();
     tokio::spawn(async move {
-        let _ = client_clone.retry_failed_messages(bw_clone).await;
+        client_clone.process_outbox(bw_clone).await;
+    });
+    let client_clone = client.clone();
+    tokio::spawn(async move {
+        client_clone.process_key_rotations().await;
     });
+    tokio::spawn(client.clone().process_receipts());
 
     loop {
         tokio::select! {
@@ -46,12 +46,30 @@ use tokio::sync::Mutex as TokioMutex;
 
 pub mod attachment;
 pub mod client;
+pub mod collision;
+pub mod connection;
+pub mod contact_request;
+pub mod control;
 pub mod db;
+pub mod deniable;
+pub mod envelope;
 pub mod error;
+pub mod frame;
+pub mod hybrid;
+pub mod identity;
 pub mod ipc;
 pub mod message;
+pub mod outbox;
+pub mod padding;
+pub mod peer_lock;
+pub mod prekey;
 pub mod ratchet;
+pub mod receipt;
+pub mod replay;
+pub mod reset;
+pub mod rotation;
 pub mod rpc;
+pub mod safety;
 pub mod ui_focus;
 
 /// Project directory storing sqlite db + config.
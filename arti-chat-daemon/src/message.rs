@@ -1,5 +1,16 @@
 //! Logic for different types of messages (text, image,...)
 
+/// Generate random 128-bit message id.
+/// Sender and receiver store a message under the same id, so retries are deduplicated.
+pub fn new_message_id() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Check if message id received from peer is 128-bit hex.
+pub fn is_valid_message_id(message_id: &str) -> bool {
+    message_id.len() == 32 && message_id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
 /// Content type of message.
 #[non_exhaustive]
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
@@ -13,6 +24,7 @@ pub enum MessageContent {
     /// Image.
     Image {
         /// Image bytes.
+        #[serde(with = "serde_bytes")]
         data: Vec<u8>,
     },
     /// Display error in chat.
@@ -20,4 +32,70 @@ pub enum MessageContent {
         /// Error message.
         message: String,
     },
+    /// Receipt for messages we sent to peer, never stored or shown.
+    Receipt {
+        /// Kind of receipt.
+        kind: ReceiptKind,
+        /// Ids of acknowledged messages.
+        message_ids: Vec<String>,
+    },
+}
+
+/// Kind of receipt.
+#[non_exhaustive]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptKind {
+    /// Peer decrypted and stored the messages.
+    Delivered,
+    /// Peer viewed the messages.
+    Read,
+}
+
+/// Delivery status of a message we sent, as shown in the UI.
+#[non_exhaustive]
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    /// Message waits in the outbox for its next attempt.
+    Queued,
+    /// Attempt to deliver the message is running.
+    Sending,
+    /// Message left the outbox.
+    Sent,
+    /// Delivery gave up, until the user retries.
+    Failed,
+    /// Peer stored the message.
+    Delivered,
+    /// Peer viewed the message.
+    Read,
+}
+
+impl From<ReceiptKind> for MessageStatus {
+    fn from(kind: ReceiptKind) -> Self {
+        match kind {
+            ReceiptKind::Delivered => Self::Delivered,
+            ReceiptKind::Read => Self::Read,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_message_ids_are_valid_and_random() {
+        let (first, second) = (new_message_id(), new_message_id());
+        assert!(is_valid_message_id(&first));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn rejects_malformed_message_ids() {
+        assert!(!is_valid_message_id(""));
+        assert!(!is_valid_message_id(&"0".repeat(31)));
+        assert!(!is_valid_message_id(&"g".repeat(32)));
+        assert!(!is_valid_message_id("' OR 1=1 --"));
+    }
 }
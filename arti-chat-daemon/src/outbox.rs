@@ -0,0 +1,52 @@
+//! Outbox of messages waiting for delivery.
+//! A failed delivery is retried with exponential backoff until the message is
+//! delivered, cancelled by the user or expired. Expired messages stay in the
+//! outbox as failed until the user retries or cancels them.
+//! A written message stays in the outbox until the delivery receipt of the
+//! peer arrives, since a stream closed by the peer can still accept writes.
+
+/// Seconds between two runs of the outbox task.
+pub const OUTBOX_INTERVAL: u64 = 5;
+
+/// Delay in seconds after the first failed attempt, doubled after every next one.
+pub const BASE_BACKOFF: i64 = 30;
+
+/// Max delay in seconds between two attempts.
+pub const MAX_BACKOFF: i64 = 60 * 60;
+
+/// Seconds to wait for the delivery receipt of a written message before sending it again.
+pub const RECEIPT_TIMEOUT: i64 = 2 * 60;
+
+/// Seconds to wait before the next attempt, after `attempts` failed attempts.
+pub fn backoff(attempts: i64) -> i64 {
+    let doublings = attempts.saturating_sub(1).clamp(0, 16);
+    (BASE_BACKOFF << doublings).min(MAX_BACKOFF)
+}
+
+/// Timestamp of the next attempt, never after the expiry of the message.
+pub fn next_attempt_at(now: i64, attempts: i64, expires_at: Option<i64>) -> i64 {
+    let next = now + backoff(attempts);
+    expires_at.map_or(next, |expires_at| next.min(expires_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        assert_eq!(backoff(0), BASE_BACKOFF);
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(2), 2 * BASE_BACKOFF);
+        assert_eq!(backoff(3), 4 * BASE_BACKOFF);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(i64::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn next_attempt_is_never_after_expiry() {
+        assert_eq!(next_attempt_at(100, 1, None), 100 + BASE_BACKOFF);
+        assert_eq!(next_attempt_at(100, 1, Some(110)), 110);
+        assert_eq!(next_attempt_at(100, 1, Some(1000)), 100 + BASE_BACKOFF);
+    }
+}
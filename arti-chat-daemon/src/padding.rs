@@ -0,0 +1,154 @@
+//! Length-hiding padding of plaintext before encryption.
+//! Plaintext is padded to a bucket size, so the ciphertext length only reveals
+//! the bucket and not the exact size of a message.
+//! Padding is a 0x80 marker followed by zeros (ISO/IEC 7816-4), so it can be
+//! removed without knowing the policy of the sender.
+
+use crate::error::RatchetError;
+
+/// Marker byte between plaintext and zero padding.
+const PADDING_MARKER: u8 = 0x80;
+
+/// Smallest bucket of the bucket policy.
+const MIN_BUCKET: usize = 256;
+
+/// Smallest bucket of the strict policy.
+const MIN_STRICT_BUCKET: usize = 4 * 1024;
+
+/// Above this size buckets grow linearly instead of doubling,
+/// to limit the overhead on attachments.
+const MAX_EXPONENTIAL_BUCKET: usize = 64 * 1024;
+
+/// How plaintext is padded before encryption.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// No padding besides the marker, length of message is visible.
+    Off,
+    /// Pad to power of two buckets starting at 256 bytes.
+    #[default]
+    Buckets,
+    /// Pad to power of two buckets starting at 4 KiB, hiding the size of
+    /// all short messages at the cost of bandwidth.
+    Strict,
+}
+
+impl PaddingPolicy {
+    /// Size of bucket for plaintext of given length, marker included.
+    fn bucket_size(self, len: usize) -> usize {
+        let min_bucket = match self {
+            Self::Off => return len,
+            Self::Buckets => MIN_BUCKET,
+            Self::Strict => MIN_STRICT_BUCKET,
+        };
+
+        if len <= MAX_EXPONENTIAL_BUCKET {
+            len.max(min_bucket).next_power_of_two()
+        } else {
+            len.div_ceil(MAX_EXPONENTIAL_BUCKET) * MAX_EXPONENTIAL_BUCKET
+        }
+    }
+}
+
+impl std::fmt::Display for PaddingPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Off => "off",
+            Self::Buckets => "buckets",
+            Self::Strict => "strict",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::str::FromStr for PaddingPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "buckets" => Ok(Self::Buckets),
+            "strict" => Ok(Self::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Pad plaintext to bucket of policy.
+pub fn pad(plaintext: &[u8], policy: PaddingPolicy) -> Vec<u8> {
+    let size = policy.bucket_size(plaintext.len() + 1);
+    let mut padded = Vec::with_capacity(size);
+    padded.extend_from_slice(plaintext);
+    padded.push(PADDING_MARKER);
+    padded.resize(size, 0);
+    padded
+}
+
+/// Remove padding from decrypted plaintext.
+pub fn unpad(mut padded: Vec<u8>) -> Result<Vec<u8>, RatchetError> {
+    let marker = padded
+        .iter()
+        .rposition(|b| *b != 0)
+        .ok_or(RatchetError::InvalidPadding)?;
+    if padded[marker] != PADDING_MARKER {
+        return Err(RatchetError::InvalidPadding);
+    }
+
+    padded.truncate(marker);
+    Ok(padded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpad_restores_plaintext_of_every_policy() {
+        for policy in [
+            PaddingPolicy::Off,
+            PaddingPolicy::Buckets,
+            PaddingPolicy::Strict,
+        ] {
+            for len in [0, 1, 255, 256, 5000, 70_000] {
+                // Trailing zeros and markers of the plaintext itself are kept.
+                let mut plaintext = vec![PADDING_MARKER; len];
+                if let Some(last) = plaintext.last_mut() {
+                    *last = 0;
+                }
+                let padded = pad(&plaintext, policy);
+                assert_eq!(unpad(padded).expect("unpad"), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn pads_to_bucket_size() {
+        assert_eq!(pad(b"hi", PaddingPolicy::Off).len(), 3);
+        assert_eq!(pad(b"hi", PaddingPolicy::Buckets).len(), MIN_BUCKET);
+        assert_eq!(pad(&[1; 300], PaddingPolicy::Buckets).len(), 512);
+        assert_eq!(pad(b"hi", PaddingPolicy::Strict).len(), MIN_STRICT_BUCKET);
+
+        // Large plaintexts grow linearly.
+        let large = pad(&vec![1; MAX_EXPONENTIAL_BUCKET + 1], PaddingPolicy::Buckets);
+        assert_eq!(large.len(), 2 * MAX_EXPONENTIAL_BUCKET);
+    }
+
+    #[test]
+    fn rejects_invalid_padding() {
+        assert!(unpad(Vec::new()).is_err());
+        assert!(unpad(vec![0; 16]).is_err());
+        assert!(unpad(vec![1, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn parses_policy_names() {
+        for policy in [
+            PaddingPolicy::Off,
+            PaddingPolicy::Buckets,
+            PaddingPolicy::Strict,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("huge".parse::<PaddingPolicy>().is_err());
+    }
+}
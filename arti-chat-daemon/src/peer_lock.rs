@@ -0,0 +1,60 @@
+//! Locks per peer.
+//! Work on the session of one peer is serialized without waiting for other peers,
+//! so slow database writes or handshakes with one contact never block the rest.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::{Mutex as TokioMutex, OwnedMutexGuard};
+
+/// Lock of each peer, created on first use.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct PeerLocks {
+    /// Onion id of peer -> its lock.
+    locks: TokioMutex<HashMap<String, Arc<TokioMutex<()>>>>,
+}
+
+impl PeerLocks {
+    /// Wait until no one else holds the lock of peer.
+    pub async fn lock(&self, peer_onion_id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().await;
+            // Onion ids of unauthenticated frames are arbitrary, so unused locks are dropped.
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(peer_onion_id.into()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serializes_per_peer_only() {
+        let locks = PeerLocks::default();
+        let alice = locks.lock("alice.onion").await;
+
+        // Other peers are not blocked.
+        let _bob = locks.lock("bob.onion").await;
+        let waiting = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            locks.lock("alice.onion"),
+        )
+        .await;
+        assert!(waiting.is_err());
+
+        drop(alice);
+        let _alice = locks.lock("alice.onion").await;
+    }
+
+    #[tokio::test]
+    async fn unused_locks_are_dropped() {
+        let locks = PeerLocks::default();
+        drop(locks.lock("alice.onion").await);
+        let _bob = locks.lock("bob.onion").await;
+        assert_eq!(locks.locks.lock().await.len(), 1);
+    }
+}
@@ -0,0 +1,103 @@
+//! Signed prekey bundles to set up a session without a round trip (X3DH-style).
+//! A bundle is published in invites and exchanged in handshakes, so a first
+//! message can be encrypted while the contact is offline.
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{db, error::RatchetError};
+
+/// Domain separator of bundle signature.
+const SIGNATURE_LABEL: &[u8] = b"arti-chat/prekey-bundle";
+
+/// Signed prekey of a user.
+#[non_exhaustive]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct PrekeyBundle {
+    /// Onion id of owner of the prekey.
+    pub onion_id: String,
+    /// Id of the prekey.
+    pub prekey_id: u32,
+    /// X25519 public prekey.
+    pub prekey_pub_key: [u8; 32],
+    /// Signature of owner over bundle.
+    pub signature: String,
+}
+
+impl PrekeyBundle {
+    /// Data covered by signature.
+    fn transcript(onion_id: &str, prekey_id: u32, prekey_pub_key: &[u8; 32]) -> Vec<u8> {
+        let mut t = Vec::new();
+        t.extend_from_slice(SIGNATURE_LABEL);
+        t.extend_from_slice(onion_id.as_bytes());
+        t.push(0);
+        t.extend_from_slice(&prekey_id.to_be_bytes());
+        t.extend_from_slice(prekey_pub_key);
+        t
+    }
+
+    /// Verify signature of bundle with identity key of owner.
+    pub fn verify(&self, onion_id: &str, public_key: &VerifyingKey) -> Result<(), RatchetError> {
+        if self.onion_id != onion_id {
+            return Err(RatchetError::InvalidPrekeyBundle);
+        }
+
+        let t = Self::transcript(&self.onion_id, self.prekey_id, &self.prekey_pub_key);
+        let signature: ed25519_dalek::Signature = self.signature.parse()?;
+        public_key.verify_strict(&t, &signature)?;
+
+        Ok(())
+    }
+
+    /// Encode bundle to share it in an invite.
+    pub fn encode(&self) -> Result<String, serde_json::Error> {
+        Ok(hex::encode(serde_json::to_vec(self)?))
+    }
+
+    /// Decode bundle from invite.
+    pub fn decode(encoded: &str) -> Result<Self, RatchetError> {
+        let bytes = hex::decode(encoded.trim())?;
+        serde_json::from_slice(&bytes).map_err(|_| RatchetError::InvalidPrekeyBundle)
+    }
+}
+
+/// Generate new signed prekey to store in database.
+pub fn generate_signed_prekey(onion_id: &str, private_key: &SigningKey) -> db::PrekeyDb {
+    let prekey = StaticSecret::random_from_rng(rand_core::OsRng);
+    let prekey_pub_key = PublicKey::from(&prekey).to_bytes();
+    // Random id so bundles of reinstalled clients do not collide.
+    let prekey_id = rand::random::<u32>() >> 1;
+
+    let t = PrekeyBundle::transcript(onion_id, prekey_id, &prekey_pub_key);
+    let signature = private_key.sign(&t).to_string();
+
+    db::PrekeyDb {
+        id: prekey_id,
+        private_key: hex::encode(prekey.to_bytes()),
+        public_key: hex::encode(prekey_pub_key),
+        signature,
+        created_at: chrono::Utc::now().timestamp(),
+    }
+}
+
+/// Build publishable bundle from stored signed prekey.
+pub fn bundle_from_db(onion_id: &str, prekey: &db::PrekeyDb) -> Result<PrekeyBundle, RatchetError> {
+    Ok(PrekeyBundle {
+        onion_id: onion_id.into(),
+        prekey_id: prekey.id,
+        prekey_pub_key: decode_key(&prekey.public_key)?,
+        signature: prekey.signature.clone(),
+    })
+}
+
+/// Get private prekey from stored signed prekey.
+pub fn secret_from_db(prekey: &db::PrekeyDb) -> Result<StaticSecret, RatchetError> {
+    Ok(StaticSecret::from(decode_key(&prekey.private_key)?))
+}
+
+/// Decode hex encoded 32 byte key.
+fn decode_key(hex_key: &str) -> Result<[u8; 32], RatchetError> {
+    hex::decode(hex_key)?
+        .try_into()
+        .map_err(|_| RatchetError::InvalidKeyLength)
+}
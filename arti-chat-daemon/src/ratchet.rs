@@ -1,162 +1,625 @@
-//! Logic to encrypt a chat session with a simple single ratchet algorithm.
-//! This to provide forward secrecy.
+//! Logic to encrypt a chat session with the Double Ratchet algorithm.
+//! The symmetric ratchet provides forward secrecy, the Diffie-Hellman ratchet
+//! heals the session after a compromise (post-compromise security).
 
 use chacha20poly1305::{
     ChaCha20Poly1305, Key, Nonce,
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
 };
 use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
-use tokio::io::AsyncReadExt;
 use x25519_dalek::{PublicKey, StaticSecret};
 use zeroize::Zeroize;
 
+use crate::deniable;
+use crate::envelope;
 use crate::error::RatchetError;
+use crate::hybrid;
 use crate::message::MessageContent;
+use crate::prekey::PrekeyBundle;
 
-/// Send and receive chain.
+/// Version of the key derivation scheme.
+/// Sessions negotiated with a different version are discarded and renegotiated.
+pub const RATCHET_VERSION: u8 = 8;
+
+/// Max difference in seconds between the timestamp of a handshake and our clock.
+pub const MAX_HANDSHAKE_AGE: i64 = 5 * 60;
+
+/// Max age in seconds of a handshake made with a signed prekey.
+/// These are delivered together with the first messages, possibly long after creation.
+pub const MAX_PREKEY_HANDSHAKE_AGE: i64 = 30 * 24 * 60 * 60;
+
+/// Version of the layout of decrypted payloads.
+/// Payloads of a newer version are rejected instead of misread.
+pub const PAYLOAD_VERSION: u8 = 1;
+
+/// Max amount of message keys skipped in a single receive chain.
+const MAX_SKIP: u32 = 1000;
+
+/// Max amount of skipped message keys stored per session.
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// Max age in seconds of a skipped message key before it is discarded.
+const SKIPPED_KEY_MAX_AGE: i64 = 7 * 24 * 60 * 60;
+
+/// HKDF info labels to keep every derived key in its own domain.
+mod kdf_info {
+    /// Derive message key from chain key.
+    pub(super) const MESSAGE_KEY: &[u8] = b"arti-chat/ratchet/message-key";
+    /// Derive next chain key from chain key.
+    pub(super) const CHAIN_KEY: &[u8] = b"arti-chat/ratchet/chain-key";
+    /// Derive new root key and chain key from DH output.
+    pub(super) const ROOT_KEY: &[u8] = b"arti-chat/ratchet/root-key";
+    /// Derive initial root key from shared secret.
+    pub(super) const INITIAL_ROOT_KEY: &[u8] = b"arti-chat/handshake/root-key";
+    /// Derive chain of initiator from shared secret.
+    pub(super) const INITIATOR_CHAIN: &[u8] = b"arti-chat/handshake/initiator-chain";
+    /// Derive chain of responder from shared secret.
+    pub(super) const RESPONDER_CHAIN: &[u8] = b"arti-chat/handshake/responder-chain";
+}
+
+/// Message key of a message which has not been received yet.
 #[non_exhaustive]
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct SkippedKey {
+    /// Ratchet public key of peer the message was sent with.
+    pub ratchet_pub_key: [u8; 32],
+    /// Counter of the message in its send chain.
+    pub counter: u32,
+    /// Message key.
+    pub key: [u8; 32],
+    /// Timestamp when key was stored.
+    pub stored_at: i64,
+}
+
+/// Securely zero memory.
+impl Drop for SkippedKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Double ratchet session state.
+#[non_exhaustive]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct RatchetChain {
+    /// Version of the key derivation scheme used to create this session.
+    #[serde(default)]
+    pub version: u8,
+    /// Root key, advanced on every DH ratchet step.
+    pub root_key: [u8; 32],
     /// To encrypt sending messages.
     pub send_chain: [u8; 32],
     /// To decrypt receiving messages.
     pub recv_chain: [u8; 32],
+    /// Our current ratchet private key.
+    pub self_ratchet_key: [u8; 32],
+    /// Current ratchet public key of peer.
+    pub peer_ratchet_pub_key: [u8; 32],
+    /// Amount of messages sent in current send chain.
+    pub send_counter: u32,
+    /// Amount of messages received in current receive chain.
+    pub recv_counter: u32,
+    /// Amount of messages sent in previous send chain.
+    pub previous_send_counter: u32,
+    /// Message keys of skipped messages which can still arrive late.
+    pub skipped_keys: Vec<SkippedKey>,
+    /// Nonce of handshake which established this session.
+    #[serde(default)]
+    pub handshake_nonce: [u8; 16],
+    /// Timestamp of the last handshake of peer which established this session.
+    #[serde(default)]
+    pub peer_handshake_timestamp: i64,
+    /// Prekey handshake attached to outgoing messages until peer replied.
+    #[serde(default)]
+    pub pending_handshake: Option<Handshake>,
+    /// Hex encoded identity key of peer which signed the handshake of this session.
+    #[serde(default)]
+    pub peer_public_key: String,
+    /// Capabilities announced by peer in its handshake.
+    /// Unknown for a session set up with a prekey until the peer replied.
+    #[serde(default)]
+    pub peer_capabilities: Option<Vec<String>>,
+    /// Session was set up with the hybrid post-quantum handshake.
+    #[serde(default)]
+    pub post_quantum: bool,
+    /// Session was set up with the deniable handshake.
+    #[serde(default)]
+    pub deniable: bool,
 }
 
 impl RatchetChain {
     /// Next step in ratchet.
+    /// Returns (message key, next chain key).
     fn next_step(chain: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
         let hk = hkdf::Hkdf::<sha2::Sha256>::from_prk(chain).expect("Invalid PRK");
 
         let mut msg = [0_u8; 32];
         let mut next = [0_u8; 32];
-        hk.expand(&[], &mut msg)
+        hk.expand(kdf_info::MESSAGE_KEY, &mut msg)
             .expect("RFC5869 HKDF-Expand operation failed");
-        hk.expand(&[], &mut next)
+        hk.expand(kdf_info::CHAIN_KEY, &mut next)
             .expect("RFC5869 HKDF-Expand operation failed");
 
         (msg, next)
     }
 
+    /// Mix DH output into root key.
+    /// Returns (next root key, new chain key).
+    fn root_step(root_key: &[u8; 32], dh_output: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+        let hk = hkdf::Hkdf::<sha2::Sha256>::new(Some(root_key), dh_output);
+
+        let mut okm = [0_u8; 64];
+        hk.expand(kdf_info::ROOT_KEY, &mut okm)
+            .expect("RFC5869 HKDF-Expand operation failed");
+
+        let mut root = [0_u8; 32];
+        let mut chain = [0_u8; 32];
+        root.copy_from_slice(&okm[..32]);
+        chain.copy_from_slice(&okm[32..]);
+        okm.zeroize();
+
+        (root, chain)
+    }
+
+    /// DH ratchet step on receiving a new ratchet public key of peer.
+    /// Derives the new receive chain and starts a new send chain with a fresh key.
+    fn dh_ratchet(&mut self, peer_ratchet_pub_key: [u8; 32]) {
+        let peer = PublicKey::from(peer_ratchet_pub_key);
+
+        let self_ratchet_key = StaticSecret::from(self.self_ratchet_key);
+        let dh_recv = self_ratchet_key.diffie_hellman(&peer);
+        let (root_key, recv_chain) = Self::root_step(&self.root_key, dh_recv.as_bytes());
+
+        let new_ratchet_key = StaticSecret::random_from_rng(rand_core::OsRng);
+        let dh_send = new_ratchet_key.diffie_hellman(&peer);
+        let (root_key, send_chain) = Self::root_step(&root_key, dh_send.as_bytes());
+
+        self.root_key = root_key;
+        self.recv_chain = recv_chain;
+        self.send_chain = send_chain;
+        self.self_ratchet_key = new_ratchet_key.to_bytes();
+        self.peer_ratchet_pub_key = peer_ratchet_pub_key;
+        self.previous_send_counter = self.send_counter;
+        self.send_counter = 0;
+        self.recv_counter = 0;
+    }
+
+    /// Advance receive chain up to `until` and store the message keys of skipped messages.
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), RatchetError> {
+        if until > self.recv_counter.saturating_add(MAX_SKIP) {
+            return Err(RatchetError::TooManySkippedMessages);
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        while self.recv_counter < until {
+            let (key, next_key) = Self::next_step(&self.recv_chain);
+            self.recv_chain = next_key;
+            self.skipped_keys.push(SkippedKey {
+                ratchet_pub_key: self.peer_ratchet_pub_key,
+                counter: self.recv_counter,
+                key,
+                stored_at: now,
+            });
+            self.recv_counter += 1;
+        }
+
+        // Discard oldest keys when store is full.
+        if self.skipped_keys.len() > MAX_SKIPPED_KEYS {
+            let overflow = self.skipped_keys.len() - MAX_SKIPPED_KEYS;
+            self.skipped_keys.drain(..overflow);
+        }
+
+        Ok(())
+    }
+
+    /// Discard skipped message keys which are too old.
+    fn prune_skipped_keys(&mut self) {
+        let now = chrono::Utc::now().timestamp();
+        self.skipped_keys
+            .retain(|k| now.saturating_sub(k.stored_at) <= SKIPPED_KEY_MAX_AGE);
+    }
+
+    /// Decrypt ciphertext with message key and verify header as associated data.
+    fn decrypt_with_key(key: &[u8; 32], msg: &EncryptedMessage) -> Result<Vec<u8>, RatchetError> {
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key));
+        let payload = Payload {
+            msg: msg.data.as_ref(),
+            aad: &msg.header.associated_data(),
+        };
+        ciphertext
+            .decrypt(Nonce::from_slice(&msg.nonce), payload)
+            .map_err(|_| RatchetError::MessageDecryptError)
+    }
+
+    /// Check if session was negotiated with the current key derivation scheme.
+    pub fn is_current(&self) -> bool {
+        self.version == RATCHET_VERSION
+    }
+
+    /// Check if peer supports an optional feature.
+    /// Assumed supported while the capabilities of peer are unknown.
+    pub fn peer_supports(&self, capability: envelope::Capability) -> bool {
+        self.peer_capabilities
+            .as_ref()
+            .is_none_or(|capabilities| capabilities.iter().any(|c| c == capability.name()))
+    }
+
     /// Encrypt plaintext data + do next step in send chain.
     pub fn encrypt(&mut self, plaintext: &[u8], self_onion_id: String) -> EncryptedMessage {
         let (current_key, next_key) = Self::next_step(&self.send_chain);
         self.send_chain = next_key;
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let ratchet_pub_key = PublicKey::from(&StaticSecret::from(self.self_ratchet_key));
+        let header = MessageHeader {
+            version: self.version,
+            from: self_onion_id,
+            ratchet_pub_key: ratchet_pub_key.to_bytes(),
+            counter,
+            previous_counter: self.previous_send_counter,
+        };
 
         let cipher = ChaCha20Poly1305::new(Key::from_slice(&current_key));
         let nonce = rand::random::<[u8; 12]>();
+        let payload = Payload {
+            msg: plaintext,
+            aad: &header.associated_data(),
+        };
         let data = cipher
-            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .encrypt(Nonce::from_slice(&nonce), payload)
             .expect("encryption failed");
 
         EncryptedMessage {
-            from: self_onion_id,
+            header,
             nonce,
             data,
+            prekey_handshake: self.pending_handshake.clone(),
         }
     }
 
     /// Decrypt encrypted message + do next step in receive chain.
+    /// Does a DH ratchet step first if the peer sent a new ratchet public key.
+    /// Late messages are decrypted with a stored skipped message key.
+    /// The session state is only updated if the message could be decrypted.
     pub fn decrypt(&mut self, msg: &EncryptedMessage) -> Result<Vec<u8>, RatchetError> {
-        let (current_key, next_key) = Self::next_step(&self.recv_chain);
-        self.recv_chain = next_key;
+        let header = &msg.header;
+        if header.version != self.version {
+            return Err(RatchetError::VersionMismatch);
+        }
 
-        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&current_key));
-        ciphertext
-            .decrypt(Nonce::from_slice(&msg.nonce), msg.data.as_ref())
-            .map_err(|_| RatchetError::MessageDecryptError)
+        self.prune_skipped_keys();
+
+        // Message of current receive chain which was already received.
+        if header.ratchet_pub_key == self.peer_ratchet_pub_key
+            && header.counter < self.recv_counter
+            && !self
+                .skipped_keys
+                .iter()
+                .any(|k| k.ratchet_pub_key == header.ratchet_pub_key && k.counter == header.counter)
+        {
+            return Err(RatchetError::ReplayedMessage);
+        }
+
+        // Message which arrived late or out of order.
+        if let Some(index) = self.skipped_keys.iter().position(|k| {
+            k.ratchet_pub_key == header.ratchet_pub_key && k.counter == header.counter
+        }) {
+            let plaintext = Self::decrypt_with_key(&self.skipped_keys[index].key, msg)?;
+            self.skipped_keys.remove(index);
+            self.pending_handshake = None;
+            return Ok(plaintext);
+        }
+
+        // Work on a copy so a forged or corrupted message does not advance the session.
+        let mut state = self.clone();
+        if header.ratchet_pub_key != state.peer_ratchet_pub_key {
+            state.skip_message_keys(header.previous_counter)?;
+            state.dh_ratchet(header.ratchet_pub_key);
+        }
+        state.skip_message_keys(header.counter)?;
+
+        let (current_key, next_key) = Self::next_step(&state.recv_chain);
+        state.recv_chain = next_key;
+        state.recv_counter += 1;
+
+        let plaintext = Self::decrypt_with_key(&current_key, msg)?;
+        // Peer replied, so it has the session and no longer needs the prekey handshake.
+        state.pending_handshake = None;
+        *self = state;
+
+        Ok(plaintext)
     }
 }
 
 /// Securely zero memory.
 impl Drop for RatchetChain {
     fn drop(&mut self) {
+        self.root_key.zeroize();
         self.send_chain.zeroize();
         self.recv_chain.zeroize();
+        self.self_ratchet_key.zeroize();
     }
 }
 
 /// Handshake to establish or accept a session.
 #[non_exhaustive]
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct Handshake {
+    /// Version of the key derivation scheme.
+    #[serde(default)]
+    pub version: u8,
     /// Origin of handshake.
     pub from: String,
     /// Receiver of handshake.
     pub to: String,
     /// Ephemeral public key to establish session.
     pub ephemeral_pub_key: [u8; 32],
-    /// Signature to verify handshake.
+    /// Timestamp of creation, to reject stale handshakes.
+    #[serde(default)]
+    pub timestamp: i64,
+    /// Random nonce, to reject duplicate handshakes.
+    #[serde(default)]
+    pub nonce: [u8; 16],
+    /// Id of signed prekey of receiver used instead of an ephemeral reply.
+    #[serde(default)]
+    pub prekey_id: Option<u32>,
+    /// Names of optional features supported by sender.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// ML-KEM encapsulation key of initiator offering a hybrid session.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub kem_pub_key: Option<Vec<u8>>,
+    /// ML-KEM ciphertext of responder accepting a hybrid session.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub kem_ciphertext: Option<Vec<u8>>,
+    /// Authenticated with a MAC instead of a signature.
+    #[serde(default)]
+    pub deniable: bool,
+    /// Signature to verify handshake, or MAC if deniable.
     pub signature: String,
+    /// Signed prekey bundle of sender, so the receiver can reach us while we are offline.
+    /// Not part of the transcript since the bundle is signed itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prekey_bundle: Option<PrekeyBundle>,
+}
+
+/// Options of a handshake we initiate or accept.
+#[non_exhaustive]
+#[derive(Clone, Copy, Default)]
+pub struct HandshakeOptions {
+    /// Offer or accept the hybrid post-quantum handshake.
+    pub post_quantum: bool,
+    /// Refuse sessions without the hybrid post-quantum handshake, implies `post_quantum`.
+    pub require_post_quantum: bool,
+    /// Authenticate deniably instead of signing.
+    pub deniable: bool,
+}
+
+/// Our secrets of a handshake, kept until it completes.
+#[non_exhaustive]
+pub struct HandshakeSecret {
+    /// Ephemeral X25519 secret.
+    ephemeral_priv_key: StaticSecret,
+    /// X25519 identity secret, for deniable sessions.
+    identity_priv_key: StaticSecret,
+    /// Session is deniable, as initiated by us or replied by us.
+    deniable: bool,
+    /// Reply without ML-KEM ciphertext is refused.
+    require_post_quantum: bool,
+    /// ML-KEM decapsulation key of initiator offering a hybrid session.
+    kem_decapsulation_key: Option<hybrid::DecapsulationKey>,
+    /// ML-KEM shared secret of responder accepting a hybrid session.
+    kem_shared_secret: Option<[u8; 32]>,
+}
+
+impl HandshakeSecret {
+    /// Secret without hybrid part.
+    fn new(ephemeral_priv_key: StaticSecret, self_private_key: &SigningKey) -> Self {
+        Self {
+            ephemeral_priv_key,
+            identity_priv_key: deniable::identity_secret(self_private_key),
+            deniable: false,
+            require_post_quantum: false,
+            kem_decapsulation_key: None,
+            kem_shared_secret: None,
+        }
+    }
+}
+
+/// Securely zero memory.
+impl Drop for HandshakeSecret {
+    fn drop(&mut self) {
+        self.kem_shared_secret.zeroize();
+    }
 }
 
 impl Handshake {
+    /// Create unsigned handshake with fresh timestamp and nonce.
+    fn new(from: &str, to: &str, ephemeral_pub_key: &PublicKey) -> Self {
+        Self {
+            version: RATCHET_VERSION,
+            from: from.into(),
+            to: to.into(),
+            ephemeral_pub_key: ephemeral_pub_key.to_bytes(),
+            timestamp: chrono::Utc::now().timestamp(),
+            nonce: rand::random::<[u8; 16]>(),
+            prekey_id: None,
+            capabilities: envelope::supported_capabilities(),
+            kem_pub_key: None,
+            kem_ciphertext: None,
+            deniable: false,
+            signature: String::new(),
+            prekey_bundle: None,
+        }
+    }
+
     /// Create transcipt so we can sign public key for handshake.
-    fn transcript(from: &str, to: &str, ephemeral_pub_key: &[u8; 32]) -> Vec<u8> {
+    fn transcript(&self) -> Vec<u8> {
         let mut t = Vec::new();
-        t.extend_from_slice(from.as_bytes());
+        t.push(self.version);
+        t.extend_from_slice(self.from.as_bytes());
         t.push(0);
-        t.extend_from_slice(to.as_bytes());
+        t.extend_from_slice(self.to.as_bytes());
         t.push(0);
-        t.extend_from_slice(ephemeral_pub_key);
+        t.extend_from_slice(&self.ephemeral_pub_key);
+        t.extend_from_slice(&self.timestamp.to_be_bytes());
+        t.extend_from_slice(&self.nonce);
+        match self.prekey_id {
+            Some(prekey_id) => {
+                t.push(1);
+                t.extend_from_slice(&prekey_id.to_be_bytes());
+            }
+            None => t.push(0),
+        }
+        t.extend_from_slice(&(self.capabilities.len() as u32).to_be_bytes());
+        for capability in &self.capabilities {
+            t.extend_from_slice(capability.as_bytes());
+            t.push(0);
+        }
+        // Absent without the hybrid handshake, so classic peers sign the same transcript.
+        if self.kem_pub_key.is_some() || self.kem_ciphertext.is_some() {
+            for kem_field in [&self.kem_pub_key, &self.kem_ciphertext] {
+                match kem_field {
+                    Some(bytes) => {
+                        t.push(1);
+                        t.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+                        t.extend_from_slice(bytes);
+                    }
+                    None => t.push(0),
+                }
+            }
+        }
+        // Absent unless deniable, so signed handshakes keep their transcript.
+        if self.deniable {
+            t.push(1);
+        }
         t
     }
 
-    /// Create handshake and return ephemeral secret.
-    /// Send by initiator.
+    /// Sign transcript, or MAC it for peer if deniable.
+    fn sign(&mut self, self_private_key: &SigningKey, peer_public_key: &VerifyingKey) {
+        self.signature = if self.deniable {
+            let self_identity = deniable::identity_secret(self_private_key);
+            deniable::mac(&self.transcript(), &self_identity, peer_public_key)
+        } else {
+            self_private_key.sign(&self.transcript()).to_string()
+        };
+    }
+
+    /// Check target, version, signature (or MAC) and freshness of received handshake.
+    fn verify(
+        &self,
+        self_onion_id: &str,
+        peer_public_key: &VerifyingKey,
+        self_identity: &StaticSecret,
+    ) -> Result<(), RatchetError> {
+        if self.to != self_onion_id {
+            return Err(RatchetError::InvalidHandshakeTarget);
+        }
+        if self.version != RATCHET_VERSION {
+            return Err(RatchetError::UnsupportedVersion(self.version));
+        }
+
+        if self.deniable {
+            deniable::verify_mac(
+                &self.transcript(),
+                &self.signature,
+                self_identity,
+                peer_public_key,
+            )?;
+        } else {
+            let signature: ed25519_dalek::Signature = self.signature.parse()?;
+            peer_public_key.verify_strict(&self.transcript(), &signature)?;
+        }
+
+        if !self.is_fresh() {
+            return Err(RatchetError::StaleHandshake);
+        }
+
+        Ok(())
+    }
+
+    /// Check if timestamp of handshake is within the allowed window.
+    pub fn is_fresh(&self) -> bool {
+        let max_age = match self.prekey_id {
+            Some(_) => MAX_PREKEY_HANDSHAKE_AGE,
+            None => MAX_HANDSHAKE_AGE,
+        };
+        let now = chrono::Utc::now().timestamp();
+        now.abs_diff(self.timestamp) <= max_age.unsigned_abs()
+    }
+
+    /// Create handshake and return our secrets.
+    /// Send by initiator, offering a hybrid session if `post_quantum` is set.
     pub fn initiate(
         self_onion_id: &str,
         peer_onion_id: &str,
+        peer_public_key: &VerifyingKey,
         self_private_key: &SigningKey,
-    ) -> (Self, StaticSecret) {
+        options: HandshakeOptions,
+    ) -> (Self, HandshakeSecret) {
         let ephemeral_priv_key = StaticSecret::random_from_rng(rand_core::OsRng);
         let ephemeral_pub_key = PublicKey::from(&ephemeral_priv_key);
-        let t = Self::transcript(self_onion_id, peer_onion_id, ephemeral_pub_key.as_bytes());
-        let signature = self_private_key.sign(&t).to_string();
-
-        (
-            Self {
-                from: self_onion_id.into(),
-                to: peer_onion_id.into(),
-                ephemeral_pub_key: ephemeral_pub_key.to_bytes(),
-                signature,
-            },
-            ephemeral_priv_key,
-        )
+
+        let mut handshake = Self::new(self_onion_id, peer_onion_id, &ephemeral_pub_key);
+        let mut secret = HandshakeSecret::new(ephemeral_priv_key, self_private_key);
+        if options.post_quantum || options.require_post_quantum {
+            let (decapsulation_key, encapsulation_key) = hybrid::generate();
+            handshake.kem_pub_key = Some(encapsulation_key);
+            secret.kem_decapsulation_key = Some(decapsulation_key);
+        }
+        secret.require_post_quantum = options.require_post_quantum;
+        handshake.deniable = options.deniable;
+        secret.deniable = options.deniable;
+        handshake.sign(self_private_key, peer_public_key);
+
+        (handshake, secret)
     }
 
-    /// Accept incoming handshake + create response handshake and return ephemeral secret.
-    /// Done by responder.
+    /// Accept incoming handshake + create response handshake and return our secrets.
+    /// Done by responder, accepting an offered hybrid session if `post_quantum` is set.
+    /// A handshake without hybrid offer is refused if `require_post_quantum` is set.
+    /// The reply is deniable if the initiator or we want a deniable session,
+    /// but only if the initiator supports it.
     pub fn accept(
         &self,
         self_onion_id: &str,
         peer_public_key: &VerifyingKey,
         self_private_key: &SigningKey,
-    ) -> Result<(Self, StaticSecret), RatchetError> {
-        if self.to != self_onion_id {
-            return Err(RatchetError::InvalidHandshakeTarget);
-        }
-
-        // Verify incoming handshake.
-        let t = Self::transcript(&self.from, &self.to, &self.ephemeral_pub_key);
-        let signature: ed25519_dalek::Signature = self.signature.parse()?;
-        peer_public_key.verify_strict(&t, &signature)?;
-
+        options: HandshakeOptions,
+    ) -> Result<(Self, HandshakeSecret), RatchetError> {
         // Create reply.
         let ephemeral_priv_key = StaticSecret::random_from_rng(rand_core::OsRng);
         let ephemeral_pub_key = PublicKey::from(&ephemeral_priv_key);
-        let t_reply = Self::transcript(self_onion_id, &self.from, ephemeral_pub_key.as_bytes());
-        let signature_reply = self_private_key.sign(&t_reply).to_string();
-
-        Ok((
-            Self {
-                from: self_onion_id.into(),
-                to: self.from.clone(),
-                ephemeral_pub_key: ephemeral_pub_key.to_bytes(),
-                signature: signature_reply,
-            },
-            ephemeral_priv_key,
-        ))
+
+        let mut reply = Self::new(self_onion_id, &self.from, &ephemeral_pub_key);
+        let mut secret = HandshakeSecret::new(ephemeral_priv_key, self_private_key);
+
+        // Verify incoming handshake.
+        self.verify(self_onion_id, peer_public_key, &secret.identity_priv_key)?;
+
+        if options.require_post_quantum && self.kem_pub_key.is_none() {
+            return Err(RatchetError::PostQuantumRequired);
+        }
+        if let Some(encapsulation_key) = self
+            .kem_pub_key
+            .as_deref()
+            .filter(|_| options.post_quantum || options.require_post_quantum)
+        {
+            let (ciphertext, shared_secret) = hybrid::encapsulate(encapsulation_key)?;
+            reply.kem_ciphertext = Some(ciphertext);
+            secret.kem_shared_secret = Some(shared_secret);
+        }
+        let peer_supports_deniable = self
+            .capabilities
+            .iter()
+            .any(|c| c == envelope::Capability::Deniable.name());
+        reply.deniable = self.deniable || (options.deniable && peer_supports_deniable);
+        secret.deniable = reply.deniable;
+        reply.sign(self_private_key, peer_public_key);
+
+        Ok((reply, secret))
     }
 
     /// Complete handshake and derive ratchet chains.
@@ -164,55 +627,297 @@ impl Handshake {
         &self,
         self_onion_id: &str,
         peer_public_key: &VerifyingKey,
-        self_ephemeral_priv_key: &StaticSecret,
+        self_secret: &HandshakeSecret,
         is_initiator: bool,
     ) -> Result<RatchetChain, RatchetError> {
-        if self.to != self_onion_id {
-            return Err(RatchetError::InvalidHandshakeTarget);
+        // Verify reply.
+        self.verify(
+            self_onion_id,
+            peer_public_key,
+            &self_secret.identity_priv_key,
+        )?;
+
+        // The reply decides, but may not drop the deniability we asked for.
+        let deniable = if is_initiator {
+            if self_secret.deniable && !self.deniable {
+                return Err(RatchetError::DeniabilityMismatch);
+            }
+            self.deniable
+        } else {
+            self_secret.deniable
+        };
+        let mut identity_dh = deniable.then(|| {
+            deniable::identity_dh(
+                &self_secret.identity_priv_key,
+                &self_secret.ephemeral_priv_key,
+                peer_public_key,
+                self.ephemeral_pub_key,
+                is_initiator,
+            )
+        });
+
+        // Initiator gets the KEM secret from the reply, the responder already has it.
+        let kem_shared_secret = if is_initiator {
+            match (&self_secret.kem_decapsulation_key, &self.kem_ciphertext) {
+                (Some(decapsulation_key), Some(ciphertext)) => {
+                    Some(hybrid::decapsulate(decapsulation_key, ciphertext)?)
+                }
+                // Ciphertext for a key we never offered.
+                (None, Some(_)) => return Err(RatchetError::InvalidKemEncoding),
+                // Peer declined hybrid session.
+                (Some(_), None) if self_secret.require_post_quantum => {
+                    return Err(RatchetError::PostQuantumRequired);
+                }
+                _ => None,
+            }
+        } else {
+            self_secret.kem_shared_secret
+        };
+
+        let mut ratchet = Self::derive_session(
+            &self_secret.ephemeral_priv_key,
+            self.ephemeral_pub_key,
+            identity_dh.as_ref(),
+            kem_shared_secret.as_ref(),
+            is_initiator,
+        )?;
+        identity_dh.zeroize();
+        ratchet.handshake_nonce = self.nonce;
+        ratchet.peer_handshake_timestamp = self.timestamp;
+        ratchet.peer_public_key = hex::encode(peer_public_key.as_bytes());
+        ratchet.peer_capabilities = Some(self.capabilities.clone());
+
+        Ok(ratchet)
+    }
+
+    /// Create session with signed prekey of peer, without waiting for a reply.
+    /// The handshake is attached to outgoing messages until the peer replies.
+    pub fn initiate_with_prekey(
+        self_onion_id: &str,
+        peer_onion_id: &str,
+        peer_bundle: &PrekeyBundle,
+        peer_public_key: &VerifyingKey,
+        self_private_key: &SigningKey,
+        deniable: bool,
+    ) -> Result<RatchetChain, RatchetError> {
+        peer_bundle.verify(peer_onion_id, peer_public_key)?;
+
+        let ephemeral_priv_key = StaticSecret::random_from_rng(rand_core::OsRng);
+        let ephemeral_pub_key = PublicKey::from(&ephemeral_priv_key);
+
+        let mut handshake = Self::new(self_onion_id, peer_onion_id, &ephemeral_pub_key);
+        handshake.prekey_id = Some(peer_bundle.prekey_id);
+        handshake.deniable = deniable;
+        handshake.sign(self_private_key, peer_public_key);
+
+        let mut identity_dh = deniable.then(|| {
+            deniable::identity_dh(
+                &deniable::identity_secret(self_private_key),
+                &ephemeral_priv_key,
+                peer_public_key,
+                peer_bundle.prekey_pub_key,
+                true,
+            )
+        });
+        let mut ratchet = Self::derive_session(
+            &ephemeral_priv_key,
+            peer_bundle.prekey_pub_key,
+            identity_dh.as_ref(),
+            None,
+            true,
+        )?;
+        identity_dh.zeroize();
+        ratchet.handshake_nonce = handshake.nonce;
+        ratchet.pending_handshake = Some(handshake);
+        ratchet.peer_public_key = hex::encode(peer_public_key.as_bytes());
+
+        Ok(ratchet)
+    }
+
+    /// Complete session from prekey handshake attached to an incoming message.
+    /// Done by responder with the signed prekey the handshake refers to.
+    pub fn accept_with_prekey(
+        &self,
+        self_onion_id: &str,
+        peer_public_key: &VerifyingKey,
+        self_private_key: &SigningKey,
+        self_prekey: &StaticSecret,
+    ) -> Result<RatchetChain, RatchetError> {
+        if self.prekey_id.is_none() {
+            return Err(RatchetError::InvalidPrekeyBundle);
         }
+        let self_identity = deniable::identity_secret(self_private_key);
+        self.verify(self_onion_id, peer_public_key, &self_identity)?;
 
-        // Verify reply.
-        let t = Self::transcript(&self.from, &self.to, &self.ephemeral_pub_key);
-        let signature: ed25519_dalek::Signature = self.signature.parse()?;
-        peer_public_key.verify_strict(&t, &signature)?;
+        let mut identity_dh = self.deniable.then(|| {
+            deniable::identity_dh(
+                &self_identity,
+                self_prekey,
+                peer_public_key,
+                self.ephemeral_pub_key,
+                false,
+            )
+        });
+        let mut ratchet = Self::derive_session(
+            self_prekey,
+            self.ephemeral_pub_key,
+            identity_dh.as_ref(),
+            None,
+            false,
+        )?;
+        identity_dh.zeroize();
+        ratchet.handshake_nonce = self.nonce;
+        ratchet.peer_handshake_timestamp = self.timestamp;
+        ratchet.peer_public_key = hex::encode(peer_public_key.as_bytes());
+        ratchet.peer_capabilities = Some(self.capabilities.clone());
+
+        Ok(ratchet)
+    }
 
+    /// Derive initial session state from our ephemeral (or prekey) secret and
+    /// the ephemeral (or prekey) public key of peer.
+    /// A deniable session also feeds the identity DHs into the key derivation,
+    /// a hybrid session the ML-KEM secret.
+    fn derive_session(
+        self_ephemeral_priv_key: &StaticSecret,
+        peer_ephemeral_pub_key: [u8; 32],
+        identity_dh: Option<&[u8; 64]>,
+        kem_shared_secret: Option<&[u8; 32]>,
+        is_initiator: bool,
+    ) -> Result<RatchetChain, RatchetError> {
         // Shared DH secret.
         let shared_secret =
-            self_ephemeral_priv_key.diffie_hellman(&PublicKey::from(self.ephemeral_pub_key));
+            self_ephemeral_priv_key.diffie_hellman(&PublicKey::from(peer_ephemeral_pub_key));
+        let mut input_key_material = shared_secret.as_bytes().to_vec();
+        if let Some(identity_dh) = identity_dh {
+            input_key_material.extend_from_slice(identity_dh);
+        }
+        if let Some(kem_shared_secret) = kem_shared_secret {
+            input_key_material.extend_from_slice(kem_shared_secret);
+        }
 
-        // Derive key chains for sending and receiving.
-        let hk = hkdf::Hkdf::<sha2::Sha256>::new(None, shared_secret.as_bytes());
-        let mut a = [0_u8; 32];
-        let mut b = [0_u8; 32];
-        hk.expand(&[], &mut a)
+        // Derive root key and initial chains for sending and receiving.
+        // The initiator sends on the initiator chain, the responder on the responder chain.
+        let hk = hkdf::Hkdf::<sha2::Sha256>::new(None, &input_key_material);
+        input_key_material.zeroize();
+        let mut root_key = [0_u8; 32];
+        let mut initiator_chain = [0_u8; 32];
+        let mut responder_chain = [0_u8; 32];
+        hk.expand(kdf_info::INITIAL_ROOT_KEY, &mut root_key)
+            .map_err(|_| RatchetError::HkdfInvalidLength)?;
+        hk.expand(kdf_info::INITIATOR_CHAIN, &mut initiator_chain)
             .map_err(|_| RatchetError::HkdfInvalidLength)?;
-        hk.expand(&[], &mut b)
+        hk.expand(kdf_info::RESPONDER_CHAIN, &mut responder_chain)
             .map_err(|_| RatchetError::HkdfInvalidLength)?;
 
-        let (send_chain, recv_chain) = if is_initiator { (a, b) } else { (b, a) };
-        Ok(RatchetChain {
-            send_chain,
-            recv_chain,
-        })
+        // The responder starts with its handshake key as ratchet key.
+        // The initiator immediately does a DH ratchet step with a fresh key,
+        // so the first reply of the responder already triggers a new DH step.
+        let mut ratchet = RatchetChain {
+            version: RATCHET_VERSION,
+            root_key,
+            send_chain: responder_chain,
+            recv_chain: initiator_chain,
+            self_ratchet_key: self_ephemeral_priv_key.to_bytes(),
+            peer_ratchet_pub_key: peer_ephemeral_pub_key,
+            send_counter: 0,
+            recv_counter: 0,
+            previous_send_counter: 0,
+            skipped_keys: Vec::new(),
+            handshake_nonce: [0_u8; 16],
+            peer_handshake_timestamp: 0,
+            pending_handshake: None,
+            peer_public_key: String::new(),
+            peer_capabilities: None,
+            post_quantum: kem_shared_secret.is_some(),
+            deniable: identity_dh.is_some(),
+        };
+
+        if is_initiator {
+            let ratchet_key = StaticSecret::random_from_rng(rand_core::OsRng);
+            let dh_send = ratchet_key.diffie_hellman(&PublicKey::from(peer_ephemeral_pub_key));
+            let (root_key, send_chain) =
+                RatchetChain::root_step(&ratchet.root_key, dh_send.as_bytes());
+
+            ratchet.root_key = root_key;
+            ratchet.send_chain = send_chain;
+            ratchet.recv_chain = responder_chain;
+            ratchet.self_ratchet_key = ratchet_key.to_bytes();
+        }
+
+        initiator_chain.zeroize();
+        responder_chain.zeroize();
+
+        Ok(ratchet)
     }
 }
 
-/// Encrypted ciphertext.
+/// Header of encrypted message.
+/// Authenticated as associated data, so tampering is detected on decrypt.
 #[non_exhaustive]
 #[derive(serde::Serialize, serde::Deserialize)]
-pub struct EncryptedMessage {
+pub struct MessageHeader {
+    /// Version of the key derivation scheme of the session.
+    #[serde(default)]
+    pub version: u8,
     /// Origin of message.
     pub from: String,
+    /// Current ratchet public key of sender.
+    #[serde(default)]
+    pub ratchet_pub_key: [u8; 32],
+    /// Number of message in current send chain.
+    #[serde(default)]
+    pub counter: u32,
+    /// Amount of messages in previous send chain of sender.
+    #[serde(default)]
+    pub previous_counter: u32,
+}
+
+impl MessageHeader {
+    /// Domain separator of associated data.
+    const AAD_LABEL: &'static [u8] = b"arti-chat/message-header";
+
+    /// Canonical encoding of header used as associated data.
+    /// Fixed field order, length-prefixed strings and big-endian integers.
+    pub fn associated_data(&self) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(Self::AAD_LABEL.len() + self.from.len() + 48);
+        aad.extend_from_slice(Self::AAD_LABEL);
+        aad.push(self.version);
+        aad.extend_from_slice(&(self.from.len() as u32).to_be_bytes());
+        aad.extend_from_slice(self.from.as_bytes());
+        aad.extend_from_slice(&self.ratchet_pub_key);
+        aad.extend_from_slice(&self.counter.to_be_bytes());
+        aad.extend_from_slice(&self.previous_counter.to_be_bytes());
+        aad
+    }
+}
+
+/// Encrypted ciphertext.
+#[non_exhaustive]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct EncryptedMessage {
+    /// Authenticated header.
+    #[serde(flatten)]
+    pub header: MessageHeader,
     /// Nonce for unique keystream.
     pub nonce: [u8; 12],
     /// Message data / payload.
+    #[serde(with = "serde_bytes")]
     pub data: Vec<u8>,
+    /// Prekey handshake of sender, present until we replied to the sender.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prekey_handshake: Option<Handshake>,
 }
 
 /// Unencrypted message.
 #[non_exhaustive]
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct PlaintextPayload {
+    /// Version of layout, 0 if sent before payloads were versioned.
+    #[serde(default)]
+    pub version: u8,
+    /// Random id of message, reused when the message is sent again.
+    pub message_id: String,
     /// Sender or receiver.
     pub onion_id: String,
     /// Timestamp from sending or receiving.
@@ -221,6 +926,36 @@ pub struct PlaintextPayload {
     pub message: MessageContent,
 }
 
+impl PlaintextPayload {
+    /// Create payload of current version.
+    pub fn new(message_id: &str, onion_id: &str, timestamp: i64, message: MessageContent) -> Self {
+        Self {
+            version: PAYLOAD_VERSION,
+            message_id: message_id.into(),
+            onion_id: onion_id.into(),
+            timestamp,
+            message,
+        }
+    }
+
+    /// Check if we understand the layout of a received payload.
+    pub fn check_version(&self) -> Result<(), RatchetError> {
+        if self.version > PAYLOAD_VERSION {
+            return Err(RatchetError::UnsupportedPayloadVersion(self.version));
+        }
+        Ok(())
+    }
+
+    /// Check if a received payload names the sender of its authenticated header.
+    /// A peer must not place messages in the chat of another contact.
+    pub fn check_sender(&self, header: &MessageHeader) -> Result<(), RatchetError> {
+        if self.onion_id != header.from {
+            return Err(RatchetError::SenderMismatch);
+        }
+        Ok(())
+    }
+}
+
 // --- Helpers ---
 
 /// Get VerifyingKey from hex string.
@@ -232,26 +967,615 @@ pub fn verifying_key_from_hex(hex_pk: &str) -> Result<VerifyingKey, RatchetError
     Ok(VerifyingKey::from_bytes(&arr)?)
 }
 
-/// Read null-terminated frame from Tor stream.
-pub async fn read_null_terminated<S: tokio::io::AsyncRead + Unpin>(
-    stream: &mut S,
-) -> Result<String, RatchetError> {
-    let mut buffer = Vec::new();
-    let mut byte = [0_u8; 1];
-
-    loop {
-        match stream.read(&mut byte).await {
-            Ok(0) => break, // EOF
-            Ok(1) => {
-                if byte[0] == 0 {
-                    break;
-                }
-                buffer.push(byte[0]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sessions of alice and bob after a handshake with options of both.
+    fn session_pair(
+        alice_options: HandshakeOptions,
+        bob_options: HandshakeOptions,
+    ) -> (RatchetChain, RatchetChain) {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+
+        let (handshake, alice_secret) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            alice_options,
+        );
+        let (reply, bob_secret) = handshake
+            .accept(
+                "bob.onion",
+                &alice_key.verifying_key(),
+                &bob_key,
+                bob_options,
+            )
+            .expect("accept handshake");
+        let bob = handshake
+            .complete("bob.onion", &alice_key.verifying_key(), &bob_secret, false)
+            .expect("complete as responder");
+        let alice = reply
+            .complete("alice.onion", &bob_key.verifying_key(), &alice_secret, true)
+            .expect("complete as initiator");
+        (alice, bob)
+    }
+
+    /// Sessions of alice and bob with default options.
+    fn pair() -> (RatchetChain, RatchetChain) {
+        session_pair(HandshakeOptions::default(), HandshakeOptions::default())
+    }
+
+    /// Encrypt text from alice.
+    fn send(chain: &mut RatchetChain, text: &str) -> EncryptedMessage {
+        chain.encrypt(text.as_bytes(), "alice.onion".into())
+    }
+
+    /// Decrypt message as text.
+    fn receive(chain: &mut RatchetChain, msg: &EncryptedMessage) -> Result<String, RatchetError> {
+        chain
+            .decrypt(msg)
+            .map(|plaintext| String::from_utf8(plaintext).expect("utf-8 plaintext"))
+    }
+
+    #[test]
+    fn chains_of_both_directions_differ() {
+        let (mut alice, mut bob) = pair();
+        assert!(alice.is_current());
+        assert_eq!(alice.recv_chain, bob.send_chain);
+        assert_ne!(alice.send_chain, alice.recv_chain);
+
+        let msg = alice.encrypt(b"hello", "alice.onion".into());
+        assert_eq!(bob.decrypt(&msg).expect("decrypt"), b"hello");
+        let reply = bob.encrypt(b"hi", "bob.onion".into());
+        assert_eq!(alice.decrypt(&reply).expect("decrypt reply"), b"hi");
+    }
+
+    #[test]
+    fn every_reply_does_dh_ratchet_step() {
+        let (mut alice, mut bob) = pair();
+        let mut alice_keys = vec![alice.self_ratchet_key];
+        let mut bob_keys = vec![bob.self_ratchet_key];
+
+        for turn in 0..3 {
+            let msg = alice.encrypt(b"ping", "alice.onion".into());
+            assert_eq!(bob.decrypt(&msg).expect("decrypt ping"), b"ping");
+            let reply = bob.encrypt(b"pong", "bob.onion".into());
+            assert_eq!(alice.decrypt(&reply).expect("decrypt pong"), b"pong");
+
+            // Both sides moved to a fresh ratchet key and agree on the new chain.
+            assert!(!alice_keys.contains(&alice.self_ratchet_key), "turn {turn}");
+            assert!(!bob_keys.contains(&bob.self_ratchet_key), "turn {turn}");
+            alice_keys.push(alice.self_ratchet_key);
+            bob_keys.push(bob.self_ratchet_key);
+            assert_eq!(alice.recv_chain, bob.send_chain);
+        }
+    }
+
+    #[test]
+    fn same_ratchet_key_advances_only_chain() {
+        let (mut alice, mut bob) = pair();
+        let first = alice.encrypt(b"0", "alice.onion".into());
+        let second = alice.encrypt(b"1", "alice.onion".into());
+        assert_eq!(first.header.ratchet_pub_key, second.header.ratchet_pub_key);
+
+        bob.decrypt(&first).expect("decrypt first");
+        let root_key = bob.root_key;
+        bob.decrypt(&second).expect("decrypt second");
+        assert_eq!(bob.root_key, root_key);
+    }
+
+    #[test]
+    fn message_key_differs_from_next_chain_key() {
+        let (message_key, chain_key) = RatchetChain::next_step(&[7; 32]);
+        assert_ne!(message_key, chain_key);
+    }
+
+    #[test]
+    fn rejects_message_of_other_version() {
+        let (mut alice, mut bob) = pair();
+        let mut msg = alice.encrypt(b"hello", "alice.onion".into());
+        msg.header.version = RATCHET_VERSION + 1;
+        assert!(matches!(
+            bob.decrypt(&msg),
+            Err(RatchetError::VersionMismatch)
+        ));
+    }
+
+    #[test]
+    fn unauthenticated_version_change_keeps_session() {
+        let (mut alice, mut bob) = pair();
+        let msg = alice.encrypt(b"hello", "alice.onion".into());
+
+        // Anyone can send a header of another version, it is rejected before decryption.
+        let mut forged = alice.encrypt(b"forged", "alice.onion".into());
+        forged.header.version = RATCHET_VERSION + 1;
+        forged.data = vec![0; forged.data.len()];
+        assert!(bob.decrypt(&forged).is_err());
+
+        assert!(bob.is_current());
+        assert_eq!(bob.decrypt(&msg).expect("decrypt"), b"hello");
+    }
+
+    #[test]
+    fn session_stored_before_versioning_is_not_current() {
+        let (alice, _) = pair();
+        let mut stored = serde_json::to_value(&alice).expect("serialize session");
+        stored
+            .as_object_mut()
+            .expect("session is an object")
+            .remove("version");
+        let stored: RatchetChain = serde_json::from_value(stored).expect("parse stored session");
+        assert!(!stored.is_current());
+    }
+
+    #[test]
+    fn rejects_handshake_of_other_version() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let (mut handshake, _) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            HandshakeOptions::default(),
+        );
+        handshake.version = 0;
+        assert!(matches!(
+            handshake.accept(
+                "bob.onion",
+                &alice_key.verifying_key(),
+                &bob_key,
+                HandshakeOptions::default()
+            ),
+            Err(RatchetError::UnsupportedVersion(0))
+        ));
+    }
+
+    #[test]
+    fn decrypts_messages_out_of_order() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "0");
+        let second = send(&mut alice, "1");
+        let third = send(&mut alice, "2");
+
+        assert_eq!(receive(&mut bob, &third).expect("decrypt"), "2");
+        assert_eq!(bob.skipped_keys.len(), 2);
+
+        // A new receive chain after a reply keeps the skipped keys of the old one.
+        let reply = bob.encrypt(b"r", "bob.onion".into());
+        assert_eq!(alice.decrypt(&reply).expect("decrypt reply"), b"r");
+        let fourth = send(&mut alice, "3");
+        assert_eq!(receive(&mut bob, &fourth).expect("decrypt"), "3");
+
+        assert_eq!(receive(&mut bob, &first).expect("decrypt late"), "0");
+        assert_eq!(receive(&mut bob, &second).expect("decrypt late"), "1");
+        assert!(bob.skipped_keys.is_empty());
+    }
+
+    #[test]
+    fn skipped_key_is_used_once() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "0");
+        let second = send(&mut alice, "1");
+
+        receive(&mut bob, &second).expect("decrypt");
+        receive(&mut bob, &first).expect("decrypt late");
+        assert!(receive(&mut bob, &first).is_err());
+        assert!(receive(&mut bob, &second).is_err());
+    }
+
+    #[test]
+    fn tampered_message_does_not_advance_session() {
+        let (mut alice, mut bob) = pair();
+        let mut msg = send(&mut alice, "0");
+        msg.data[0] ^= 1;
+        assert!(receive(&mut bob, &msg).is_err());
+        assert!(bob.skipped_keys.is_empty());
+
+        msg.data[0] ^= 1;
+        assert_eq!(receive(&mut bob, &msg).expect("decrypt"), "0");
+    }
+
+    #[test]
+    fn rejects_too_many_skipped_messages() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "0");
+        for _ in 0..MAX_SKIP {
+            send(&mut alice, "skipped");
+        }
+        let last = send(&mut alice, "last");
+
+        assert!(matches!(
+            receive(&mut bob, &last),
+            Err(RatchetError::TooManySkippedMessages)
+        ));
+        assert_eq!(receive(&mut bob, &first).expect("decrypt"), "0");
+    }
+
+    #[test]
+    fn header_is_authenticated() {
+        let (mut alice, mut bob) = pair();
+        let mut msg = send(&mut alice, "0");
+
+        msg.header.from = "mallory.onion".into();
+        assert!(receive(&mut bob, &msg).is_err());
+        msg.header.from = "alice.onion".into();
+
+        msg.header.previous_counter += 1;
+        assert!(receive(&mut bob, &msg).is_err());
+        msg.header.previous_counter -= 1;
+
+        assert_eq!(receive(&mut bob, &msg).expect("decrypt"), "0");
+    }
+
+    #[test]
+    fn payload_of_other_sender_is_dropped() {
+        let (mut alice, mut bob) = pair();
+        for (onion_id, is_sender) in [("carol.onion", false), ("alice.onion", true)] {
+            let payload = PlaintextPayload::new(
+                "id",
+                onion_id,
+                0,
+                MessageContent::Text { text: "hi".into() },
+            );
+            let mut plaintext = Vec::new();
+            ciborium::into_writer(&payload, &mut plaintext).expect("encode payload");
+            let msg = alice.encrypt(&plaintext, "alice.onion".into());
+
+            let plaintext = bob.decrypt(&msg).expect("decrypt");
+            let payload: PlaintextPayload =
+                ciborium::from_reader(plaintext.as_slice()).expect("decode payload");
+            let checked = payload.check_sender(&msg.header);
+            assert_eq!(checked.is_ok(), is_sender);
+            if !is_sender {
+                assert!(matches!(checked, Err(RatchetError::SenderMismatch)));
             }
-            Ok(_) => unreachable!(),
-            Err(e) => return Err(e.into()),
         }
     }
 
-    Ok(String::from_utf8_lossy(&buffer).to_string())
+    #[test]
+    fn rejects_replayed_message() {
+        let (mut alice, mut bob) = pair();
+        let msg = send(&mut alice, "0");
+        assert_eq!(receive(&mut bob, &msg).expect("decrypt"), "0");
+        assert!(matches!(
+            receive(&mut bob, &msg),
+            Err(RatchetError::ReplayedMessage)
+        ));
+    }
+
+    #[test]
+    fn rejects_stale_handshake() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let (mut handshake, _) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            HandshakeOptions::default(),
+        );
+        handshake.timestamp -= MAX_HANDSHAKE_AGE + 1;
+        handshake.sign(&alice_key, &bob_key.verifying_key());
+
+        assert!(matches!(
+            handshake.accept(
+                "bob.onion",
+                &alice_key.verifying_key(),
+                &bob_key,
+                HandshakeOptions::default()
+            ),
+            Err(RatchetError::StaleHandshake)
+        ));
+    }
+
+    #[test]
+    fn rejects_handshake_with_changed_timestamp() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let (mut handshake, _) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            HandshakeOptions::default(),
+        );
+        handshake.timestamp += 1;
+
+        assert!(matches!(
+            handshake.accept(
+                "bob.onion",
+                &alice_key.verifying_key(),
+                &bob_key,
+                HandshakeOptions::default()
+            ),
+            Err(RatchetError::Ed25519Error(_))
+        ));
+    }
+
+    /// Session of alice set up with prekey of bob, and the prekey secret of bob.
+    fn prekey_session(
+        alice_key: &SigningKey,
+        bob_key: &SigningKey,
+        deniable: bool,
+    ) -> (RatchetChain, StaticSecret) {
+        let prekey = crate::prekey::generate_signed_prekey("bob.onion", bob_key);
+        let bundle = crate::prekey::bundle_from_db("bob.onion", &prekey).expect("prekey bundle");
+        let alice = Handshake::initiate_with_prekey(
+            "alice.onion",
+            "bob.onion",
+            &bundle,
+            &bob_key.verifying_key(),
+            alice_key,
+            deniable,
+        )
+        .expect("initiate with prekey");
+        let secret = crate::prekey::secret_from_db(&prekey).expect("prekey secret");
+        (alice, secret)
+    }
+
+    #[test]
+    fn prekey_session_works_before_peer_replies() {
+        for deniable in [false, true] {
+            let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+            let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+            let (mut alice, prekey_secret) = prekey_session(&alice_key, &bob_key, deniable);
+
+            // Every message carries the handshake until bob replies.
+            let first = send(&mut alice, "0");
+            let second = send(&mut alice, "1");
+            let handshake = second.prekey_handshake.clone().expect("prekey handshake");
+            assert!(first.prekey_handshake.is_some());
+
+            let mut bob = handshake
+                .accept_with_prekey(
+                    "bob.onion",
+                    &alice_key.verifying_key(),
+                    &bob_key,
+                    &prekey_secret,
+                )
+                .expect("accept prekey handshake");
+            assert_eq!(bob.deniable, deniable);
+            assert_eq!(receive(&mut bob, &second).expect("decrypt"), "1");
+            assert_eq!(receive(&mut bob, &first).expect("decrypt late"), "0");
+
+            let reply = bob.encrypt(b"r", "bob.onion".into());
+            assert!(reply.prekey_handshake.is_none());
+            assert_eq!(alice.decrypt(&reply).expect("decrypt reply"), b"r");
+            assert!(send(&mut alice, "2").prekey_handshake.is_none());
+        }
+    }
+
+    #[test]
+    fn prekey_handshake_needs_matching_keys() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let mallory_key = SigningKey::generate(&mut rand_core::OsRng);
+
+        // Bundle of bob does not verify with the key of another peer.
+        let prekey = crate::prekey::generate_signed_prekey("bob.onion", &bob_key);
+        let bundle = crate::prekey::bundle_from_db("bob.onion", &prekey).expect("prekey bundle");
+        assert!(
+            Handshake::initiate_with_prekey(
+                "alice.onion",
+                "bob.onion",
+                &bundle,
+                &mallory_key.verifying_key(),
+                &alice_key,
+                false,
+            )
+            .is_err()
+        );
+
+        // Handshake from alice does not verify with the key of another peer.
+        let (mut alice, prekey_secret) = prekey_session(&alice_key, &bob_key, false);
+        let handshake = send(&mut alice, "0")
+            .prekey_handshake
+            .expect("prekey handshake");
+        assert!(
+            handshake
+                .accept_with_prekey(
+                    "bob.onion",
+                    &mallory_key.verifying_key(),
+                    &bob_key,
+                    &prekey_secret,
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn session_knows_capabilities_of_peer() {
+        let (alice, _) = pair();
+        assert!(alice.peer_supports(envelope::Capability::Attachments));
+
+        let mut without = pair().0;
+        without.peer_capabilities = Some(Vec::new());
+        assert!(!without.peer_supports(envelope::Capability::Attachments));
+    }
+
+    #[test]
+    fn capabilities_are_signed() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let (mut handshake, _) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            HandshakeOptions::default(),
+        );
+        handshake.capabilities.clear();
+        assert!(
+            handshake
+                .accept(
+                    "bob.onion",
+                    &alice_key.verifying_key(),
+                    &bob_key,
+                    HandshakeOptions::default()
+                )
+                .is_err()
+        );
+    }
+
+    /// Handshake options with post-quantum offered or not.
+    fn post_quantum(offer: bool) -> HandshakeOptions {
+        HandshakeOptions {
+            post_quantum: offer,
+            ..HandshakeOptions::default()
+        }
+    }
+
+    #[test]
+    fn hybrid_handshake_needs_both_peers() {
+        for (alice_pq, bob_pq) in [(true, true), (true, false), (false, true)] {
+            let (mut alice, mut bob) = session_pair(post_quantum(alice_pq), post_quantum(bob_pq));
+            assert_eq!(alice.post_quantum, alice_pq && bob_pq);
+            assert_eq!(bob.post_quantum, alice_pq && bob_pq);
+
+            let msg = send(&mut alice, "hi");
+            assert_eq!(receive(&mut bob, &msg).expect("decrypt"), "hi");
+        }
+    }
+
+    #[test]
+    fn kem_key_is_signed() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let (mut handshake, _) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            post_quantum(true),
+        );
+        handshake.kem_pub_key = None;
+        assert!(
+            handshake
+                .accept(
+                    "bob.onion",
+                    &alice_key.verifying_key(),
+                    &bob_key,
+                    post_quantum(true)
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn kem_fields_only_in_transcript_if_present() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let (mut handshake, _) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            post_quantum(true),
+        );
+        let kem_pub_key = handshake.kem_pub_key.take().expect("kem key");
+        let classic = handshake.transcript();
+        handshake.kem_pub_key = Some(kem_pub_key.clone());
+        let hybrid = handshake.transcript();
+
+        assert_eq!(hybrid.len(), classic.len() + 1 + 4 + kem_pub_key.len() + 1);
+    }
+
+    #[test]
+    fn deniable_session_if_either_peer_wants_it() {
+        let deniable = |deniable| HandshakeOptions {
+            deniable,
+            ..HandshakeOptions::default()
+        };
+        for (alice_deniable, bob_deniable) in
+            [(true, true), (true, false), (false, true), (false, false)]
+        {
+            let (mut alice, mut bob) =
+                session_pair(deniable(alice_deniable), deniable(bob_deniable));
+            assert_eq!(alice.deniable, alice_deniable || bob_deniable);
+            assert_eq!(bob.deniable, alice_deniable || bob_deniable);
+
+            let msg = send(&mut alice, "hi");
+            assert_eq!(receive(&mut bob, &msg).expect("decrypt"), "hi");
+            let reply = bob.encrypt(b"yo", "bob.onion".into());
+            assert_eq!(alice.decrypt(&reply).expect("decrypt reply"), b"yo");
+        }
+    }
+
+    #[test]
+    fn no_deniable_reply_to_peer_without_support() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let (mut handshake, _) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            HandshakeOptions::default(),
+        );
+        handshake
+            .capabilities
+            .retain(|c| c != envelope::Capability::Deniable.name());
+        handshake.sign(&alice_key, &bob_key.verifying_key());
+
+        let (reply, _) = handshake
+            .accept(
+                "bob.onion",
+                &alice_key.verifying_key(),
+                &bob_key,
+                HandshakeOptions {
+                    deniable: true,
+                    ..HandshakeOptions::default()
+                },
+            )
+            .expect("accept handshake");
+        assert!(!reply.deniable);
+
+        // Signed handshakes keep the transcript of peers without deniability.
+        let signed = handshake.transcript();
+        handshake.deniable = true;
+        assert_eq!(handshake.transcript().len(), signed.len() + 1);
+    }
+
+    #[test]
+    fn deniable_handshake_verifies_for_its_peer_only() {
+        let alice_key = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_key = SigningKey::generate(&mut rand_core::OsRng);
+        let carol_key = SigningKey::generate(&mut rand_core::OsRng);
+        let options = HandshakeOptions {
+            deniable: true,
+            ..HandshakeOptions::default()
+        };
+
+        let (handshake, _) = Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &bob_key.verifying_key(),
+            &alice_key,
+            options,
+        );
+        assert!(
+            handshake
+                .accept(
+                    "bob.onion",
+                    &alice_key.verifying_key(),
+                    &bob_key,
+                    HandshakeOptions::default(),
+                )
+                .is_ok()
+        );
+        // The MAC is keyed for bob, so no one else can verify it.
+        assert!(matches!(
+            handshake.accept(
+                "bob.onion",
+                &alice_key.verifying_key(),
+                &carol_key,
+                HandshakeOptions::default(),
+            ),
+            Err(RatchetError::InvalidHandshakeMac)
+        ));
+    }
 }
@@ -0,0 +1,106 @@
+//! Queue of delivery and read receipts waiting to be sent.
+//! Receipts are sent by a background task, so handling an incoming message
+//! never waits for the peer, and receipts of messages arriving shortly
+//! after each other are batched into one.
+
+use crate::message::ReceiptKind;
+
+/// Milliseconds to wait for more receipts to the same peer before sending.
+pub const RECEIPT_BATCH_DELAY: u64 = 500;
+
+/// Max amount of message ids in a single receipt.
+pub const MAX_RECEIPT_IDS: usize = 100;
+
+/// Receipts to send, grouped per peer and kind.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct ReceiptQueue {
+    /// (Onion id of peer, kind) -> message ids in the order they were queued.
+    pending: std::collections::HashMap<(String, ReceiptKind), Vec<String>>,
+}
+
+impl ReceiptQueue {
+    /// Queue receipt for messages of peer, ids already queued are skipped.
+    pub fn push(&mut self, peer_onion_id: &str, kind: ReceiptKind, message_ids: Vec<String>) {
+        let queued = self
+            .pending
+            .entry((peer_onion_id.into(), kind))
+            .or_default();
+        for message_id in message_ids {
+            if !queued.contains(&message_id) {
+                queued.push(message_id);
+            }
+        }
+    }
+
+    /// Take all queued receipts as (onion id of peer, kind, message ids).
+    /// Receipts with many ids are split, so each fits in one message.
+    pub fn take(&mut self) -> Vec<(String, ReceiptKind, Vec<String>)> {
+        let mut receipts = Vec::new();
+        for ((peer_onion_id, kind), message_ids) in self.pending.drain() {
+            for chunk in message_ids.chunks(MAX_RECEIPT_IDS) {
+                receipts.push((peer_onion_id.clone(), kind, chunk.to_vec()));
+            }
+        }
+        receipts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Message ids of range as strings.
+    fn ids(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn batches_receipts_per_peer_and_kind() {
+        let mut queue = ReceiptQueue::default();
+        queue.push("alice.onion", ReceiptKind::Delivered, ids(0..1));
+        queue.push("alice.onion", ReceiptKind::Delivered, ids(1..3));
+        queue.push("alice.onion", ReceiptKind::Read, ids(0..1));
+        queue.push("bob.onion", ReceiptKind::Delivered, ids(0..1));
+
+        let mut receipts = queue.take();
+        receipts.sort_by(|a, b| (&a.0, a.2.len()).cmp(&(&b.0, b.2.len())));
+        assert_eq!(
+            receipts,
+            [
+                ("alice.onion".to_string(), ReceiptKind::Read, ids(0..1)),
+                ("alice.onion".to_string(), ReceiptKind::Delivered, ids(0..3)),
+                ("bob.onion".to_string(), ReceiptKind::Delivered, ids(0..1)),
+            ]
+        );
+        assert!(queue.take().is_empty());
+    }
+
+    #[test]
+    fn skips_ids_already_queued() {
+        let mut queue = ReceiptQueue::default();
+        queue.push("alice.onion", ReceiptKind::Delivered, ids(0..2));
+        queue.push("alice.onion", ReceiptKind::Delivered, ids(1..3));
+
+        let receipts = queue.take();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].2, ids(0..3));
+    }
+
+    #[test]
+    fn splits_large_receipts() {
+        let mut queue = ReceiptQueue::default();
+        queue.push(
+            "alice.onion",
+            ReceiptKind::Read,
+            ids(0..MAX_RECEIPT_IDS + 1),
+        );
+
+        let receipts = queue.take();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(
+            receipts[0].2.len() + receipts[1].2.len(),
+            MAX_RECEIPT_IDS + 1
+        );
+    }
+}
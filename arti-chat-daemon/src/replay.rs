@@ -0,0 +1,105 @@
+//! Replay protection for incoming handshakes and control messages.
+//! Rejected handshakes and messages are logged as security events.
+
+use crate::{error::RatchetError, ratchet};
+
+/// Remembers nonces of recently accepted handshakes.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct ReplayCache {
+    /// Nonce of handshake or control message -> its timestamp.
+    handshakes: std::collections::HashMap<[u8; 16], i64>,
+}
+
+impl ReplayCache {
+    /// Reject stale handshakes and handshakes with an already seen nonce.
+    /// Call only after the signature of the handshake has been verified.
+    pub fn check_handshake(&mut self, handshake: &ratchet::Handshake) -> Result<(), RatchetError> {
+        if !handshake.is_fresh() {
+            return Err(RatchetError::StaleHandshake);
+        }
+
+        self.check_nonce(handshake.nonce, handshake.timestamp)
+    }
+
+    /// Reject already seen nonce of a fresh, verified handshake or control message.
+    pub fn check_nonce(&mut self, nonce: [u8; 16], timestamp: i64) -> Result<(), RatchetError> {
+        // Nonces outside the window can be forgotten,
+        // those handshakes are rejected as stale anyway.
+        let now = chrono::Utc::now().timestamp();
+        self.handshakes
+            .retain(|_, ts| now.abs_diff(*ts) <= ratchet::MAX_HANDSHAKE_AGE.unsigned_abs());
+
+        if self.handshakes.insert(nonce, timestamp).is_some() {
+            return Err(RatchetError::ReplayedHandshake);
+        }
+
+        Ok(())
+    }
+}
+
+/// Log rejected handshake or message of peer as security event.
+pub fn log_security_event(peer_onion_id: &str, err: &RatchetError) {
+    if matches!(
+        err,
+        RatchetError::StaleHandshake
+            | RatchetError::ReplayedHandshake
+            | RatchetError::ReplayedMessage
+            | RatchetError::InvalidKeyRotation
+            | RatchetError::InvalidContactRequest
+            | RatchetError::ConflictingContactRequest
+            | RatchetError::MessageDecryptError
+            | RatchetError::SenderMismatch
+            | RatchetError::Ed25519Error(_)
+            | RatchetError::InvalidHandshakeMac
+            | RatchetError::PostQuantumRequired
+    ) {
+        tracing::warn!(
+            target: "arti_chat::security",
+            "Security event: rejected data from {}: {}",
+            safelog::sensitive(peer_onion_id),
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Signed handshake of alice to bob.
+    fn handshake() -> ratchet::Handshake {
+        let key = ed25519_dalek::SigningKey::generate(&mut rand_core::OsRng);
+        ratchet::Handshake::initiate(
+            "alice.onion",
+            "bob.onion",
+            &key.verifying_key(),
+            &key,
+            ratchet::HandshakeOptions::default(),
+        )
+        .0
+    }
+
+    #[test]
+    fn accepts_handshake_once() {
+        let mut cache = ReplayCache::default();
+        let first = handshake();
+        assert!(cache.check_handshake(&first).is_ok());
+        assert!(matches!(
+            cache.check_handshake(&first),
+            Err(RatchetError::ReplayedHandshake)
+        ));
+        assert!(cache.check_handshake(&handshake()).is_ok());
+    }
+
+    #[test]
+    fn rejects_stale_handshake() {
+        let mut cache = ReplayCache::default();
+        let mut handshake = handshake();
+        handshake.timestamp -= ratchet::MAX_HANDSHAKE_AGE + 1;
+        assert!(matches!(
+            cache.check_handshake(&handshake),
+            Err(RatchetError::StaleHandshake)
+        ));
+    }
+}
@@ -0,0 +1,295 @@
+//! Session reset control message.
+//! Sent to a peer whose message we could not decrypt, so it re-handshakes
+//! and resends what we missed.
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use x25519_dalek::StaticSecret;
+
+use crate::{deniable, error::RatchetError, ratchet};
+
+/// Min interval in seconds between resets sent to or accepted from the same peer.
+pub const SESSION_RESET_INTERVAL: i64 = 60;
+
+/// Undecryptable messages of a peer with a working session before a reset is requested.
+/// A single one authenticates nothing, so anyone could forge it.
+pub const RESET_AFTER_FAILURES: u32 = 3;
+
+/// Max age in seconds of messages resent after a reset.
+pub const MAX_RESEND_AGE: i64 = 24 * 60 * 60;
+
+/// Domain separator of reset signature.
+const SIGNATURE_LABEL: &[u8] = b"arti-chat/session-reset";
+
+/// Request to peer to drop its session with us, re-handshake and resend.
+#[non_exhaustive]
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SessionReset {
+    /// Ratchet version of sender.
+    pub version: u8,
+    /// Onion id of sender.
+    pub from: String,
+    /// Onion id of receiver.
+    pub to: String,
+    /// Timestamp of last message sender received from receiver.
+    /// Messages after it are resent.
+    pub since: i64,
+    /// Timestamp of creation.
+    pub timestamp: i64,
+    /// Random nonce, to reject duplicate resets.
+    pub nonce: [u8; 16],
+    /// Reset is authenticated with a MAC instead of a signature.
+    #[serde(default)]
+    pub deniable: bool,
+    /// Signature to verify reset, or MAC if deniable.
+    pub signature: String,
+}
+
+impl SessionReset {
+    /// Create reset for peer, signed or with a MAC if deniable.
+    pub fn new(
+        from: &str,
+        to: &str,
+        since: i64,
+        self_private_key: &SigningKey,
+        peer_public_key: &VerifyingKey,
+        deniable: bool,
+    ) -> Self {
+        let mut reset = Self {
+            version: ratchet::RATCHET_VERSION,
+            from: from.into(),
+            to: to.into(),
+            since,
+            timestamp: chrono::Utc::now().timestamp(),
+            nonce: rand::random::<[u8; 16]>(),
+            deniable,
+            signature: String::new(),
+        };
+        reset.signature = if deniable {
+            let self_identity = deniable::identity_secret(self_private_key);
+            deniable::mac(&reset.transcript(), &self_identity, peer_public_key)
+        } else {
+            self_private_key.sign(&reset.transcript()).to_string()
+        };
+        reset
+    }
+
+    /// Data covered by signature or MAC.
+    fn transcript(&self) -> Vec<u8> {
+        let mut t = Vec::new();
+        t.extend_from_slice(SIGNATURE_LABEL);
+        t.push(self.version);
+        t.extend_from_slice(self.from.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.to.as_bytes());
+        t.push(0);
+        t.extend_from_slice(&self.since.to_be_bytes());
+        t.extend_from_slice(&self.timestamp.to_be_bytes());
+        t.extend_from_slice(&self.nonce);
+        t
+    }
+
+    /// Check target, signature or MAC, and freshness of received reset.
+    pub fn verify(
+        &self,
+        self_onion_id: &str,
+        peer_public_key: &VerifyingKey,
+        self_identity: &StaticSecret,
+    ) -> Result<(), RatchetError> {
+        if self.to != self_onion_id {
+            return Err(RatchetError::InvalidHandshakeTarget);
+        }
+
+        if self.deniable {
+            deniable::verify_mac(
+                &self.transcript(),
+                &self.signature,
+                self_identity,
+                peer_public_key,
+            )?;
+        } else {
+            let signature: ed25519_dalek::Signature = self.signature.parse()?;
+            peer_public_key.verify_strict(&self.transcript(), &signature)?;
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        if now.abs_diff(self.timestamp) > ratchet::MAX_HANDSHAKE_AGE.unsigned_abs() {
+            return Err(RatchetError::StaleHandshake);
+        }
+
+        Ok(())
+    }
+}
+
+/// Limits resets per peer to prevent reset loops.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct ResetThrottle {
+    /// Onion id of peer -> timestamp of last reset.
+    last_reset: std::collections::HashMap<String, i64>,
+}
+
+impl ResetThrottle {
+    /// Check if a reset with peer is allowed now and remember it if so.
+    pub fn allow(&mut self, peer_onion_id: &str) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.last_reset
+            .retain(|_, ts| now - *ts < SESSION_RESET_INTERVAL);
+
+        if self.last_reset.contains_key(peer_onion_id) {
+            return false;
+        }
+        self.last_reset.insert(peer_onion_id.into(), now);
+        true
+    }
+}
+
+/// Counts undecryptable messages per peer, so a forged message can't drop a working session.
+#[non_exhaustive]
+#[derive(Default)]
+pub struct DecryptFailures {
+    /// Onion id of peer -> failures since its last authenticated message and time of the last one.
+    failures: std::collections::HashMap<String, (u32, i64)>,
+}
+
+impl DecryptFailures {
+    /// Record an undecryptable message of peer and check if a reset should be requested.
+    pub fn record(&mut self, peer_onion_id: &str) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.failures
+            .retain(|_, (_, ts)| now - *ts < MAX_RESEND_AGE);
+
+        let (count, ts) = self.failures.entry(peer_onion_id.into()).or_default();
+        *count += 1;
+        *ts = now;
+        if *count < RESET_AFTER_FAILURES {
+            return false;
+        }
+        self.failures.remove(peer_onion_id);
+        true
+    }
+
+    /// Forget failures of peer once one of its messages authenticated.
+    pub fn clear(&mut self, peer_onion_id: &str) {
+        self.failures.remove(peer_onion_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_reset_for_its_receiver_only() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_identity = deniable::identity_secret(&bob);
+
+        for deniable in [false, true] {
+            let mut reset = SessionReset::new(
+                "alice.onion",
+                "bob.onion",
+                42,
+                &alice,
+                &bob.verifying_key(),
+                deniable,
+            );
+            assert!(
+                reset
+                    .verify("bob.onion", &alice.verifying_key(), &bob_identity)
+                    .is_ok()
+            );
+            assert!(matches!(
+                reset.verify("carol.onion", &alice.verifying_key(), &bob_identity),
+                Err(RatchetError::InvalidHandshakeTarget)
+            ));
+
+            // Resend of older messages can't be requested by changing the reset.
+            reset.since = 0;
+            assert!(
+                reset
+                    .verify("bob.onion", &alice.verifying_key(), &bob_identity)
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_deniable_reset_for_other_peer() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let carol = SigningKey::generate(&mut rand_core::OsRng);
+
+        let reset = SessionReset::new(
+            "alice.onion",
+            "bob.onion",
+            0,
+            &alice,
+            &carol.verifying_key(),
+            true,
+        );
+        let result = reset.verify(
+            "bob.onion",
+            &alice.verifying_key(),
+            &deniable::identity_secret(&bob),
+        );
+        assert!(matches!(result, Err(RatchetError::InvalidHandshakeMac)));
+    }
+
+    #[test]
+    fn rejects_stale_reset() {
+        let alice = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let mut reset = SessionReset::new(
+            "alice.onion",
+            "bob.onion",
+            42,
+            &alice,
+            &bob.verifying_key(),
+            false,
+        );
+        reset.timestamp -= ratchet::MAX_HANDSHAKE_AGE + 1;
+        reset.signature = alice.sign(&reset.transcript()).to_string();
+        assert!(matches!(
+            reset.verify(
+                "bob.onion",
+                &alice.verifying_key(),
+                &deniable::identity_secret(&bob)
+            ),
+            Err(RatchetError::StaleHandshake)
+        ));
+    }
+
+    #[test]
+    fn throttles_resets_per_peer() {
+        let mut throttle = ResetThrottle::default();
+        assert!(throttle.allow("alice.onion"));
+        assert!(!throttle.allow("alice.onion"));
+        assert!(throttle.allow("bob.onion"));
+
+        // Allowed again once the interval passed.
+        throttle.last_reset.insert(
+            "alice.onion".into(),
+            chrono::Utc::now().timestamp() - SESSION_RESET_INTERVAL,
+        );
+        assert!(throttle.allow("alice.onion"));
+    }
+
+    #[test]
+    fn single_forged_message_does_not_reset() {
+        let mut failures = DecryptFailures::default();
+        for _ in 1..RESET_AFTER_FAILURES {
+            assert!(!failures.record("alice.onion"));
+        }
+        // An authenticated message of the peer proves its session works.
+        failures.clear("alice.onion");
+        assert!(!failures.record("alice.onion"));
+        assert!(!failures.record("bob.onion"));
+
+        for _ in 2..RESET_AFTER_FAILURES {
+            assert!(!failures.record("alice.onion"));
+        }
+        assert!(failures.record("alice.onion"));
+        // Counting starts over after a reset.
+        assert!(!failures.record("alice.onion"));
+    }
+}
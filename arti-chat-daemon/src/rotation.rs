@@ -0,0 +1,199 @@
+//! Identity key rotation announced to contacts.
+//! A notice is signed by both the old and the new key, so only the owner of
+//! the key a contact already trusts can replace it.
+//! Deniable contacts get a notice with MACs of both keys instead.
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use x25519_dalek::StaticSecret;
+
+use crate::{deniable, error::RatchetError, ratchet};
+
+/// Domain separator of rotation signatures.
+const SIGNATURE_LABEL: &[u8] = b"arti-chat/key-rotation";
+
+/// Notice to contacts that our identity key was replaced.
+#[non_exhaustive]
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct KeyRotation {
+    /// Onion id of sender.
+    pub from: String,
+    /// Hex encoded public key which is replaced.
+    pub old_public_key: String,
+    /// Hex encoded public key which replaces the old one.
+    pub new_public_key: String,
+    /// Timestamp of rotation.
+    pub timestamp: i64,
+    /// Notice is authenticated with MACs for one contact instead of signatures.
+    #[serde(default)]
+    pub deniable: bool,
+    /// Signature with old key, or MAC if deniable.
+    pub old_signature: String,
+    /// Signature with new key, or MAC if deniable.
+    pub new_signature: String,
+}
+
+impl KeyRotation {
+    /// Create notice signed by old and new key.
+    /// With the public key of a deniable contact, both keys MAC it for that contact only.
+    pub fn new(
+        from: &str,
+        old_private_key: &SigningKey,
+        new_private_key: &SigningKey,
+        deniable_peer: Option<&VerifyingKey>,
+    ) -> Self {
+        let mut rotation = Self {
+            from: from.into(),
+            old_public_key: hex::encode(old_private_key.verifying_key().as_bytes()),
+            new_public_key: hex::encode(new_private_key.verifying_key().as_bytes()),
+            timestamp: chrono::Utc::now().timestamp(),
+            deniable: deniable_peer.is_some(),
+            old_signature: String::new(),
+            new_signature: String::new(),
+        };
+
+        let t = rotation.transcript();
+        match deniable_peer {
+            Some(peer_public_key) => {
+                let old_identity = deniable::identity_secret(old_private_key);
+                let new_identity = deniable::identity_secret(new_private_key);
+                rotation.old_signature = deniable::mac(&t, &old_identity, peer_public_key);
+                rotation.new_signature = deniable::mac(&t, &new_identity, peer_public_key);
+            }
+            None => {
+                rotation.old_signature = old_private_key.sign(&t).to_string();
+                rotation.new_signature = new_private_key.sign(&t).to_string();
+            }
+        }
+        rotation
+    }
+
+    /// Data covered by both signatures or MACs.
+    fn transcript(&self) -> Vec<u8> {
+        let mut t = Vec::new();
+        t.extend_from_slice(SIGNATURE_LABEL);
+        t.extend_from_slice(self.from.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.old_public_key.as_bytes());
+        t.push(0);
+        t.extend_from_slice(self.new_public_key.as_bytes());
+        t.push(0);
+        t.extend_from_slice(&self.timestamp.to_be_bytes());
+        t
+    }
+
+    /// Verify notice against the key we currently know of the sender.
+    /// Returns the new key if both signatures or MACs verify.
+    /// A replayed notice is rejected since its old key is no longer current.
+    pub fn verify(
+        &self,
+        current_public_key: &VerifyingKey,
+        self_identity: &StaticSecret,
+    ) -> Result<VerifyingKey, RatchetError> {
+        let old_public_key = ratchet::verifying_key_from_hex(&self.old_public_key)?;
+        if old_public_key != *current_public_key {
+            return Err(RatchetError::InvalidKeyRotation);
+        }
+        let new_public_key = ratchet::verifying_key_from_hex(&self.new_public_key)?;
+
+        let t = self.transcript();
+        if self.deniable {
+            deniable::verify_mac(&t, &self.old_signature, self_identity, &old_public_key)?;
+            deniable::verify_mac(&t, &self.new_signature, self_identity, &new_public_key)?;
+        } else {
+            let old_signature: ed25519_dalek::Signature = self.old_signature.parse()?;
+            let new_signature: ed25519_dalek::Signature = self.new_signature.parse()?;
+            old_public_key.verify_strict(&t, &old_signature)?;
+            new_public_key.verify_strict(&t, &new_signature)?;
+        }
+
+        Ok(new_public_key)
+    }
+
+    /// Parse notice queued for delivery, `None` if it is unreadable.
+    pub fn from_stored(notice: &str) -> Option<Self> {
+        serde_json::from_str(notice).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_notice_signed_by_old_and_new_key() {
+        let old = SigningKey::generate(&mut rand_core::OsRng);
+        let new = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let notice = KeyRotation::new("alice.onion", &old, &new, None);
+
+        let new_public_key = notice
+            .verify(&old.verifying_key(), &deniable::identity_secret(&bob))
+            .expect("verify notice");
+        assert_eq!(new_public_key, new.verifying_key());
+    }
+
+    #[test]
+    fn verifies_deniable_notice_for_its_contact_only() {
+        let old = SigningKey::generate(&mut rand_core::OsRng);
+        let new = SigningKey::generate(&mut rand_core::OsRng);
+        let bob = SigningKey::generate(&mut rand_core::OsRng);
+        let carol = SigningKey::generate(&mut rand_core::OsRng);
+        let notice = KeyRotation::new("alice.onion", &old, &new, Some(&bob.verifying_key()));
+
+        let new_public_key = notice
+            .verify(&old.verifying_key(), &deniable::identity_secret(&bob))
+            .expect("verify deniable notice");
+        assert_eq!(new_public_key, new.verifying_key());
+        assert!(
+            notice
+                .verify(&old.verifying_key(), &deniable::identity_secret(&carol))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_notice_of_other_key() {
+        let old = SigningKey::generate(&mut rand_core::OsRng);
+        let new = SigningKey::generate(&mut rand_core::OsRng);
+        let other = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_identity = deniable::identity_secret(&SigningKey::generate(&mut rand_core::OsRng));
+        let notice = KeyRotation::new("alice.onion", &old, &new, None);
+        assert!(matches!(
+            notice.verify(&other.verifying_key(), &bob_identity),
+            Err(RatchetError::InvalidKeyRotation)
+        ));
+
+        // Replayed once the new key is current.
+        assert!(notice.verify(&new.verifying_key(), &bob_identity).is_err());
+    }
+
+    #[test]
+    fn rejects_notice_with_replaced_new_key() {
+        let old = SigningKey::generate(&mut rand_core::OsRng);
+        let new = SigningKey::generate(&mut rand_core::OsRng);
+        let mallory = SigningKey::generate(&mut rand_core::OsRng);
+        let bob_identity = deniable::identity_secret(&SigningKey::generate(&mut rand_core::OsRng));
+        let mut notice = KeyRotation::new("alice.onion", &old, &new, None);
+        notice.new_public_key = hex::encode(mallory.verifying_key().as_bytes());
+        notice.new_signature = mallory.sign(&notice.transcript()).to_string();
+
+        assert!(notice.verify(&old.verifying_key(), &bob_identity).is_err());
+    }
+
+    #[test]
+    fn parses_stored_notice() {
+        let old = SigningKey::generate(&mut rand_core::OsRng);
+        let new = SigningKey::generate(&mut rand_core::OsRng);
+        let notice = KeyRotation::new("alice.onion", &old, &new, None);
+        let stored = serde_json::to_string(&notice).expect("serialize notice");
+
+        let parsed = KeyRotation::from_stored(&stored).expect("parse notice");
+        assert_eq!(parsed.new_signature, notice.new_signature);
+    }
+
+    #[test]
+    fn rejects_unreadable_notice() {
+        assert!(KeyRotation::from_stored("not a notice").is_none());
+        assert!(KeyRotation::from_stored(r#"{"kind":"key_rotation"}"#).is_none());
+    }
+}
@@ -4,10 +4,12 @@ use crate::{
     attachment,
     client::{self, ClientConfigKey},
     db::{self, DbModel, DbUpdateModel},
+    envelope,
     error::{self, RpcError},
-    ipc::MessageToUI,
-    message::MessageContent,
-    ui_focus,
+    identity,
+    ipc::{self, MessageToUI},
+    message::{self, MessageContent},
+    prekey, ratchet, ui_focus,
 };
 use async_trait::async_trait;
 
@@ -45,8 +47,15 @@ pub enum RpcCommand {
         nickname: String,
         /// Onion ID of the contact.
         onion_id: String,
-        /// Public key of the contact.
-        public_key: String,
+        /// Public key of the contact, required unless the contact uses identity mode.
+        #[serde(default)]
+        public_key: Option<String>,
+        /// Contact uses identity mode, so its public key is derived from the onion ID.
+        #[serde(default)]
+        identity_mode: bool,
+        /// Optional signed prekey bundle of the contact.
+        #[serde(default)]
+        prekey_bundle: Option<String>,
     },
 
     /// Update an existing contact.
@@ -57,6 +66,9 @@ pub enum RpcCommand {
         nickname: Option<String>,
         /// Optional new public key for the contact.
         public_key: Option<String>,
+        /// Optional new signed prekey bundle for the contact.
+        #[serde(default)]
+        prekey_bundle: Option<String>,
     },
 
     /// Load the local user profile.
@@ -70,6 +82,9 @@ pub enum RpcCommand {
         private_key: Option<String>,
     },
 
+    /// Replace user keys by a new keypair and announce it to contacts.
+    RotateKey,
+
     /// Delete all messages associated with a contact.
     DeleteContactMessages {
         /// Onion ID of the contact whose messages should be deleted.
@@ -82,6 +97,41 @@ pub enum RpcCommand {
         onion_id: String,
     },
 
+    /// Get safety number of conversation with a contact.
+    GetSafetyNumber {
+        /// Onion ID of the contact.
+        onion_id: String,
+    },
+
+    /// Mark current public key of a contact as verified.
+    VerifyContact {
+        /// Onion ID of the contact.
+        onion_id: String,
+        /// Optional scanned QR payload, must match our safety number.
+        #[serde(default)]
+        qr_payload: Option<String>,
+    },
+
+    /// Remove verification of a contact.
+    UnverifyContact {
+        /// Onion ID of the contact.
+        onion_id: String,
+    },
+
+    /// Choose between signed and deniable handshakes with a contact.
+    SetContactDeniable {
+        /// Onion ID of the contact.
+        onion_id: String,
+        /// Use deniable handshakes.
+        deniable: bool,
+    },
+
+    /// Reset the encryption session with a contact.
+    ResetSession {
+        /// Onion ID of the contact.
+        onion_id: String,
+    },
+
     /// Reset the current Tor circuit.
     ResetTorCircuit,
 
@@ -121,6 +171,55 @@ pub enum RpcCommand {
         /// File path.
         path: String,
     },
+
+    /// Tell a contact the user started or stopped typing.
+    SetTyping {
+        /// Onion ID of the contact.
+        onion_id: String,
+        /// User is typing.
+        typing: bool,
+    },
+
+    /// Retry delivery of an unsent message right away.
+    RetryMessage {
+        /// Message id of the message.
+        message_id: String,
+    },
+
+    /// Stop delivery of an unsent message and delete it.
+    CancelMessage {
+        /// Message id of the message.
+        message_id: String,
+    },
+
+    /// Ask a peer which does not know us yet to add us as contact.
+    SendContactRequest {
+        /// Onion ID of the peer.
+        onion_id: String,
+        /// Nickname we introduce ourselves with.
+        nickname: String,
+        /// Short note to the peer.
+        #[serde(default)]
+        note: String,
+    },
+
+    /// List contact requests waiting for the user.
+    ListContactRequests,
+
+    /// Add the peer of a contact request as contact.
+    AcceptContactRequest {
+        /// Onion ID of the peer.
+        onion_id: String,
+        /// Nickname for the contact, the one of the request if omitted.
+        #[serde(default)]
+        nickname: Option<String>,
+    },
+
+    /// Delete a contact request.
+    RejectContactRequest {
+        /// Onion ID of the peer.
+        onion_id: String,
+    },
 }
 
 /// LoadContacts response.
@@ -156,6 +255,10 @@ impl SendRpcReply for SuccessResponse {}
 pub struct LoadUserResponse {
     /// User.
     pub user: serde_json::Value,
+    /// Signed prekey bundle to share with contacts.
+    pub prekey_bundle: String,
+    /// Onion address of user encodes its public key.
+    pub identity_mode: bool,
 }
 impl SendRpcReply for LoadUserResponse {}
 
@@ -176,9 +279,42 @@ pub struct SendAttachmentResponse {
     pub success: bool,
     /// Error message.
     pub error: String,
+    /// Message id of the queued attachment.
+    pub message_id: Option<String>,
 }
 impl SendRpcReply for SendAttachmentResponse {}
 
+/// SendMessage response.
+#[non_exhaustive]
+#[derive(serde::Serialize)]
+pub struct SendMessageResponse {
+    /// Message id of the queued message.
+    pub message_id: String,
+}
+impl SendRpcReply for SendMessageResponse {}
+
+/// ListContactRequests response.
+#[non_exhaustive]
+#[derive(serde::Serialize)]
+pub struct ListContactRequestsResponse {
+    /// Contact requests, newest first.
+    pub requests: Vec<serde_json::Value>,
+}
+impl SendRpcReply for ListContactRequestsResponse {}
+
+/// GetSafetyNumber response.
+#[non_exhaustive]
+#[derive(serde::Serialize)]
+pub struct GetSafetyNumberResponse {
+    /// Safety number as digits.
+    pub digits: String,
+    /// Safety number as QR payload.
+    pub qr_payload: String,
+    /// Whether the current key of contact is verified.
+    pub verified: bool,
+}
+impl SendRpcReply for GetSafetyNumberResponse {}
+
 /// Trait to define default behavior to send RPC reply.
 #[async_trait]
 pub trait SendRpcReply: serde::Serialize {
@@ -196,6 +332,8 @@ pub trait SendRpcReply: serde::Serialize {
 // Implementation of RpcCommand containing routing to correct method.
 impl RpcCommand {
     /// Route incoming RPC call to correct handler.
+    // One arm per command, the handlers contain the logic.
+    #[allow(clippy::cognitive_complexity)]
     pub async fn route(
         &self,
         tx_rpc: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
@@ -212,22 +350,25 @@ impl RpcCommand {
                 offset,
                 limit,
             } => {
-                self.handle_load_chat(onion_id, offset, limit, tx_rpc, client.db_conn.clone())
+                self.handle_load_chat(onion_id, offset, limit, tx_rpc, client)
                     .await
             }
             RpcCommand::SendMessage { to, text } => {
-                self.handle_send_message(to, text, tx_broadcast, client)
+                self.handle_send_message(to, text, tx_rpc, tx_broadcast, client)
                     .await
             }
             RpcCommand::AddContact {
                 nickname,
                 onion_id,
                 public_key,
+                identity_mode,
+                prekey_bundle,
             } => {
                 self.handle_add_contact(
                     nickname,
                     onion_id,
-                    public_key,
+                    Self::contact_public_key(onion_id, public_key.as_deref(), *identity_mode),
+                    prekey_bundle.as_deref(),
                     tx_rpc,
                     client.db_conn.clone(),
                 )
@@ -237,50 +378,62 @@ impl RpcCommand {
                 onion_id,
                 nickname,
                 public_key,
+                prekey_bundle,
             } => {
                 self.handle_update_contact(
                     onion_id,
                     nickname.as_deref(),
                     public_key.as_deref(),
+                    prekey_bundle.as_deref(),
                     tx_rpc,
                     client.db_conn.clone(),
                 )
                 .await
             }
-            RpcCommand::LoadUser => {
-                self.handle_load_user(
-                    &client.get_identity_unredacted()?,
-                    tx_rpc,
-                    client.db_conn.clone(),
-                )
-                .await
-            }
+            RpcCommand::LoadUser => self.handle_load_user(tx_rpc, client).await,
             RpcCommand::UpdateUser {
                 public_key,
                 private_key,
             } => {
                 self.handle_update_user(
-                    &client.get_identity_unredacted()?,
                     public_key.as_deref(),
                     private_key.as_deref(),
                     tx_rpc,
-                    client.db_conn.clone(),
+                    client,
                 )
                 .await
             }
+            RpcCommand::RotateKey => self.handle_rotate_key(tx_rpc, client).await,
             RpcCommand::DeleteContactMessages { onion_id } => {
                 self.handle_delete_contact_messages(onion_id, tx_rpc, client.db_conn.clone())
                     .await
             }
             RpcCommand::DeleteContact { onion_id } => {
-                self.handle_delete_contact(onion_id, tx_rpc, client.db_conn.clone())
+                self.handle_delete_contact(onion_id, tx_rpc, client).await
+            }
+            RpcCommand::GetSafetyNumber { onion_id } => {
+                self.handle_get_safety_number(onion_id, client, tx_rpc)
                     .await
             }
-            RpcCommand::ResetTorCircuit => self.handle_reset_tor_circuit(client, tx_rpc).await,
-            RpcCommand::DeleteAllContacts => {
-                self.handle_delete_all_contacts(tx_rpc, client.db_conn.clone())
+            RpcCommand::VerifyContact {
+                onion_id,
+                qr_payload,
+            } => {
+                self.handle_verify_contact(onion_id, qr_payload.as_deref(), client, tx_rpc)
                     .await
             }
+            RpcCommand::UnverifyContact { onion_id } => {
+                self.handle_unverify_contact(onion_id, client, tx_rpc).await
+            }
+            RpcCommand::SetContactDeniable { onion_id, deniable } => {
+                self.handle_set_contact_deniable(onion_id, *deniable, client, tx_rpc)
+                    .await
+            }
+            RpcCommand::ResetSession { onion_id } => {
+                self.handle_reset_session(onion_id, client, tx_rpc).await
+            }
+            RpcCommand::ResetTorCircuit => self.handle_reset_tor_circuit(client, tx_rpc).await,
+            RpcCommand::DeleteAllContacts => self.handle_delete_all_contacts(tx_rpc, client).await,
             RpcCommand::SendAppFocusState { focussed } => {
                 ui_focus::set_focussed(*focussed);
                 Ok(())
@@ -297,6 +450,44 @@ impl RpcCommand {
                 self.handle_send_attachment(to, path, tx_rpc, tx_broadcast, client)
                     .await
             }
+            RpcCommand::SetTyping { onion_id, typing } => {
+                self.handle_set_typing(onion_id, *typing, client, tx_rpc)
+                    .await
+            }
+            RpcCommand::RetryMessage { message_id } => {
+                self.handle_retry_message(message_id, tx_rpc, tx_broadcast, client)
+                    .await
+            }
+            RpcCommand::CancelMessage { message_id } => {
+                self.handle_cancel_message(message_id, tx_rpc, tx_broadcast, client)
+                    .await
+            }
+            RpcCommand::SendContactRequest {
+                onion_id,
+                nickname,
+                note,
+            } => {
+                self.handle_send_contact_request(onion_id, nickname, note, client, tx_rpc)
+                    .await
+            }
+            RpcCommand::ListContactRequests => {
+                self.handle_list_contact_requests(tx_rpc, client.db_conn.clone())
+                    .await
+            }
+            RpcCommand::AcceptContactRequest { onion_id, nickname } => {
+                self.handle_accept_contact_request(
+                    onion_id,
+                    nickname.as_deref(),
+                    tx_rpc,
+                    tx_broadcast,
+                    client.db_conn.clone(),
+                )
+                .await
+            }
+            RpcCommand::RejectContactRequest { onion_id } => {
+                self.handle_reject_contact_request(onion_id, tx_rpc, client.db_conn.clone())
+                    .await
+            }
         }
     }
 
@@ -327,10 +518,11 @@ impl RpcCommand {
         offset: &Option<usize>,
         limit: &Option<usize>,
         tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
-        db_conn: db::DatabaseConnection,
+        client: &client::Client,
     ) -> Result<(), RpcError> {
         let messages =
-            db::MessageDb::retrieve_messages(onion_id, offset, limit, db_conn.clone()).await?;
+            db::MessageDb::retrieve_messages(onion_id, offset, limit, client.db_conn.clone())
+                .await?;
 
         LoadChatResponse {
             messages: messages
@@ -338,7 +530,11 @@ impl RpcCommand {
                 .map(serde_json::to_value)
                 .collect::<Result<Vec<_>, _>>()?,
         }
-        .send_rpc_reply(tx)
+        .send_rpc_reply(tx)?;
+
+        // After the reply, sending a read receipt can take a while.
+        client.mark_chat_read(onion_id).await?;
+        Ok(())
     }
 
     /// Handler to send message.
@@ -346,60 +542,21 @@ impl RpcCommand {
         &self,
         to: &str,
         text: &str,
-        tx: &Option<tokio::sync::mpsc::UnboundedSender<MessageToUI>>,
+        tx_rpc: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+        tx_broadcast: &Option<tokio::sync::mpsc::UnboundedSender<MessageToUI>>,
         client: &client::Client,
     ) -> Result<(), RpcError> {
-        // Insert message into db.
+        // Reply once stored, the outbox task delivers the message.
         let message = MessageContent::Text {
             text: text.to_string(),
         };
-        let message_id = db::MessageDb {
-            id: 0,
-            contact_onion_id: to.to_string(),
-            body: serde_json::to_string(&message)?,
-            timestamp: chrono::Utc::now().timestamp() as i32,
-            is_incoming: false,
-            sent_status: false,
-            verified_status: false,
-        }
-        .insert(client.db_conn.clone())
-        .await?;
-
-        // Send message to peer.
-        if client
-            .send_message_to_peer(
-                to,
-                crate::message::MessageContent::Text {
-                    text: text.to_string(),
-                },
-            )
-            .await
-            .is_ok()
-        {
-            // Update sent status.
-            db::UpdateMessageDb {
-                id: message_id.expect_i64()?,
-                sent_status: Some(true),
-            }
-            .update(client.db_conn.clone())
-            .await?;
-        }
+        let message_id = client.queue_message(to, &message).await?;
 
-        // By sending a incoming message to the UI over broadcast, the UI will reload the chat.
-        #[derive(serde::Serialize)]
-        struct SendIncomingMessage {
-            /// HsId from peer we received this message from.
-            pub onion_id: String,
+        SendMessageResponse {
+            message_id: message_id.clone(),
         }
-        let incoming_message = SendIncomingMessage {
-            onion_id: to.to_string(),
-        };
-        let incoming_message = serde_json::to_string(&incoming_message)? + "\n";
-        if let Some(tx_broadcast) = tx {
-            let _ = tx_broadcast.send(MessageToUI::Broadcast(incoming_message));
-        }
-
-        Ok(())
+        .send_rpc_reply(tx_rpc)?;
+        notify_status(to, message_id, message::MessageStatus::Queued, tx_broadcast)
     }
 
     /// Handler to add new contact.
@@ -407,14 +564,26 @@ impl RpcCommand {
         &self,
         nickname: &str,
         onion_id: &str,
-        public_key: &str,
+        public_key: Option<String>,
+        prekey_bundle: Option<&str>,
         tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
         db_conn: db::DatabaseConnection,
     ) -> Result<(), RpcError> {
+        let Some(public_key) = public_key else {
+            return SuccessResponse { success: false }.send_rpc_reply(tx);
+        };
+
+        if prekey_bundle.is_some_and(|b| !Self::is_valid_prekey_bundle(onion_id, &public_key, b)) {
+            return SuccessResponse { success: false }.send_rpc_reply(tx);
+        }
+
         let success = db::ContactDb {
             onion_id: onion_id.into(),
             nickname: nickname.into(),
-            public_key: public_key.into(),
+            public_key,
+            prekey_bundle: prekey_bundle.map(|b| b.to_string()),
+            verified_key: None,
+            deniable: false,
             last_message_at: 0,
             last_viewed_at: chrono::Utc::now().timestamp() as i32,
             amount_unread_messages: 0,
@@ -426,19 +595,56 @@ impl RpcCommand {
         SuccessResponse { success }.send_rpc_reply(tx)
     }
 
+    /// Public key of a new contact.
+    /// A contact in identity mode has its key encoded in the address, others must give it.
+    fn contact_public_key(
+        onion_id: &str,
+        public_key: Option<&str>,
+        identity_mode: bool,
+    ) -> Option<String> {
+        let public_key = public_key.map(str::trim).filter(|pk| !pk.is_empty());
+        if !identity_mode {
+            return public_key.map(str::to_string);
+        }
+
+        let derived = hex::encode(
+            identity::public_key_from_onion_id(onion_id)
+                .ok()?
+                .as_bytes(),
+        );
+        public_key.is_none_or(|pk| pk == derived).then_some(derived)
+    }
+
     /// Handler to update existing contact.
     async fn handle_update_contact(
         &self,
         onion_id: &str,
         nickname: Option<&str>,
         public_key: Option<&str>,
+        prekey_bundle: Option<&str>,
         tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
         db_conn: db::DatabaseConnection,
     ) -> Result<(), RpcError> {
+        if let Some(bundle) = prekey_bundle {
+            // Bundle must be signed by the (new) public key of the contact.
+            let public_key = match public_key {
+                Some(pk) => pk.to_string(),
+                None => {
+                    db::ContactDb::retrieve(onion_id, db_conn.clone())
+                        .await?
+                        .public_key
+                }
+            };
+            if !Self::is_valid_prekey_bundle(onion_id, &public_key, bundle) {
+                return SuccessResponse { success: false }.send_rpc_reply(tx);
+            }
+        }
+
         let success = db::UpdateContactDb {
             onion_id: onion_id.into(),
             nickname: nickname.map(|n| n.to_string()),
             public_key: public_key.map(|pk| pk.to_string()),
+            prekey_bundle: prekey_bundle.map(|b| b.to_string()),
         }
         .update(db_conn.clone())
         .await
@@ -450,35 +656,59 @@ impl RpcCommand {
     /// Handler to load user of app.
     async fn handle_load_user(
         &self,
-        onion_id: &str,
         tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
-        db_conn: db::DatabaseConnection,
+        client: &client::Client,
     ) -> Result<(), RpcError> {
-        let user = db::UserDb::retrieve(onion_id, db_conn.clone()).await?;
+        let onion_id = client.get_identity_unredacted()?;
+        let user = db::UserDb::retrieve(&onion_id, client.db_conn.clone()).await?;
 
         LoadUserResponse {
             user: serde_json::to_value(user)?,
+            prekey_bundle: client.prekey_bundle().await?,
+            identity_mode: client.is_identity_mode(),
         }
         .send_rpc_reply(tx)
     }
 
+    /// Check if encoded prekey bundle is signed by contact.
+    fn is_valid_prekey_bundle(onion_id: &str, public_key: &str, bundle: &str) -> bool {
+        let Ok(public_key) = ratchet::verifying_key_from_hex(public_key) else {
+            return false;
+        };
+        prekey::PrekeyBundle::decode(bundle)
+            .and_then(|bundle| bundle.verify(onion_id, &public_key))
+            .is_ok()
+    }
+
     /// Handler to update user of app.
+    /// Keys are replaced by a rotation, so contacts learn the new key.
     async fn handle_update_user(
         &self,
-        onion_id: &str,
         public_key: Option<&str>,
         private_key: Option<&str>,
         tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
-        db_conn: db::DatabaseConnection,
+        client: &client::Client,
     ) -> Result<(), RpcError> {
-        let success = db::UpdateUserDb {
-            onion_id: onion_id.into(),
-            public_key: public_key.map(|pk| pk.to_string()),
-            private_key: private_key.map(|n| n.to_string()),
-        }
-        .update(db_conn.clone())
-        .await
-        .is_ok();
+        let success = match private_key {
+            Some(private_key) => client
+                .import_identity_key(private_key, public_key)
+                .await
+                .is_ok(),
+            // A public key alone would not match our private key.
+            None => public_key.is_none(),
+        };
+
+        SuccessResponse { success }.send_rpc_reply(tx)
+    }
+
+    /// Handler to rotate keys of user.
+    async fn handle_rotate_key(
+        &self,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+        client: &client::Client,
+    ) -> Result<(), RpcError> {
+        let new_private_key = ed25519_dalek::SigningKey::generate(&mut rand_core::OsRng);
+        let success = client.rotate_identity_key(new_private_key).await.is_ok();
 
         SuccessResponse { success }.send_rpc_reply(tx)
     }
@@ -501,14 +731,234 @@ impl RpcCommand {
         &self,
         onion_id: &str,
         tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+        client: &client::Client,
+    ) -> Result<(), RpcError> {
+        let success = db::ContactDb::delete(onion_id, client.db_conn.clone())
+            .await
+            .is_ok();
+        if success {
+            client.forget_sessions(Some(onion_id)).await;
+        }
+        SuccessResponse { success }.send_rpc_reply(tx)
+    }
+
+    /// Handler to get safety number of conversation with contact.
+    async fn handle_get_safety_number(
+        &self,
+        onion_id: &str,
+        client: &client::Client,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+    ) -> Result<(), RpcError> {
+        let safety_number = client.safety_number(onion_id).await?;
+        let contact = db::ContactDb::retrieve(onion_id, client.db_conn.clone()).await?;
+
+        GetSafetyNumberResponse {
+            digits: safety_number.digits,
+            qr_payload: safety_number.qr_payload,
+            verified: contact.is_verified(),
+        }
+        .send_rpc_reply(tx)
+    }
+
+    /// Handler to mark contact as verified.
+    async fn handle_verify_contact(
+        &self,
+        onion_id: &str,
+        qr_payload: Option<&str>,
+        client: &client::Client,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+    ) -> Result<(), RpcError> {
+        if let Some(qr_payload) = qr_payload {
+            let safety_number = client.safety_number(onion_id).await?;
+            if !safety_number.matches_qr_payload(qr_payload) {
+                return SuccessResponse { success: false }.send_rpc_reply(tx);
+            }
+        }
+
+        // Store the key itself, so verification is lost when the key of contact changes.
+        let contact = db::ContactDb::retrieve(onion_id, client.db_conn.clone()).await?;
+        let success = db::ContactDb::set_verified_key(
+            onion_id,
+            Some(&contact.public_key),
+            client.db_conn.clone(),
+        )
+        .await
+        .is_ok();
+
+        SuccessResponse { success }.send_rpc_reply(tx)
+    }
+
+    /// Handler to remove verification of contact.
+    async fn handle_unverify_contact(
+        &self,
+        onion_id: &str,
+        client: &client::Client,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+    ) -> Result<(), RpcError> {
+        let success = db::ContactDb::set_verified_key(onion_id, None, client.db_conn.clone())
+            .await
+            .is_ok();
+
+        SuccessResponse { success }.send_rpc_reply(tx)
+    }
+
+    /// Handler to choose handshake of contact.
+    async fn handle_set_contact_deniable(
+        &self,
+        onion_id: &str,
+        deniable: bool,
+        client: &client::Client,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+    ) -> Result<(), RpcError> {
+        let success = client
+            .set_contact_deniable(onion_id, deniable)
+            .await
+            .is_ok();
+        SuccessResponse { success }.send_rpc_reply(tx)
+    }
+
+    /// Handler to send typing indicator to contact.
+    async fn handle_set_typing(
+        &self,
+        onion_id: &str,
+        typing: bool,
+        client: &client::Client,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+    ) -> Result<(), RpcError> {
+        let success = client.set_typing(onion_id, typing).await.is_ok();
+        SuccessResponse { success }.send_rpc_reply(tx)
+    }
+
+    /// Handler to retry delivery of an unsent message.
+    async fn handle_retry_message(
+        &self,
+        message_id: &str,
+        tx_rpc: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+        tx_broadcast: &Option<tokio::sync::mpsc::UnboundedSender<MessageToUI>>,
+        client: &client::Client,
+    ) -> Result<(), RpcError> {
+        let Some(msg) = Self::unsent_message(message_id, client).await? else {
+            return SuccessResponse { success: false }.send_rpc_reply(tx_rpc);
+        };
+        client.retry_message(&msg).await?;
+        SuccessResponse { success: true }.send_rpc_reply(tx_rpc)?;
+        notify_status(
+            &msg.contact_onion_id,
+            msg.message_id,
+            message::MessageStatus::Queued,
+            tx_broadcast,
+        )
+    }
+
+    /// Handler to cancel delivery of an unsent message.
+    async fn handle_cancel_message(
+        &self,
+        message_id: &str,
+        tx_rpc: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+        tx_broadcast: &Option<tokio::sync::mpsc::UnboundedSender<MessageToUI>>,
+        client: &client::Client,
+    ) -> Result<(), RpcError> {
+        let Some(msg) = Self::unsent_message(message_id, client).await? else {
+            return SuccessResponse { success: false }.send_rpc_reply(tx_rpc);
+        };
+
+        let success = db::MessageDb::cancel(&msg.message_id, client.db_conn.clone()).await?;
+        SuccessResponse { success }.send_rpc_reply(tx_rpc)?;
+        notify_chat_changed(&msg.contact_onion_id, tx_broadcast)
+    }
+
+    /// Outgoing message which was not sent yet.
+    async fn unsent_message(
+        message_id: &str,
+        client: &client::Client,
+    ) -> Result<Option<db::MessageDb>, RpcError> {
+        Ok(
+            db::MessageDb::retrieve_by_message_id(message_id, client.db_conn.clone())
+                .await?
+                .filter(|msg| !msg.is_incoming && !msg.sent_status),
+        )
+    }
+
+    /// Handler to send contact request to peer.
+    async fn handle_send_contact_request(
+        &self,
+        onion_id: &str,
+        nickname: &str,
+        note: &str,
+        client: &client::Client,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+    ) -> Result<(), RpcError> {
+        let success = !nickname.trim().is_empty()
+            && client
+                .send_contact_request(onion_id, nickname.trim(), note.trim())
+                .await
+                .is_ok();
+        SuccessResponse { success }.send_rpc_reply(tx)
+    }
+
+    /// Handler to list contact requests.
+    async fn handle_list_contact_requests(
+        &self,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
         db_conn: db::DatabaseConnection,
     ) -> Result<(), RpcError> {
-        let success = db::ContactDb::delete(onion_id, db_conn.clone())
+        let requests =
+            db::ContactRequestDb::retrieve_all(Some("received_at"), None, db_conn.clone()).await?;
+
+        ListContactRequestsResponse {
+            requests: requests
+                .into_iter()
+                .map(serde_json::to_value)
+                .collect::<Result<Vec<_>, _>>()?,
+        }
+        .send_rpc_reply(tx)
+    }
+
+    /// Handler to accept contact request, adding its peer as contact.
+    async fn handle_accept_contact_request(
+        &self,
+        onion_id: &str,
+        nickname: Option<&str>,
+        tx_rpc: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+        tx_broadcast: &Option<tokio::sync::mpsc::UnboundedSender<MessageToUI>>,
+        db_conn: db::DatabaseConnection,
+    ) -> Result<(), RpcError> {
+        let nickname = nickname.map(str::trim).filter(|n| !n.is_empty());
+        let success = db::ContactRequestDb::accept(onion_id, nickname, db_conn.clone())
+            .await
+            .unwrap_or(false);
+        SuccessResponse { success }.send_rpc_reply(tx_rpc)?;
+
+        if success {
+            notify_chat_changed(onion_id, tx_broadcast)?;
+        }
+        Ok(())
+    }
+
+    /// Handler to reject contact request.
+    async fn handle_reject_contact_request(
+        &self,
+        onion_id: &str,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+        db_conn: db::DatabaseConnection,
+    ) -> Result<(), RpcError> {
+        let success = db::ContactRequestDb::delete(onion_id, db_conn.clone())
             .await
             .is_ok();
         SuccessResponse { success }.send_rpc_reply(tx)
     }
 
+    /// Handler to reset session with contact.
+    async fn handle_reset_session(
+        &self,
+        onion_id: &str,
+        client: &client::Client,
+        tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
+    ) -> Result<(), RpcError> {
+        let success = client.reset_session(onion_id).await.is_ok();
+        SuccessResponse { success }.send_rpc_reply(tx)
+    }
+
     /// Handler to reset Tor circuit.
     async fn handle_reset_tor_circuit(
         &self,
@@ -523,9 +973,14 @@ impl RpcCommand {
     async fn handle_delete_all_contacts(
         &self,
         tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>,
-        db_conn: db::DatabaseConnection,
+        client: &client::Client,
     ) -> Result<(), RpcError> {
-        let success = db::ContactDb::delete_all(db_conn.clone()).await.is_ok();
+        let success = db::ContactDb::delete_all(client.db_conn.clone())
+            .await
+            .is_ok();
+        if success {
+            client.forget_sessions(None).await;
+        }
         SuccessResponse { success }.send_rpc_reply(tx)
     }
 
@@ -583,33 +1038,46 @@ impl RpcCommand {
         tx_broadcast: &Option<tokio::sync::mpsc::UnboundedSender<MessageToUI>>,
         client: &client::Client,
     ) -> Result<(), RpcError> {
-        let client_config = client.config.lock().await;
-        if !client_config.enable_attachments {
+        let enable_attachments = client.config.lock().await.enable_attachments;
+        let unavailable = if !enable_attachments {
+            Some(error::AttachmentError::DisabledInSettings)
+        } else if !client
+            .peer_supports(to, envelope::Capability::Attachments)
+            .await
+        {
+            Some(error::AttachmentError::UnsupportedByContact)
+        } else {
+            None
+        };
+        if let Some(e) = unavailable {
             let _ = SendAttachmentResponse {
                 success: false,
-                error: "Sending attachments is disabled in settings.".to_string(),
+                error: e.to_string(),
+                message_id: None,
             }
             .send_rpc_reply(tx_rpc);
 
             // Insert error message.
             let error_message = MessageContent::Error {
-                message: "Sending attachments is disabled in settings.".to_string(),
+                message: e.to_string(),
             };
             let _ = db::MessageDb {
                 id: 0,
+                message_id: message::new_message_id(),
                 contact_onion_id: to.to_string(),
                 body: serde_json::to_string(&error_message)?,
                 timestamp: chrono::Utc::now().timestamp() as i32,
                 is_incoming: false,
                 sent_status: true,
                 verified_status: true,
+                delivered_at: None,
+                read_at: None,
+                failed: false,
             }
             .insert(client.db_conn.clone())
             .await?;
 
-            return Err(error::RpcError::AttachmentError(
-                error::AttachmentError::DisabledInSettings,
-            ));
+            return Err(error::RpcError::AttachmentError(e));
         }
 
         let image_bytes = match attachment::reencode_image_to_bytes(path) {
@@ -618,6 +1086,7 @@ impl RpcCommand {
                 let _ = SendAttachmentResponse {
                     success: false,
                     error: e.to_string(),
+                    message_id: None,
                 }
                 .send_rpc_reply(tx_rpc);
 
@@ -627,12 +1096,16 @@ impl RpcCommand {
                 };
                 let _ = db::MessageDb {
                     id: 0,
+                    message_id: message::new_message_id(),
                     contact_onion_id: to.to_string(),
                     body: serde_json::to_string(&error_message)?,
                     timestamp: chrono::Utc::now().timestamp() as i32,
                     is_incoming: false,
                     sent_status: true,
                     verified_status: true,
+                    delivered_at: None,
+                    read_at: None,
+                    failed: false,
                 }
                 .insert(client.db_conn.clone())
                 .await?;
@@ -642,49 +1115,106 @@ impl RpcCommand {
         };
         let message = MessageContent::Image { data: image_bytes };
 
-        // Insert message into db.
-        let message_id = db::MessageDb {
-            id: 0,
-            contact_onion_id: to.to_string(),
-            body: serde_json::to_string(&message)?,
-            timestamp: chrono::Utc::now().timestamp() as i32,
-            is_incoming: false,
-            sent_status: false,
-            verified_status: false,
-        }
-        .insert(client.db_conn.clone())
-        .await?;
-
-        // Send message to peer.
-        if client.send_message_to_peer(to, message).await.is_ok() {
-            // Update sent status.
-            db::UpdateMessageDb {
-                id: message_id.expect_i64()?,
-                sent_status: Some(true),
-            }
-            .update(client.db_conn.clone())
-            .await?;
-        }
+        // Reply once stored, the outbox task delivers the attachment.
+        let message_id = client.queue_message(to, &message).await?;
 
-        // By sending a incoming message to the UI over broadcast, the UI will reload the chat.
-        #[derive(serde::Serialize)]
-        struct SendIncomingMessage {
-            /// HsId from peer we received this message from.
-            pub onion_id: String,
-        }
-        let incoming_message = SendIncomingMessage {
-            onion_id: to.to_string(),
-        };
-        let incoming_message = serde_json::to_string(&incoming_message)? + "\n";
-        if let Some(tx_broadcast) = tx_broadcast {
-            let _ = tx_broadcast.send(MessageToUI::Broadcast(incoming_message));
+        SendAttachmentResponse {
+            success: true,
+            error: String::new(),
+            message_id: Some(message_id.clone()),
         }
+        .send_rpc_reply(tx_rpc)?;
+        notify_status(to, message_id, message::MessageStatus::Queued, tx_broadcast)
+    }
+}
 
-        Ok(())
+/// By sending a incoming message to the UI over broadcast, the UI will reload the chat.
+fn notify_chat_changed(
+    onion_id: &str,
+    tx: &Option<tokio::sync::mpsc::UnboundedSender<MessageToUI>>,
+) -> Result<(), RpcError> {
+    #[derive(serde::Serialize)]
+    struct SendIncomingMessage {
+        /// HsId from peer we received this message from.
+        pub onion_id: String,
     }
+    let incoming_message = SendIncomingMessage {
+        onion_id: onion_id.to_string(),
+    };
+    let incoming_message = serde_json::to_string(&incoming_message)? + "\n";
+    if let Some(tx_broadcast) = tx {
+        let _ = tx_broadcast.send(MessageToUI::Broadcast(incoming_message));
+    }
+
+    Ok(())
+}
+
+/// Tell the UI over broadcast that the status of our message changed.
+fn notify_status(
+    onion_id: &str,
+    message_id: String,
+    status: message::MessageStatus,
+    tx: &Option<tokio::sync::mpsc::UnboundedSender<MessageToUI>>,
+) -> Result<(), RpcError> {
+    let status_changed = ipc::MessageStatusChanged {
+        onion_id: onion_id.to_string(),
+        message_ids: vec![message_id],
+        status,
+    };
+    let status_changed = serde_json::to_string(&status_changed)? + "\n";
+    if let Some(tx_broadcast) = tx {
+        let _ = tx_broadcast.send(MessageToUI::Broadcast(status_changed));
+    }
+
+    Ok(())
 }
 
 /// Send error as reply.
 pub fn reply_rpc_error(tx: &tokio::sync::mpsc::UnboundedSender<MessageToUI>, err: &RpcError) {
     let _ = tx.send(MessageToUI::Rpc(format!(r#"{{"error":"{err}"}}\n"#)));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_change_is_broadcast_as_line() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        notify_status(
+            "alice.onion",
+            "id".into(),
+            message::MessageStatus::Queued,
+            &Some(tx),
+        )
+        .expect("notify status");
+
+        let Ok(MessageToUI::Broadcast(line)) = rx.try_recv() else {
+            panic!("no broadcast");
+        };
+        let line = line.strip_suffix('\n').expect("line ends with newline");
+        let value: serde_json::Value = serde_json::from_str(line).expect("parse broadcast");
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "type": "message_status",
+                "onion_id": "alice.onion",
+                "message_ids": ["id"],
+                "status": "queued",
+            })
+        );
+    }
+
+    #[test]
+    fn status_change_without_broadcast_is_dropped() {
+        assert!(
+            notify_status(
+                "alice.onion",
+                "id".into(),
+                message::MessageStatus::Sent,
+                &None
+            )
+            .is_ok()
+        );
+    }
+}
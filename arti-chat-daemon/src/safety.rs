@@ -0,0 +1,154 @@
+//! Safety numbers to verify a contact out of band.
+//! Both parties compute the same number from their identity keys and onion ids.
+
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha512};
+
+/// Version of safety number format.
+const SAFETY_NUMBER_VERSION: u8 = 1;
+
+/// Hash iterations, making it expensive to find a key with the same fingerprint.
+const FINGERPRINT_ITERATIONS: usize = 5200;
+
+/// Bytes of fingerprint used per party, 5 bytes per group of 5 digits.
+/// Only the QR payload carries all of them, see [`digit_group`].
+const FINGERPRINT_BYTES: usize = 30;
+
+/// Prefix of QR payload.
+const QR_PAYLOAD_PREFIX: &str = "arti-chat-safety";
+
+/// Safety number of the conversation between two users.
+#[non_exhaustive]
+#[derive(serde::Serialize)]
+pub struct SafetyNumber {
+    /// 60 digits in groups of five.
+    pub digits: String,
+    /// Payload to show as QR code and scan with the device of the contact.
+    pub qr_payload: String,
+}
+
+impl SafetyNumber {
+    /// Compute safety number of conversation of self with peer.
+    pub fn new(
+        self_onion_id: &str,
+        self_public_key: &VerifyingKey,
+        peer_onion_id: &str,
+        peer_public_key: &VerifyingKey,
+    ) -> Self {
+        let own = fingerprint(self_onion_id, self_public_key);
+        let peer = fingerprint(peer_onion_id, peer_public_key);
+
+        // Sort so both parties get the same number.
+        let (first, second) = if own <= peer {
+            (own, peer)
+        } else {
+            (peer, own)
+        };
+
+        let digits = first
+            .chunks(5)
+            .chain(second.chunks(5))
+            .map(digit_group)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let qr_payload = format!(
+            "{QR_PAYLOAD_PREFIX}:{SAFETY_NUMBER_VERSION}:{}{}",
+            hex::encode(first),
+            hex::encode(second)
+        );
+
+        Self { digits, qr_payload }
+    }
+
+    /// Check if scanned QR payload matches this safety number.
+    pub fn matches_qr_payload(&self, qr_payload: &str) -> bool {
+        self.qr_payload == qr_payload.trim()
+    }
+}
+
+/// Group of 5 digits shown for 5 bytes of a fingerprint.
+/// Five digits hold about 16.6 bits, so reducing the 40 bits of the chunk is lossy on
+/// purpose: the 6 groups of a party still carry about 99.7 bits. The reduction is
+/// biased by less than 10^5 / 2^40, which is negligible.
+fn digit_group(chunk: &[u8]) -> String {
+    let value = chunk
+        .iter()
+        .fold(0_u64, |acc, b| (acc << 8) | u64::from(*b));
+    format!("{:05}", value % 100_000)
+}
+
+/// Iterated hash over identity key and onion id of one party.
+fn fingerprint(onion_id: &str, public_key: &VerifyingKey) -> [u8; FINGERPRINT_BYTES] {
+    let mut hash = Sha512::new()
+        .chain_update([SAFETY_NUMBER_VERSION])
+        .chain_update(public_key.as_bytes())
+        .chain_update(onion_id.as_bytes())
+        .finalize();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(public_key.as_bytes())
+            .finalize();
+    }
+
+    let mut fingerprint = [0_u8; FINGERPRINT_BYTES];
+    fingerprint.copy_from_slice(&hash[..FINGERPRINT_BYTES]);
+    fingerprint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Random identity key.
+    fn key() -> VerifyingKey {
+        ed25519_dalek::SigningKey::generate(&mut rand_core::OsRng).verifying_key()
+    }
+
+    #[test]
+    fn both_parties_compute_the_same_number() {
+        let (alice, bob) = (key(), key());
+        let of_alice = SafetyNumber::new("alice.onion", &alice, "bob.onion", &bob);
+        let of_bob = SafetyNumber::new("bob.onion", &bob, "alice.onion", &alice);
+        assert_eq!(of_alice.digits, of_bob.digits);
+        assert_eq!(of_alice.qr_payload, of_bob.qr_payload);
+
+        let groups: Vec<&str> = of_alice.digits.split(' ').collect();
+        assert_eq!(groups.len(), 12);
+        assert!(
+            groups
+                .iter()
+                .all(|group| group.len() == 5 && group.bytes().all(|b| b.is_ascii_digit()))
+        );
+    }
+
+    #[test]
+    fn digit_group_reduces_five_bytes() {
+        assert_eq!(digit_group(&[0; 5]), "00000");
+        assert_eq!(digit_group(&[0, 0, 0, 1, 0]), "00256");
+        // 2^40 - 1
+        assert_eq!(digit_group(&[0xff; 5]), "27775");
+    }
+
+    #[test]
+    fn number_changes_with_key_or_onion_id() {
+        let (alice, bob) = (key(), key());
+        let number = SafetyNumber::new("alice.onion", &alice, "bob.onion", &bob);
+        let other_key = SafetyNumber::new("alice.onion", &alice, "bob.onion", &key());
+        let other_onion_id = SafetyNumber::new("alice.onion", &alice, "mallory.onion", &bob);
+        assert_ne!(number.digits, other_key.digits);
+        assert_ne!(number.digits, other_onion_id.digits);
+    }
+
+    #[test]
+    fn scanned_qr_payload_of_contact_matches() {
+        let (alice, bob) = (key(), key());
+        let of_alice = SafetyNumber::new("alice.onion", &alice, "bob.onion", &bob);
+        let of_bob = SafetyNumber::new("bob.onion", &bob, "alice.onion", &alice);
+        assert!(of_alice.matches_qr_payload(&format!("{}\n", of_bob.qr_payload)));
+
+        let of_mallory = SafetyNumber::new("bob.onion", &key(), "alice.onion", &alice);
+        assert!(!of_alice.matches_qr_payload(&of_mallory.qr_payload));
+    }
+}
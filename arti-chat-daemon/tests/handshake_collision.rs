@@ -0,0 +1,177 @@
+//! Simultaneous handshake initiation by two peers.
+
+use arti_chat_daemon::{
+    collision::{PendingHandshakes, Resolution, initiator_wins},
+    ratchet::{Handshake, HandshakeOptions, RatchetChain},
+};
+use ed25519_dalek::SigningKey;
+
+/// Onion id that sorts before `HIGH`.
+const LOW: &str = "aaaa.onion";
+/// Onion id that sorts after `LOW`.
+const HIGH: &str = "zzzz.onion";
+
+/// One side of the simulation.
+struct Peer {
+    onion_id: &'static str,
+    key: SigningKey,
+    pending: PendingHandshakes,
+}
+
+impl Peer {
+    fn new(onion_id: &'static str) -> Self {
+        Self {
+            onion_id,
+            key: SigningKey::generate(&mut rand_core::OsRng),
+            pending: PendingHandshakes::default(),
+        }
+    }
+}
+
+/// Check both sessions decrypt what the other encrypts.
+fn assert_converged(a: &mut RatchetChain, a_onion: &str, b: &mut RatchetChain, b_onion: &str) {
+    let msg = a.encrypt(b"ping", a_onion.into());
+    assert_eq!(b.decrypt(&msg).expect("decrypt ping"), b"ping");
+    let msg = b.encrypt(b"pong", b_onion.into());
+    assert_eq!(a.decrypt(&msg).expect("decrypt pong"), b"pong");
+}
+
+/// Both peers send a handshake before receiving the one of the other.
+/// Returns sessions of (low, high).
+fn simultaneous_initiation(low: &mut Peer, high: &mut Peer) -> (RatchetChain, RatchetChain) {
+    let options = HandshakeOptions::default();
+
+    let (low_init, low_secret) = Handshake::initiate(
+        low.onion_id,
+        high.onion_id,
+        &high.key.verifying_key(),
+        &low.key,
+        options,
+    );
+    low.pending.start(high.onion_id, low_init.nonce);
+
+    let (high_init, _high_secret) = Handshake::initiate(
+        high.onion_id,
+        low.onion_id,
+        &low.key.verifying_key(),
+        &high.key,
+        options,
+    );
+    high.pending.start(low.onion_id, high_init.nonce);
+
+    // Both handshakes cross on the wire.
+    assert_eq!(
+        low.pending.resolve(low.onion_id, high.onion_id),
+        Resolution::Reject
+    );
+    assert_eq!(
+        high.pending.resolve(high.onion_id, low.onion_id),
+        Resolution::Accept
+    );
+
+    // High answers the handshake of low as responder.
+    let (reply, reply_secret) = low_init
+        .accept(high.onion_id, &low.key.verifying_key(), &high.key, options)
+        .expect("accept handshake of low");
+    let high_session = low_init
+        .complete(
+            high.onion_id,
+            &low.key.verifying_key(),
+            &reply_secret,
+            false,
+        )
+        .expect("complete as responder");
+
+    // Low receives the reply and keeps its initiation.
+    assert!(low.pending.finish(high.onion_id, low_init.nonce));
+    let low_session = reply
+        .complete(low.onion_id, &high.key.verifying_key(), &low_secret, true)
+        .expect("complete as initiator");
+
+    // High never gets a reply and drops its own initiation.
+    assert!(!high.pending.finish(low.onion_id, high_init.nonce));
+
+    (low_session, high_session)
+}
+
+#[test]
+fn lowest_onion_id_wins() {
+    assert!(initiator_wins(LOW, HIGH));
+    assert!(!initiator_wins(HIGH, LOW));
+}
+
+#[test]
+fn simultaneous_initiation_converges() {
+    let mut low = Peer::new(LOW);
+    let mut high = Peer::new(HIGH);
+
+    let (mut low_session, mut high_session) = simultaneous_initiation(&mut low, &mut high);
+    assert_converged(&mut low_session, LOW, &mut high_session, HIGH);
+}
+
+#[test]
+fn repeated_collisions_converge() {
+    let mut low = Peer::new(LOW);
+    let mut high = Peer::new(HIGH);
+
+    for _ in 0..3 {
+        let (mut low_session, mut high_session) = simultaneous_initiation(&mut low, &mut high);
+        assert_converged(&mut high_session, HIGH, &mut low_session, LOW);
+    }
+}
+
+#[test]
+fn handshake_without_collision_is_accepted() {
+    let mut low = Peer::new(LOW);
+    let mut high = Peer::new(HIGH);
+
+    // Only high initiates, so low answers even though it would win a collision.
+    let (init, _secret) = Handshake::initiate(
+        HIGH,
+        LOW,
+        &low.key.verifying_key(),
+        &high.key,
+        HandshakeOptions::default(),
+    );
+    high.pending.start(LOW, init.nonce);
+
+    assert_eq!(low.pending.resolve(LOW, HIGH), Resolution::Accept);
+    assert!(high.pending.finish(LOW, init.nonce));
+}
+
+#[test]
+fn collision_after_reply_is_accepted() {
+    let mut low = Peer::new(LOW);
+
+    // Initiation of low already finished before the handshake of high arrives.
+    low.pending.start(HIGH, [1; 16]);
+    assert!(low.pending.finish(HIGH, [1; 16]));
+    assert_eq!(low.pending.resolve(LOW, HIGH), Resolution::Accept);
+}
+
+#[test]
+fn replaced_initiation_is_discarded() {
+    let mut pending = PendingHandshakes::default();
+
+    pending.start(HIGH, [1; 16]);
+    pending.start(HIGH, [2; 16]);
+
+    // Reply to the older initiation arrives after it was replaced.
+    assert!(!pending.finish(HIGH, [1; 16]));
+    assert!(pending.finish(HIGH, [2; 16]));
+}
+
+#[test]
+fn collisions_are_tracked_per_peer() {
+    let own = "mmmm.onion";
+    let mut pending = PendingHandshakes::default();
+
+    pending.start(LOW, [1; 16]);
+    pending.start(HIGH, [2; 16]);
+
+    // Losing against one peer leaves the initiation to another peer untouched.
+    assert_eq!(pending.resolve(own, LOW), Resolution::Accept);
+    assert_eq!(pending.resolve(own, HIGH), Resolution::Reject);
+    assert!(!pending.finish(LOW, [1; 16]));
+    assert!(pending.finish(HIGH, [2; 16]));
+}
@@ -31,12 +31,12 @@ pub async fn load_chat(
 }
 
 #[tauri::command]
-pub async fn send_message(to: String, text: String) -> Result<(), String> {
-    rpc::SendMessage { to, text }
-        .send()
+pub async fn send_message(to: String, text: String) -> Result<String, String> {
+    let response = rpc::SendMessage { to, text }
+        .receive()
         .await
         .map_err(|e| format!("send_message failed: {e}"))?;
-    Ok(())
+    Ok(response.message_id)
 }
 
 #[tauri::command]
@@ -44,11 +44,13 @@ pub async fn add_contact(
     nickname: String,
     onion_id: String,
     public_key: String,
+    identity_mode: bool,
 ) -> Result<bool, String> {
     let response = rpc::AddContact {
         nickname,
         onion_id,
         public_key,
+        identity_mode,
     }
     .receive()
     .await
@@ -208,3 +210,77 @@ pub async fn send_attachment(to: String, path: String) -> Result<SendAttachmentR
         .await
         .map_err(|e| format!("send_attachment failed: {e}"))
 }
+
+#[tauri::command]
+pub async fn set_typing(onion_id: String, typing: bool) -> Result<bool, String> {
+    let response = rpc::SetTyping { onion_id, typing }
+        .receive()
+        .await
+        .map_err(|e| format!("set_typing failed: {e}"))?;
+    Ok(response.success)
+}
+
+#[tauri::command]
+pub async fn retry_message(message_id: String) -> Result<bool, String> {
+    let response = rpc::RetryMessage { message_id }
+        .receive()
+        .await
+        .map_err(|e| format!("retry_message failed: {e}"))?;
+    Ok(response.success)
+}
+
+#[tauri::command]
+pub async fn cancel_message(message_id: String) -> Result<bool, String> {
+    let response = rpc::CancelMessage { message_id }
+        .receive()
+        .await
+        .map_err(|e| format!("cancel_message failed: {e}"))?;
+    Ok(response.success)
+}
+
+#[tauri::command]
+pub async fn send_contact_request(
+    onion_id: String,
+    nickname: String,
+    note: String,
+) -> Result<bool, String> {
+    let response = rpc::SendContactRequest {
+        onion_id,
+        nickname,
+        note,
+    }
+    .receive()
+    .await
+    .map_err(|e| format!("send_contact_request failed: {e}"))?;
+    Ok(response.success)
+}
+
+#[tauri::command]
+pub async fn list_contact_requests() -> Result<Vec<model::ContactRequest>, String> {
+    let response = rpc::ListContactRequests {}
+        .receive()
+        .await
+        .map_err(|e| format!("list_contact_requests failed: {e}"))?;
+    Ok(response.requests)
+}
+
+#[tauri::command]
+pub async fn accept_contact_request(
+    onion_id: String,
+    nickname: Option<String>,
+) -> Result<bool, String> {
+    let response = rpc::AcceptContactRequest { onion_id, nickname }
+        .receive()
+        .await
+        .map_err(|e| format!("accept_contact_request failed: {e}"))?;
+    Ok(response.success)
+}
+
+#[tauri::command]
+pub async fn reject_contact_request(onion_id: String) -> Result<bool, String> {
+    let response = rpc::RejectContactRequest { onion_id }
+        .receive()
+        .await
+        .map_err(|e| format!("reject_contact_request failed: {e}"))?;
+    Ok(response.success)
+}
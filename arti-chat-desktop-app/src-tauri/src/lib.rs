@@ -89,6 +89,13 @@ pub fn run() {
             commands::ping_daemon,
             commands::restart_daemon,
             commands::send_attachment,
+            commands::set_typing,
+            commands::retry_message,
+            commands::cancel_message,
+            commands::send_contact_request,
+            commands::list_contact_requests,
+            commands::accept_contact_request,
+            commands::reject_contact_request,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
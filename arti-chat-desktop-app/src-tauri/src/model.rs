@@ -13,12 +13,26 @@ pub struct Contact {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Message {
     pub id: i64,
+    pub message_id: String,
     pub contact_onion_id: String,
     pub body: String,
     pub timestamp: i32,
     pub is_incoming: bool,
     pub sent_status: bool,
     pub verified_status: bool,
+    pub delivered_at: Option<i64>,
+    pub read_at: Option<i64>,
+    pub failed: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ContactRequest {
+    pub onion_id: String,
+    pub public_key: String,
+    pub nickname: String,
+    pub note: String,
+    pub received_at: i64,
+    pub verified: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -40,7 +40,13 @@ pub struct SendMessage {
     pub text: String,
 }
 
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct SendMessageResponse {
+    pub message_id: String,
+}
+
 impl SendRpcCommand for SendMessage {}
+impl ReceiveRpcReply<SendMessageResponse> for SendMessage {}
 
 /// General success response for calls only returning a success field.
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -54,6 +60,7 @@ pub struct AddContact {
     pub nickname: String,
     pub onion_id: String,
     pub public_key: String,
+    pub identity_mode: bool,
 }
 
 impl SendRpcCommand for AddContact {}
@@ -180,11 +187,82 @@ pub struct SendAttachment {
 pub struct SendAttachmentResponse {
     pub success: bool,
     pub error: String,
+    pub message_id: Option<String>,
 }
 
 impl SendRpcCommand for SendAttachment {}
 impl ReceiveRpcReply<SendAttachmentResponse> for SendAttachment {}
 
+/// --- Set typing ---
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SetTyping {
+    pub onion_id: String,
+    pub typing: bool,
+}
+
+impl SendRpcCommand for SetTyping {}
+impl ReceiveRpcReply<SuccessResponse> for SetTyping {}
+
+/// --- Retry message ---
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RetryMessage {
+    pub message_id: String,
+}
+
+impl SendRpcCommand for RetryMessage {}
+impl ReceiveRpcReply<SuccessResponse> for RetryMessage {}
+
+/// --- Cancel message ---
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CancelMessage {
+    pub message_id: String,
+}
+
+impl SendRpcCommand for CancelMessage {}
+impl ReceiveRpcReply<SuccessResponse> for CancelMessage {}
+
+/// --- Send contact request ---
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SendContactRequest {
+    pub onion_id: String,
+    pub nickname: String,
+    pub note: String,
+}
+
+impl SendRpcCommand for SendContactRequest {}
+impl ReceiveRpcReply<SuccessResponse> for SendContactRequest {}
+
+/// --- List contact requests ---
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ListContactRequests {}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct ListContactRequestsResponse {
+    pub requests: Vec<model::ContactRequest>,
+}
+
+impl SendRpcCommand for ListContactRequests {}
+impl ReceiveRpcReply<ListContactRequestsResponse> for ListContactRequests {}
+
+/// --- Accept contact request ---
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AcceptContactRequest {
+    pub onion_id: String,
+    pub nickname: Option<String>,
+}
+
+impl SendRpcCommand for AcceptContactRequest {}
+impl ReceiveRpcReply<SuccessResponse> for AcceptContactRequest {}
+
+/// --- Reject contact request ---
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RejectContactRequest {
+    pub onion_id: String,
+}
+
+impl SendRpcCommand for RejectContactRequest {}
+impl ReceiveRpcReply<SuccessResponse> for RejectContactRequest {}
+
 /// Trait to send types as RPC command.
 #[async_trait]
 pub trait SendRpcCommand: Sized + serde::Serialize {
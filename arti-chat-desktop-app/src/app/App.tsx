@@ -4,6 +4,7 @@ import { invoke } from "@tauri-apps/api/core";
 
 import { Contact, useContacts } from "./hooks/useContacts";
 import { useChat } from "./hooks/useChat";
+import { useContactRequests } from "./hooks/useContactRequests";
 import { useHiddenServicePing } from "./hooks/usePingHiddenService";
 import { useDaemonPing } from "./hooks/usePingDaemon";
 
@@ -12,6 +13,7 @@ import ContactList from "./components/Contacts/ContactList";
 import Loading from "./screens/Loading/Loading";
 import Welcome from "./screens/Welcome/Welcome";
 import AddContact from "./screens/AddContact/AddContact";
+import ContactRequests from "./screens/ContactRequests/ContactRequests";
 import ContactDetails from "./screens/ContactDetails/ContactDetails";
 import UserDetails from "./screens/UserDetails/UserDetails";
 import Settings from "./screens/Settings/Settings";
@@ -34,7 +36,8 @@ const App = () => {
     const { daemonIsReachable, setDaemonIsReachable } = useDaemonPing();
     const { hsIsReachable } = useHiddenServicePing();
     const [messageBatchNumber, setMessageBatchNumber] = useState(1);
-    const {messages, sendMessage, sendAttachment } = useChat({activeContact: activeContact, loadContacts: loadContacts, messageBatchNumber: messageBatchNumber });
+    const { contactRequests, sendContactRequest, acceptContactRequest, rejectContactRequest } = useContactRequests({loadContacts: loadContacts});
+    const {messages, sendMessage, sendAttachment, retryMessage, cancelMessage, sendingIds, contactTyping, setTyping } = useChat({activeContact: activeContact, loadContacts: loadContacts, messageBatchNumber: messageBatchNumber });
 
     // Load contacts once on mount.
     useEffect(() => {
@@ -72,6 +75,11 @@ const App = () => {
                             messages={messages}
                             sendMessage={sendMessage}
                             sendAttachment={sendAttachment}
+                            retryMessage={retryMessage}
+                            cancelMessage={cancelMessage}
+                            sendingIds={sendingIds}
+                            contactTyping={contactTyping}
+                            setTyping={setTyping}
                             setView={setView}
                             setMessageBatchNumber={setMessageBatchNumber}
                         />
@@ -80,8 +88,15 @@ const App = () => {
                             contacts={contacts}
                             setContacts={setContacts}
                             setActiveContact={setActiveContact}
+                            sendContactRequest={sendContactRequest}
                             setView={setView}
                         />
+            case "contact-requests":
+                return <ContactRequests
+                            contactRequests={contactRequests}
+                            acceptContactRequest={acceptContactRequest}
+                            rejectContactRequest={rejectContactRequest}
+                        />
             case "contact-details":
                 return <ContactDetails
                             activeContact={activeContact}
@@ -117,6 +132,7 @@ const App = () => {
           />
           <ContactList
             contacts={contacts}
+            amountContactRequests={contactRequests.length}
             setActiveContact={setActiveContact}
             setView={setView}
           />
@@ -2,7 +2,7 @@ import { useState, useRef } from "react";
 import { open } from '@tauri-apps/plugin-dialog';
 import "./ChatInput.scss";
 
-export default function ChatInput({ sendMessage, sendAttachment }) {
+export default function ChatInput({ sendMessage, sendAttachment, setTyping }) {
     const [text, setText] = useState("");
     const textInputRef = useRef(null);
 
@@ -12,6 +12,7 @@ export default function ChatInput({ sendMessage, sendAttachment }) {
             return;
         }
 
+        setTyping(false);
         await sendMessage(text.trim());
         setText("");
     }
@@ -62,7 +63,10 @@ export default function ChatInput({ sendMessage, sendAttachment }) {
                     ref={textInputRef}
                     className="chat-input__text"
                     value={text}
-                    onChange={(e) => setText(e.target.value)}
+                    onChange={(e) => {
+                        setText(e.target.value);
+                        setTyping(e.target.value.trim().length > 0);
+                    }}
                     onKeyDown={handleKeydown}
                     onKeyUp={handleKeyup}
                     placeholder="Type your message..."
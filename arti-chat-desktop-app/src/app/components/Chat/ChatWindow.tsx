@@ -10,7 +10,7 @@ import ChatInput from "./ChatInput";
 
 import "./ChatWindow.scss";
 
-export default function ChatWindow({ activeContact, loadContacts, setView, messages, sendMessage, sendAttachment, setMessageBatchNumber }) {
+export default function ChatWindow({ activeContact, loadContacts, setView, messages, sendMessage, sendAttachment, retryMessage, cancelMessage, sendingIds, contactTyping, setTyping, setMessageBatchNumber }) {
     const chatRef = useRef<HTMLDivElement>(null);
     const prevScrollHeightRef = useRef<number | null>(null);
     const [autoScrollToBottom, setAutoScrollToBottom] = useState(true);
@@ -200,14 +200,18 @@ export default function ChatWindow({ activeContact, loadContacts, setView, messa
                                         data-date-mark={messageDate}
                                     />
                                 )}
-                                <Message message={msg} />
+                                <Message message={msg} sending={sendingIds.has(msg.message_id)} retryMessage={retryMessage} cancelMessage={cancelMessage} />
                             </Fragment>
                         );
                     });
                 })()}
             </div>
 
-            <ChatInput sendMessage={sendMessage} sendAttachment={sendAttachment} />
+            {contactTyping && (
+                <div className="chat__typing">{activeContact.nickname} is typing...</div>
+            )}
+
+            <ChatInput sendMessage={sendMessage} sendAttachment={sendAttachment} setTyping={setTyping} />
         </div>
     );
 }
@@ -1,6 +1,6 @@
 import "./Message.scss";
 
-export default function Message({ message }) {
+export default function Message({ message, sending, retryMessage, cancelMessage }) {
     const formatTimeFromTs = (ts) => {
         const date = new Date(ts * 1000);
         const hours = date.getHours().toString().padStart(2, "0");
@@ -8,8 +8,30 @@ export default function Message({ message }) {
         return `${hours}:${minutes}`;
     }
 
-    const statusIndicator = (sent_status) => {
-        if (sent_status) {
+    const statusIndicator = (message) => {
+        if (message.read_at) {
+            return (
+                <img
+                    className="message__sent-status message__sent-status--read"
+                    alt="Read"
+                    title="Read"
+                    src="/assets/message-sent.png"
+                />
+            )
+        }
+
+        if (message.delivered_at) {
+            return (
+                <img
+                    className="message__sent-status message__sent-status--delivered"
+                    alt="Delivered"
+                    title="Delivered"
+                    src="/assets/message-sent.png"
+                />
+            )
+        }
+
+        if (message.sent_status) {
             return (
                 <img
                     className="message__sent-status"
@@ -20,16 +42,40 @@ export default function Message({ message }) {
             )
         }
 
+        if (message.failed) {
+            return (
+                <img
+                    className="message__sent-status message__sent-status--failed"
+                    alt="Sending failed"
+                    title="Sending failed"
+                    src="/assets/error.png"
+                />
+            )
+        }
+
         return (
             <img
                 className="message__sent-status"
-                alt="Message pending"
-                title="Message pending"
+                alt={sending ? "Sending" : "Message pending"}
+                title={sending ? "Sending" : "Message pending"}
                 src="/assets/message-pending.png"
             />
         )
     }
 
+    const outboxActions = (message) => {
+        if (message.is_incoming || message.optimistic || message.sent_status || sending) {
+            return null;
+        }
+
+        return (
+            <div className="message__outbox-actions">
+                <button onClick={() => retryMessage(message.message_id)}>Retry</button>
+                <button onClick={() => cancelMessage(message.message_id)}>Cancel</button>
+            </div>
+        )
+    }
+
     const errorImage = (message) => {
         if (messageIsError(message)) {
             let message_body = JSON.parse(message.body);
@@ -69,10 +115,6 @@ export default function Message({ message }) {
     }
 
     const messageIsError = (message) => {
-        if (message.is_incoming && !message.verified_status) {
-            return true;
-        }
-
         let message_body = JSON.parse(message.body);
         if (message_body.type === "Error") {
             return true;
@@ -90,8 +132,9 @@ export default function Message({ message }) {
             <span className="message__body">{messageContent(message.body)}</span>
             <div className="message__info">
                 <span className="message__timestamp">{!message.optimistic && formatTimeFromTs(message.timestamp)}</span>
-                {!message.is_incoming && !message.optimistic && statusIndicator(message.sent_status)}
+                {!message.is_incoming && !message.optimistic && statusIndicator(message)}
             </div>
+            {outboxActions(message)}
         </div>
     );
 }
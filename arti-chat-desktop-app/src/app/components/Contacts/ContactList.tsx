@@ -2,7 +2,7 @@ import React from "react";
 import ContactItem from "./ContactItem";
 import "./ContactList.scss";
 
-export default function ContactList({contacts, setActiveContact, setView}) {
+export default function ContactList({contacts, amountContactRequests, setActiveContact, setView}) {
   return (
     <div className="contacts">
       <div className="contacts__header">
@@ -17,6 +17,18 @@ export default function ContactList({contacts, setActiveContact, setView}) {
         />
       </div>
 
+      {amountContactRequests > 0 && (
+        <div
+            className="contacts__requests"
+            onClick={() => {
+                setActiveContact(null);
+                setView('contact-requests');
+            }}
+        >
+            Contact requests ({amountContactRequests})
+        </div>
+      )}
+
       <div className="contacts__list">
         
         {contacts.length === 0 && (<div className="contacts__empty">
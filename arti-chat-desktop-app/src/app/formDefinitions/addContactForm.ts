@@ -16,4 +16,18 @@ export const addContactForm: FieldConfig[] = [
         placeholder: "",
         required: true,
     },
+    {
+        name: "introduce_as",
+        label: "Introduce yourself as (optional, sends a contact request)",
+        type: FieldType.Text,
+        placeholder: "Bob",
+        required: false,
+    },
+    {
+        name: "note",
+        label: "Note with contact request",
+        type: FieldType.TextArea,
+        placeholder: "",
+        required: false,
+    },
 ];
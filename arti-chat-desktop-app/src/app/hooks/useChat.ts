@@ -1,20 +1,65 @@
-import { useEffect, useLayoutEffect, useState, useCallback } from "react";
+import { useEffect, useLayoutEffect, useState, useCallback, useRef } from "react";
 import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
 
 export interface Message {
     id: number;
+    message_id: string;
     body: string;
     timestamp: number;
     is_incoming: boolean;
     sent_status: boolean;
     verified_status: boolean;
+    delivered_at: number | null;
+    read_at: number | null;
+    failed: boolean;
 }
 
 const BATCH_SIZE = 25;
 
+// Typing indicator of contact is hidden if not renewed within this time (ms).
+const TYPING_TIMEOUT = 10000;
+
+// Repeat own typing state to daemon at most this often (ms).
+const TYPING_REPEAT = 3000;
+
 export function useChat({activeContact, loadContacts, messageBatchNumber}) {
     const [messages, setMessages] = useState<Message[]>([]);
+    const [contactTyping, setContactTyping] = useState<boolean>(false);
+    const [sendingIds, setSendingIds] = useState<Set<string>>(new Set());
+    const typingTimeoutRef = useRef<ReturnType<typeof setTimeout> | null>(null);
+    const lastTypingSentRef = useRef<{ typing: boolean, at: number }>({ typing: false, at: 0 });
+
+    // Show typing indicator of contact until it expires.
+    const showContactTyping = useCallback((typing: boolean) => {
+        if (typingTimeoutRef.current) {
+            clearTimeout(typingTimeoutRef.current);
+            typingTimeoutRef.current = null;
+        }
+
+        setContactTyping(typing);
+        if (typing) {
+            typingTimeoutRef.current = setTimeout(() => setContactTyping(false), TYPING_TIMEOUT);
+        }
+    }, []);
+
+    // Tell contact we started or stopped typing.
+    const setTyping = useCallback((typing: boolean) => {
+        if (!activeContact) {
+            return;
+        }
+
+        const last = lastTypingSentRef.current;
+        if (last.typing === typing && (!typing || Date.now() - last.at < TYPING_REPEAT)) {
+            return;
+        }
+        lastTypingSentRef.current = { typing: typing, at: Date.now() };
+
+        invoke("set_typing", {
+            onionId: activeContact.onion_id,
+            typing: typing,
+        });
+    }, [activeContact]);
 
     // Load chat.
     const loadChat = useCallback(async () => {
@@ -62,6 +107,9 @@ export function useChat({activeContact, loadContacts, messageBatchNumber}) {
                 is_incoming: false,
                 sent_status: false,
                 verified_status: true,
+                delivered_at: null,
+                read_at: null,
+                failed: false,
                 optimistic: true,
             }
         ])
@@ -97,6 +145,9 @@ export function useChat({activeContact, loadContacts, messageBatchNumber}) {
                 is_incoming: false,
                 sent_status: false,
                 verified_status: true,
+                delivered_at: null,
+                read_at: null,
+                failed: false,
                 optimistic: true,
             }
         ])
@@ -113,10 +164,47 @@ export function useChat({activeContact, loadContacts, messageBatchNumber}) {
         await loadChat();
     }
 
+    // Retry an unsent message right away.
+    const retryMessage = async (messageId: string) => {
+        await invoke("retry_message", { messageId: messageId });
+        await loadChat();
+    }
+
+    // Stop sending an unsent message and remove it.
+    const cancelMessage = async (messageId: string) => {
+        await invoke("cancel_message", { messageId: messageId });
+        await loadChat();
+    }
+
     // Listen for new messages.
     useEffect(() => {
         const promise = listen("incoming-message", async (event) => {
             const data = JSON.parse(event.payload);
+
+            // Typing indicators do not change the chat.
+            if (data.type === "typing") {
+                if (activeContact && data.onion_id === activeContact.onion_id) {
+                    showContactTyping(data.typing);
+                }
+                return;
+            }
+
+            // Remember which messages the daemon is delivering right now.
+            if (data.type === "message_status") {
+                setSendingIds((prev) => {
+                    const next = new Set(prev);
+                    data.message_ids.forEach((id: string) => {
+                        if (data.status === "sending") {
+                            next.add(id);
+                        } else {
+                            next.delete(id);
+                        }
+                    });
+                    return next;
+                });
+            } else if (activeContact && data.onion_id === activeContact.onion_id) {
+                showContactTyping(false);
+            }
             await loadChat();
             await loadContacts();
         });
@@ -124,12 +212,23 @@ export function useChat({activeContact, loadContacts, messageBatchNumber}) {
         return () => {
             promise.then((p) => p());
         };
-    }, [loadContacts, loadChat])
+    }, [loadContacts, loadChat, activeContact, showContactTyping])
+
+    // Hide typing indicator when switching chat.
+    useEffect(() => {
+        showContactTyping(false);
+        lastTypingSentRef.current = { typing: false, at: 0 };
+    }, [activeContact, showContactTyping]);
 
     return {
         messages,
         sendMessage,
         sendAttachment,
+        retryMessage,
+        cancelMessage,
+        sendingIds,
+        contactTyping,
+        setTyping,
     };
 }
 
@@ -0,0 +1,88 @@
+import { useEffect, useState, useCallback } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+export interface ContactRequest {
+    onion_id: string;
+    public_key: string;
+    nickname: string;
+    note: string;
+    received_at: number;
+    verified: boolean;
+}
+
+export function useContactRequests({loadContacts}) {
+    const [contactRequests, setContactRequests] = useState<ContactRequest[]>([]);
+
+    // Load contact requests waiting for the user.
+    const loadContactRequests = useCallback(async () => {
+        const requests = await invoke<ContactRequest[]>("list_contact_requests");
+        setContactRequests(requests);
+    }, []);
+
+    // Introduce ourselves to a peer which does not know us yet.
+    const sendContactRequest = useCallback(
+        async ({onion_id, nickname, note}: {onion_id: string, nickname: string, note: string}): boolean => {
+            return await invoke("send_contact_request", {
+                onionId: onion_id,
+                nickname: nickname,
+                note: note,
+            });
+        },
+        []
+    );
+
+    // Add peer of request as contact.
+    const acceptContactRequest = useCallback(
+        async (onion_id: string): boolean => {
+            let response = await invoke("accept_contact_request", {
+                onionId: onion_id,
+                nickname: null,
+            });
+
+            await loadContactRequests();
+            await loadContacts();
+
+            return response;
+        },
+        [loadContactRequests, loadContacts]
+    );
+
+    // Delete request.
+    const rejectContactRequest = useCallback(
+        async (onion_id: string): boolean => {
+            let response = await invoke("reject_contact_request", {
+                onionId: onion_id,
+            });
+
+            await loadContactRequests();
+
+            return response;
+        },
+        [loadContactRequests]
+    );
+
+    // Reload when a peer sends a request.
+    useEffect(() => {
+        loadContactRequests();
+
+        const promise = listen("incoming-message", async (event) => {
+            const data = JSON.parse(event.payload);
+            if (data.type === "contact_request") {
+                await loadContactRequests();
+            }
+        });
+
+        return () => {
+            promise.then((p) => p());
+        };
+    }, [loadContactRequests]);
+
+    return {
+        contactRequests,
+        loadContactRequests,
+        sendContactRequest,
+        acceptContactRequest,
+        rejectContactRequest,
+    };
+}
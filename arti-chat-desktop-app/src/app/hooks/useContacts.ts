@@ -7,6 +7,7 @@ export interface Contact {
     public_key: string;
     amount_unread_messages: number;
     last_viewed_at: number;
+    identity_mode?: boolean;
 }
 
 export function useContacts({contacts, setContacts}) {
@@ -24,6 +25,7 @@ export function useContacts({contacts, setContacts}) {
                 nickname: contact.nickname,
                 onionId: contact.onion_id,
                 publicKey: contact.public_key,
+                identityMode: contact.identity_mode ?? false,
             });
 
             await loadContacts();
@@ -5,7 +5,7 @@ import Form from "../../components/forms/Form";
 import { addContactForm } from "../../formDefinitions/addContactForm";
 import { useContacts } from "../../hooks/useContacts";
 
-export default function AddContact({contacts, setContacts, setActiveContact, setView})  {
+export default function AddContact({contacts, setContacts, setActiveContact, sendContactRequest, setView})  {
     const { addContact } = useContacts({contacts: contacts, setContacts: setContacts});
     const [success, setSuccess] = useState<boolean | null>(null);
 
@@ -27,12 +27,22 @@ export default function AddContact({contacts, setContacts, setActiveContact, set
 
     // Decode Base64 token to get user info.
     const decodeShareToken = (token) => {
+        // Contacts in identity mode only share their address.
+        if (token.trim().endsWith(".onion")) {
+            return {
+                "onion_id": token.trim(),
+                "public_key": "",
+                "identity_mode": true,
+            }
+        }
+
         const decoded = window.atob(token);
         const json = JSON.parse(decoded);
 
         return {
             "onion_id": json.onion_id,
             "public_key": json.public_key,
+            "identity_mode": false,
         }
     }
 
@@ -49,11 +59,23 @@ export default function AddContact({contacts, setContacts, setActiveContact, set
                         return false;
                     }
 
-                    return await addContact({
+                    const added = await addContact({
                         nickname: values.nickname,
                         onion_id: contactInfo.onion_id,
                         public_key: contactInfo.public_key,
+                        identity_mode: contactInfo.identity_mode,
                     });
+
+                    // Lets the contact add us without exchanging share tokens.
+                    if (added && values.introduce_as?.trim()) {
+                        return await sendContactRequest({
+                            onion_id: contactInfo.onion_id,
+                            nickname: values.introduce_as,
+                            note: values.note ?? "",
+                        });
+                    }
+
+                    return added;
                 }}
                 success={success}
                 setSuccess={setSuccess}
@@ -0,0 +1,34 @@
+import React from "react";
+import "./ContactRequests.scss";
+
+export default function ContactRequests({contactRequests, acceptContactRequest, rejectContactRequest})  {
+    return (
+        <div className="screen screen--contact-requests">
+            <h2>Contact requests</h2>
+
+            {contactRequests.length === 0 && (
+                <p>No pending requests.</p>
+            )}
+
+            {contactRequests.map((request) => (
+                <div className="contact-request" key={request.onion_id}>
+                    <span className="contact-request__nickname">{request.nickname}</span>
+                    <span className="contact-request__onion-id">{request.onion_id}</span>
+                    {!request.verified && (
+                        <span className="contact-request__unverified">
+                            Unverified: the owner of this onion address did not confirm the key.
+                            Compare the safety number before trusting it.
+                        </span>
+                    )}
+                    {request.note && (
+                        <p className="contact-request__note">{request.note}</p>
+                    )}
+                    <div className="contact-request__actions">
+                        <button onClick={() => acceptContactRequest(request.onion_id)}>Accept</button>
+                        <button onClick={() => rejectContactRequest(request.onion_id)}>Reject</button>
+                    </div>
+                </div>
+            ))}
+        </div>
+    );
+}
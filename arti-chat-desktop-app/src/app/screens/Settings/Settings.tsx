@@ -11,6 +11,12 @@ export default function Settings({contacts, setContacts})  {
     const [deleteAllContactsSuccess, setDeleteAllContactsSuccess] = useState<boolean | null>(null);
     const [enableNotifications, setEnableNotifications] = useState<boolean>(false);
     const [enableAttachments, setEnableAttachments] = useState<boolean>(false);
+    const [strictPadding, setStrictPadding] = useState<boolean>(false);
+    const [enablePostQuantum, setEnablePostQuantum] = useState<boolean>(false);
+    const [requirePostQuantum, setRequirePostQuantum] = useState<boolean>(false);
+    const [enableReadReceipts, setEnableReadReceipts] = useState<boolean>(false);
+    const [expireMessages, setExpireMessages] = useState<boolean>(false);
+    const [requireRequestStamp, setRequireRequestStamp] = useState<boolean>(false);
 
     useEffect(() => {
         const loadConfig = async () => {
@@ -19,6 +25,24 @@ export default function Settings({contacts, setContacts})  {
             
             const enableAttachmentsValue = await getConfigValue("enable_attachments");
             setEnableAttachments(enableAttachmentsValue === "true")
+
+            const paddingPolicyValue = await getConfigValue("padding_policy");
+            setStrictPadding(paddingPolicyValue === "strict")
+
+            const enablePostQuantumValue = await getConfigValue("enable_post_quantum");
+            setEnablePostQuantum(enablePostQuantumValue === "true")
+
+            const requirePostQuantumValue = await getConfigValue("require_post_quantum");
+            setRequirePostQuantum(requirePostQuantumValue === "true")
+
+            const enableReadReceiptsValue = await getConfigValue("enable_read_receipts");
+            setEnableReadReceipts(enableReadReceiptsValue === "true")
+
+            const messageExpiryValue = await getConfigValue("message_expiry");
+            setExpireMessages(messageExpiryValue !== "0")
+
+            const requireRequestStampValue = await getConfigValue("require_contact_request_stamp");
+            setRequireRequestStamp(requireRequestStampValue === "true")
         };
 
         loadConfig();
@@ -60,6 +84,72 @@ export default function Settings({contacts, setContacts})  {
                     await setConfigValue("enable_attachments", checked.toString());
                 }}
             />
+
+            <Action
+                label="Strict padding"
+                description="Pad short messages to 4 KiB so their length is hidden, at the cost of bandwidth."
+                actionType={ActionType.Toggle}
+                checked={strictPadding}
+                onClick={async (checked: boolean) => {
+                    setStrictPadding(checked);
+                    await setConfigValue("padding_policy", checked ? "strict" : "buckets");
+                }}
+            />
+
+            <Action
+                label="Post-quantum handshake"
+                description="Add ML-KEM to new sessions with contacts supporting it, against recorded traffic being decrypted later."
+                actionType={ActionType.Toggle}
+                checked={enablePostQuantum}
+                onClick={async (checked: boolean) => {
+                    setEnablePostQuantum(checked);
+                    await setConfigValue("enable_post_quantum", checked.toString());
+                }}
+            />
+
+            <Action
+                label="Require post-quantum handshake"
+                description="Refuse new sessions with contacts not using ML-KEM, including sessions set up with prekeys while you were offline."
+                actionType={ActionType.Toggle}
+                checked={requirePostQuantum}
+                onClick={async (checked: boolean) => {
+                    setRequirePostQuantum(checked);
+                    await setConfigValue("require_post_quantum", checked.toString());
+                }}
+            />
+
+            <Action
+                label="Read receipts"
+                description="Let contacts know when you have read their messages."
+                actionType={ActionType.Toggle}
+                checked={enableReadReceipts}
+                onClick={async (checked: boolean) => {
+                    setEnableReadReceipts(checked);
+                    await setConfigValue("enable_read_receipts", checked.toString());
+                }}
+            />
+
+            <Action
+                label="Expire undelivered messages"
+                description="Stop retrying messages that could not be delivered within a week."
+                actionType={ActionType.Toggle}
+                checked={expireMessages}
+                onClick={async (checked: boolean) => {
+                    setExpireMessages(checked);
+                    await setConfigValue("message_expiry", checked ? "604800" : "0");
+                }}
+            />
+
+            <Action
+                label="Require proof of work for contact requests"
+                description="Drop contact requests of unknown peers which did not compute a proof-of-work stamp."
+                actionType={ActionType.Toggle}
+                checked={requireRequestStamp}
+                onClick={async (checked: boolean) => {
+                    setRequireRequestStamp(checked);
+                    await setConfigValue("require_contact_request_stamp", checked.toString());
+                }}
+            />
         </div>
     );
 }